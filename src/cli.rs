@@ -0,0 +1,309 @@
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{ Args, Parser, Subcommand };
+
+use rvm::rvm::view_position::ViewPosition;
+use rvm::rvm::precision::Precision;
+use rvm::rvm::fixed_point::FixedPoint;
+use rvm::rvm::formula::{ Formula, POWER_RANGE };
+use rvm::rvm::coloring::{ Coloring, Interior };
+use rvm::rvm::palette::{ Palette, PRESETS };
+use rvm::rvm::palette_file::load_palette;
+use rvm::rvm::orbit_trap::{ OrbitTrap, TrapShape, TrapImage };
+use rvm::rvm::lighting::{ Lighting, HeightField };
+use rvm::rvm::supersampling::{ Supersampling, SamplePattern, MAX_SAMPLES };
+use rvm::rvm::user_formula::UserFormula;
+use rvm::rvm::refinement::FRAME_BUDGET_RANGE;
+
+#[derive(Parser)]
+#[command(name = "rvm", version, about = "Mandelbrot set explorer on Vulkan compute shaders")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render a single image without a window and save it as PNG
+    Render {
+        #[command(flatten)]
+        view: ViewArgs,
+
+        /// Image size in pixels
+        #[arg(long, value_name = "WxH", default_value = "1000x800", value_parser = parse_size)]
+        size: (u32, u32),
+
+        /// Output PNG file
+        #[arg(long, value_name = "FILE", default_value = "rvm.png")]
+        out: PathBuf,
+
+        /// Render on the CPU even if a Vulkan device is available
+        #[arg(long)]
+        cpu: bool,
+
+        /// Number type of the kernel: single, double, double-single or perturbation
+        /// [default: chosen by zoom]
+        #[arg(long, value_parser = parse_precision)]
+        precision: Option<Precision>,
+    },
+    /// Open the interactive viewer (default)
+    View {
+        #[command(flatten)]
+        view: ViewArgs,
+
+        /// Development mode: recompile the compute shader whenever this file changes
+        #[arg(long, value_name = "FILE", num_args = 0..=1, 
+            default_missing_value = "src/compute.glsl")]
+        watch_shader: Option<PathBuf>,
+
+        /// Milliseconds the fractal may take per frame, from 5 to 200, slower views
+        /// are rendered tile by tile over several frames
+        #[arg(long, value_name = "MS", default_value = "30", value_parser = parse_frame_budget)]
+        frame_budget: Duration,
+    },
+    /// List Vulkan devices and whether they can run the viewer and the renderer
+    Devices,
+}
+
+#[derive(Args)]
+pub struct ViewArgs {
+    /// Fractal: mandelbrot, burning-ship, tricorn, multibrot, celtic, buffalo
+    /// or an expression of z and c like "z^3 + c*exp(z)"
+    #[arg(long, allow_hyphen_values = true, default_value = "mandelbrot", value_parser = parse_formula)]
+    pub formula: Formula,
+
+    /// Exponent of the multibrot formula, from 2 to 16 [default: 3]
+    #[arg(long, value_parser = parse_power)]
+    pub power: Option<f64>,
+
+    /// Point of the complex plane in the middle of the image, every digit counts
+    /// [default: the whole fractal in view]
+    #[arg(long, value_name = "X,Y", allow_hyphen_values = true, value_parser = parse_center)]
+    pub center: Option<[FixedPoint; 2]>,
+
+    /// Zoom level, magnification is exp(zoom / 10) [default: the whole fractal in view]
+    #[arg(long, allow_hyphen_values = true)]
+    pub zoom: Option<f64>,
+
+    /// Maximum number of iterations per pixel
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u32).range(1..))]
+    pub iterations: u32,
+
+    /// Coloring of escaped points: iteration-count, smooth, histogram, distance, line-art
+    /// or orbit-trap
+    #[arg(long, default_value = "iteration-count", value_parser = parse_coloring)]
+    pub coloring: Coloring,
+
+    /// Coloring of points inside the set: flat, period, magnitude or distance
+    #[arg(long, default_value = "flat", value_parser = parse_interior)]
+    pub interior: Interior,
+
+    /// Gradient: green, ultra-fractal, fire, ocean, grayscale, rainbow, sunset
+    /// or a .map, .ggr or .json palette file
+    #[arg(long, default_value = "green", value_parser = parse_palette)]
+    pub palette: Palette,
+
+    /// How many times the gradient repeats over the iterations
+    #[arg(long, default_value_t = 1.0)]
+    pub palette_scale: f32,
+
+    /// Shift of the gradient, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    pub palette_offset: f32,
+
+    /// Trap of the orbit-trap coloring: point, line, cross, circle or image
+    #[arg(long, default_value = "point", value_parser = parse_trap)]
+    pub trap: TrapShape,
+
+    /// Point, crossing of the lines or middle of the circle or the image of the trap
+    #[arg(long, value_name = "X,Y", allow_hyphen_values = true, default_value = "0,0", 
+        value_parser = parse_point)]
+    pub trap_center: [f64; 2],
+
+    /// Radius of the circle trap, half the longer side of the image trap
+    #[arg(long, default_value_t = 0.5)]
+    pub trap_radius: f64,
+
+    /// Turn of the trap in degrees
+    #[arg(long, allow_hyphen_values = true, default_value_t = 0.0)]
+    pub trap_angle: f64,
+
+    /// PNG of the image trap, transparent pixels let the orbit through
+    #[arg(long, value_name = "FILE", value_parser = parse_trap_image)]
+    pub trap_image: Option<TrapImage>,
+
+    /// Light a relief raised from iterations or distance, off if not given
+    #[arg(long, value_name = "HEIGHT", value_parser = parse_height_field)]
+    pub lighting: Option<HeightField>,
+
+    /// Direction the light comes from in degrees, counterclockwise from the right
+    #[arg(long, allow_hyphen_values = true, default_value_t = 135.0)]
+    pub light_azimuth: f64,
+
+    /// Angle of the light over the image in degrees, 90 straight above
+    #[arg(long, default_value_t = 45.0)]
+    pub light_elevation: f64,
+
+    /// Strength of the highlights, 0 for a matte surface
+    #[arg(long, default_value_t = 0.3)]
+    pub light_specular: f64,
+
+    /// Steepness of the relief
+    #[arg(long, default_value_t = 1.0)]
+    pub relief: f64,
+
+    /// Anti-aliasing: every pixel averages SAMPLES x SAMPLES points
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=MAX_SAMPLES as i64))]
+    pub samples: u32,
+
+    /// Shift the samples randomly inside their cells instead of a regular grid
+    #[arg(long)]
+    pub jitter: bool,
+
+    /// Take all the samples only where neighbouring pixels differ
+    #[arg(long)]
+    pub adaptive: bool,
+}
+
+impl ViewArgs {
+    pub fn view_position(&self) -> ViewPosition {
+        let start = ViewPosition::formula(self.formula.clone());
+        let mut view_position = ViewPosition {
+            zoom: self.zoom.unwrap_or(start.zoom),
+            quality: self.iterations,
+            power: self.power.unwrap_or(start.power),
+            coloring: self.coloring,
+            interior: self.interior,
+            palette: Palette {
+                scale: self.palette_scale,
+                offset: self.palette_offset,
+                ..self.palette.clone()
+            },
+            trap: OrbitTrap {
+                shape: self.trap,
+                center: self.trap_center,
+                radius: self.trap_radius,
+                angle: self.trap_angle.to_radians(),
+                image: Arc::new(self.trap_image.clone().unwrap_or_default()),
+            },
+            lighting: Lighting {
+                enabled: self.lighting.is_some(),
+                height: self.lighting.unwrap_or_default(),
+                azimuth: self.light_azimuth.to_radians(),
+                elevation: self.light_elevation.to_radians(),
+                specular: self.light_specular,
+                relief: self.relief,
+            },
+            supersampling: Supersampling {
+                samples: self.samples,
+                pattern: if self.jitter { SamplePattern::Jittered } else { SamplePattern::Grid },
+                adaptive: self.adaptive,
+            },
+            ..start
+        };
+        if let Some(center) = &self.center {
+            view_position.set_precise_center(center.clone());
+        }
+        view_position
+    }
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value.split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WxH, got '{}'", value))?;
+    let width: u32 = width.trim().parse().map_err(|err| format!("invalid width: {}", err))?;
+    let height: u32 = height.trim().parse().map_err(|err| format!("invalid height: {}", err))?;
+    if width == 0 || height == 0 {
+        return Err(String::from("image size must be greater than zero"));
+    }
+    Ok((width, height))
+}
+
+fn parse_power(value: &str) -> Result<f64, String> {
+    let power: f64 = value.parse().map_err(|err| format!("invalid power: {}", err))?;
+    if !POWER_RANGE.contains(&power) {
+        return Err(format!("power must be from {} to {}", POWER_RANGE.start(), POWER_RANGE.end()));
+    }
+    Ok(power)
+}
+
+fn parse_center(value: &str) -> Result<[FixedPoint; 2], String> {
+    let (x, y) = value.split_once(',')
+        .ok_or_else(|| format!("expected X,Y, got '{}'", value))?;
+    let x: FixedPoint = x.trim().parse().map_err(|err| format!("invalid X: {}", err))?;
+    let y: FixedPoint = y.trim().parse().map_err(|err| format!("invalid Y: {}", err))?;
+    Ok([x, y])
+}
+
+fn parse_point(value: &str) -> Result<[f64; 2], String> {
+    let (x, y) = value.split_once(',')
+        .ok_or_else(|| format!("expected X,Y, got '{}'", value))?;
+    let x: f64 = x.trim().parse().map_err(|err| format!("invalid X: {}", err))?;
+    let y: f64 = y.trim().parse().map_err(|err| format!("invalid Y: {}", err))?;
+    Ok([x, y])
+}
+
+fn parse_formula(value: &str) -> Result<Formula, String> {
+    if let Some(formula) = Formula::ALL.into_iter().find(|formula| formula.name() == value) {
+        return Ok(formula);
+    }
+    let user_formula: UserFormula = value.parse().map_err(|err| format!(
+        "expected mandelbrot, burning-ship, tricorn, multibrot, celtic, buffalo \
+        or an expression of z and c, got '{}': {}", value, err))?;
+    Ok(Formula::User(user_formula))
+}
+
+fn parse_coloring(value: &str) -> Result<Coloring, String> {
+    Coloring::ALL.into_iter()
+        .find(|coloring| coloring.name() == value)
+        .ok_or_else(|| {
+            let names: Vec<&str> = Coloring::ALL.iter().map(Coloring::name).collect();
+            format!("expected one of {}, got '{}'", names.join(", "), value)
+        })
+}
+
+fn parse_interior(value: &str) -> Result<Interior, String> {
+    Interior::ALL.into_iter()
+        .find(|interior| interior.name() == value)
+        .ok_or_else(|| format!("expected flat, period, magnitude or distance, got '{}'", value))
+}
+
+fn parse_trap(value: &str) -> Result<TrapShape, String> {
+    TrapShape::ALL.into_iter()
+        .find(|shape| shape.name() == value)
+        .ok_or_else(|| format!("expected point, line, cross, circle or image, got '{}'", value))
+}
+
+fn parse_height_field(value: &str) -> Result<HeightField, String> {
+    HeightField::ALL.into_iter()
+        .find(|height| height.name() == value)
+        .ok_or_else(|| format!("expected iterations or distance, got '{}'", value))
+}
+
+fn parse_frame_budget(value: &str) -> Result<Duration, String> {
+    let milliseconds: u64 = value.parse().map_err(|err| format!("invalid milliseconds: {}", err))?;
+    if !FRAME_BUDGET_RANGE.contains(&milliseconds) {
+        return Err(format!("frame budget must be from {} to {} milliseconds", 
+            FRAME_BUDGET_RANGE.start(), FRAME_BUDGET_RANGE.end()));
+    }
+    Ok(Duration::from_millis(milliseconds))
+}
+
+fn parse_trap_image(value: &str) -> Result<TrapImage, String> {
+    TrapImage::load(Path::new(value)).map_err(|err| err.to_string())
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    if let Some(palette) = Palette::preset(value) { return Ok(palette) }
+    load_palette(Path::new(value)).map_err(|err| format!(
+        "expected one of {} or a palette file: {}", PRESETS.join(", "), err))
+}
+
+fn parse_precision(value: &str) -> Result<Precision, String> {
+    Precision::ALL.into_iter()
+        .find(|precision| precision.name() == value)
+        .ok_or_else(|| format!(
+            "expected single, double, double-single or perturbation, got '{}'", value))
+}
@@ -1,52 +1,1073 @@
 #version 450
 
+// Собирается в нескольких вариантах точности, см. shader_module.rs:
+// PRECISION_DOUBLE - double, PRECISION_DOUBLE_SINGLE - пара float,
+// PRECISION_PERTURBATION - отклонения от опорной орбиты в double, иначе float.
+// USER_FORMULA - выражение пользователя над z и c, собирается во время работы
+// и только во float, см. user_formula.rs.
+// HISTOGRAM_PASS - второй проход раскраски по гистограмме вместо фрактала.
+// LIGHTING_PASS - освещение рельефа по высотам пикселей, последний проход.
+
+// Сторону рабочей группы выбирает pipelines.rs: 16 или меньше, если устройство не тянет 16 x 16
 layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+layout(local_size_x_id = 2, local_size_y_id = 3) in;
 
+#if defined(LIGHTING_PASS)
+layout(set = 0, binding = 0, rgba8) uniform image2D img;
+#else
 layout(set = 0, binding = 0, rgba8) uniform writeonly image2D img;
-layout(set = 0, binding = 1) buffer ViewPosition {
-    vec3 color;
-    uint quality;
+#endif
+layout(set = 0, binding = 1, std430) buffer ViewPosition {
     vec3 fract_color;
+    uint quality;
     float zoom;
-    float pos_x;
-    float pos_y;
+    float center_x[3];
+    float center_y[3];
+    float scale;
+    int scale_exponent;
+    float julia_x[3];
+    float julia_y[3];
+    uint julia;
+    float power;
+    uint coloring;
+    uint trap_shape;
+    float trap_x;
+    float trap_y;
+    float trap_radius;
+    float trap_angle;
+    uint interior;
+    uint lighting;
+    uint light_height;
+    float light_azimuth;
+    float light_elevation;
+    float light_specular;
+    float light_relief;
+    uint samples;
+    uint sample_pattern;
+    uint adaptive;
+    uint block_size;
+    uint keep_previous;
 } view_position;
 
-void main() {
-    float aspect_ratio = float(imageSize(img).x) / float(imageSize(img).y);
-    vec2 norm_coordinates = gl_GlobalInvocationID.xy;
-    vec2 c = (2.0 * norm_coordinates - vec2(imageSize(img))) / float(imageSize(img).y);
-    vec2 z = vec2(0.0, 0.0);
+// Градиент, см. palette.rs. Остановки отсортированы по позиции:
+// цвет в xyz, позиция в w.
+layout(set = 0, binding = 2, std430) readonly buffer Palette {
+    float offset;
+    float scale;
+    uint count;
+    uint cycling;
+    vec4 stops[256];
+} palette;
 
-    float actual_zoom = exp(view_position.zoom / 10.0);
+// Раскраска по гистограмме, см. histogram.rs. Первый проход считает точки в каждой
+// доле итераций и оставляет значения пикселей, второй заменяет счётчики суммой
+// всех предыдущих и по ней раскрашивает. Внутренние точки получают значение -1.
+#define HISTOGRAM_BINS 4096
+layout(set = 0, binding = 5, std430) buffer Histogram {
+    uint counts[HISTOGRAM_BINS + 1];
+    float values[];
+} histogram;
+
+// Картинка ловушки орбиты, см. orbit_trap.rs. Пиксель - RGBA8 в одном uint,
+// строки идут через TRAP_IMAGE_SIZE.
+#define TRAP_IMAGE_SIZE 256u
+layout(set = 0, binding = 6, std430) readonly buffer TrapImage {
+    uint width;
+    uint height;
+    uint pixels[TRAP_IMAGE_SIZE * TRAP_IMAGE_SIZE];
+} trap_image;
+
+// Высоты пикселей для освещения рельефа, см. lighting.rs. Их оставляет ядро,
+// нормали по ним считает проход освещения. У внутренних точек высоты нет.
+#define NO_HEIGHT -1e30
+layout(set = 0, binding = 7, std430) buffer Heights {
+    float values[];
+} heights;
+
+// Сглаживание, см. supersampling.rs: samples x samples точек на пиксель
+#define MAX_SAMPLES 4u
+#define PATTERN_GRID 0u
+#define PATTERN_JITTERED 1u
+
+// Из чего поднимается рельеф, см. lighting.rs
+#define HEIGHT_ITERATIONS 0u
+#define HEIGHT_DISTANCE 1u
+
+// Формула итерации, см. formula.rs. Задаётся при создании конвейера,
+// поэтому лишние ветки выбрасываются компилятором и не стоят ничего в цикле.
+layout(constant_id = 0) const uint FORMULA = 0u;
+#define FORMULA_MANDELBROT 0u
+#define FORMULA_BURNING_SHIP 1u
+#define FORMULA_TRICORN 2u
+#define FORMULA_MULTIBROT 3u
+#define FORMULA_CELTIC 4u
+#define FORMULA_BUFFALO 5u
+
+// Раскраска, см. coloring.rs
+#define COLORING_ITERATION_COUNT 0u
+#define COLORING_SMOOTH 1u
+#define COLORING_HISTOGRAM 2u
+#define COLORING_DISTANCE 3u
+#define COLORING_LINE_ART 4u
+#define COLORING_ORBIT_TRAP 5u
+
+// Раскраска точек внутри множества, см. coloring.rs
+#define INTERIOR_FLAT 0u
+#define INTERIOR_PERIOD 1u
+#define INTERIOR_MAGNITUDE 2u
+#define INTERIOR_DISTANCE 3u
+
+// Форма ловушки, см. orbit_trap.rs
+#define TRAP_POINT 0u
+#define TRAP_LINE 1u
+#define TRAP_CROSS 2u
+#define TRAP_CIRCLE 3u
+#define TRAP_IMAGE 4u
+
+// Итог итераций точки, |z| после выхода нужен плавной раскраске,
+// расстояние до множества в пикселях - раскраскам по расстоянию,
+// ближайшее расстояние до ловушки и цвет пойманного пикселя картинки - ловушке.
+// У точек внутри - длина найденного цикла орбиты и расстояние изнутри до границы.
+struct Escape {
+    int iterations;
+    float radius;
+    float distance;
+    float trap;
+    vec4 trap_color;
+    int period;
+    float interior_distance;
+};
+
+// Расстояние до ловушки у орбиты, которая к ней не подходила
+#define NO_TRAP 1e20
+
+// Плавной раскраске нужен |z| подальше от множества, где от формулы остаётся старшая степень
+float escape_radius() {
+    return view_position.coloring != COLORING_ITERATION_COUNT ? 256.0 : 4.0;
+}
+
+// Производная z по c считается, только когда нужна: это ещё одно умножение на итерацию
+bool tracks_distance() {
+    return view_position.coloring == COLORING_DISTANCE || view_position.coloring == COLORING_LINE_ART
+        || (view_position.lighting != 0u && view_position.light_height == HEIGHT_DISTANCE);
+}
+
+// Производная считается сразу в пикселях: она растёт вместе с увеличением и на глубине
+// не поместилась бы даже в double, а в пикселях остаётся порядка единицы.
+// Тогда оценка расстояния |z| ln|z| / |dz| тоже в пикселях.
+float distance_estimate(float radius, float derivative) {
+    return radius * log(radius) / derivative;
+}
+
+vec2 vec2_mul(vec2 a, vec2 b) {
+    return vec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+vec2 vec2_pow(vec2 z, float power) {
+    if (power >= 1.0 && power == floor(power))
+    {
+        vec2 result = z;
+        for (int i = 1; i < int(power); i++) result = vec2_mul(result, z);
+        return result;
+    }
+    if (z == vec2(0.0)) return z;
+    float angle = atan(z.y, z.x) * power;
+    return pow(length(z), power) * vec2(cos(angle), sin(angle));
+}
+
+bool tracks_trap() {
+    return view_position.coloring == COLORING_ORBIT_TRAP;
+}
+
+// Циклы ищутся, только когда внутренность красится по ним
+bool tracks_cycles() {
+    return view_position.interior == INTERIOR_PERIOD || view_position.interior == INTERIOR_DISTANCE;
+}
+
+// Точка в системе ловушки: от её центра и повёрнутая на её угол
+vec2 trap_local(vec2 z) {
+    vec2 offset = z - vec2(view_position.trap_x, view_position.trap_y);
+    float c = cos(view_position.trap_angle);
+    float s = sin(view_position.trap_angle);
+    return vec2(c * offset.x + s * offset.y, c * offset.y - s * offset.x);
+}
+
+float trap_distance(vec2 z) {
+    vec2 p = trap_local(z);
+    switch (view_position.trap_shape)
+    {
+        case TRAP_LINE: return abs(p.y);
+        case TRAP_CROSS: return min(abs(p.x), abs(p.y));
+        case TRAP_CIRCLE: return abs(length(p) - view_position.trap_radius);
+        default: return length(p);
+    }
+}
+
+// Непрозрачный пиксель картинки под точкой, иначе прозрачный цвет.
+// Длинная сторона картинки - два радиуса ловушки.
+vec4 trap_image_color(vec2 z) {
+    if (view_position.trap_shape != TRAP_IMAGE || trap_image.width == 0u) return vec4(0.0);
+    vec2 size = vec2(trap_image.width, trap_image.height);
+    vec2 pixel = trap_local(z) * max(size.x, size.y) / (2.0 * view_position.trap_radius) + size / 2.0;
+    if (any(lessThan(pixel, vec2(0.0))) || any(greaterThanEqual(pixel, size))) return vec4(0.0);
+    uvec2 texel = uvec2(pixel);
+    vec4 color = unpackUnorm4x8(trap_image.pixels[texel.y * TRAP_IMAGE_SIZE + texel.x]);
+    return color.a >= 0.5 ? color : vec4(0.0);
+}
+
+// Каждая точка орбиты до выхода: ближайшее расстояние и первый пойманный пиксель
+void trap_step(vec2 z, inout float trap, inout vec4 trap_color) {
+    trap = min(trap, trap_distance(z));
+    if (trap_color.a == 0.0) trap_color = trap_image_color(z);
+}
+
+// Орбита, вернувшаяся так близко к пройденной точке, попала в цикл и уже не выйдет.
+// Точка сравнения сохраняется через всё более длинные промежутки, как у Брента,
+// тогда цикл любой длины найдётся не позже чем через два своих оборота после схождения.
+// Пока орбита сходится по спирали, она может вернуться к точке только через несколько
+// оборотов, поэтому найденный период потом сокращается до наименьшего делителя, через
+// который точка цикла возвращается к себе с точностью до пикселя.
+#define PERIOD_EPSILON 1e-3
+
+// Расстояние изнутри считается только для z^2 + c, у Жюлиа и своих формул его нет
+bool has_interior_distance() {
+#if defined(USER_FORMULA)
+    return false;
+#else
+    return view_position.interior == INTERIOR_DISTANCE && FORMULA == FORMULA_MANDELBROT 
+        && view_position.julia == 0u;
+#endif
+}
+
+// Оценка расстояния до границы изнутри по точке цикла z0: производные по начальному z
+// и по c за один оборот цикла. Во float, на глубине она размывается.
+float interior_distance(vec2 z0, vec2 c, int period) {
+    vec2 z = z0;
+    vec2 dz = vec2(1.0, 0.0);
+    vec2 dc = vec2(0.0);
+    vec2 dzdz = vec2(0.0);
+    vec2 dcdz = vec2(0.0);
+    for (int i = 0; i < period; i++)
+    {
+        dcdz = 2.0 * (vec2_mul(z, dcdz) + vec2_mul(dc, dz));
+        dzdz = 2.0 * (vec2_mul(dz, dz) + vec2_mul(z, dzdz));
+        dc = 2.0 * vec2_mul(z, dc) + vec2(1.0, 0.0);
+        dz = 2.0 * vec2_mul(z, dz);
+        z = vec2_mul(z, z) + c;
+    }
+    vec2 rest = vec2(1.0, 0.0) - dz;
+    vec2 ratio = vec2_mul(dc, vec2(rest.x, -rest.y)) / dot(rest, rest);
+    return (1.0 - dot(dz, dz)) / length(dcdz + vec2_mul(dzdz, ratio));
+}
+
+#if defined(PRECISION_PERTURBATION)
+
+// Орбита центра экрана Z(n), посчитанная на CPU с произвольной точностью
+layout(set = 0, binding = 3, std430) readonly buffer ReferenceOrbit {
+    dvec2 points[];
+} reference_orbit;
+
+// dz(skipped) = A dc + B dc^2 + C dc^3, общий для всего экрана, см. series_approximation.rs
+layout(set = 0, binding = 4, std430) readonly buffer SeriesApproximation {
+    dvec2 coefficients[3];
+    uint skipped;
+} series;
+
+dvec2 complex_mul(dvec2 a, dvec2 b) {
+    return dvec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+// C опорной орбиты: центр экрана у Мандельброта, константа у Жюлиа
+dvec2 reference_constant() {
+    if (view_position.julia != 0u) return dvec2(
+        double(view_position.julia_x[0]) + double(view_position.julia_x[1])
+            + double(view_position.julia_x[2]),
+        double(view_position.julia_y[0]) + double(view_position.julia_y[1])
+            + double(view_position.julia_y[2])
+    );
+    return dvec2(
+        double(view_position.center_x[0]) + double(view_position.center_x[1])
+            + double(view_position.center_x[2]),
+        double(view_position.center_y[0]) + double(view_position.center_y[1])
+            + double(view_position.center_y[2])
+    );
+}
+
+int cycle_period(dvec2 z, dvec2 c, int period, double tolerance) {
+    dvec2 w = z;
+    for (int divisor = 1; divisor < period; divisor++)
+    {
+        w = complex_mul(w, w) + c;
+        if (period % divisor == 0 && length(w - z) < tolerance) return divisor;
+    }
+    return period;
+}
+
+Escape escape_time(vec2 screen) {
+    float bailout = escape_radius();
+    dvec2 dc = ldexp(dvec2(screen * view_position.scale), ivec2(view_position.scale_exponent));
+    // У Жюлиа c общее, смещение пикселя входит только в начальное dz
+    dvec2 dc_step = view_position.julia != 0u ? dvec2(0.0LF) : dc;
+    int last = reference_orbit.points.length() - 1;
+    int n = int(series.skipped);
+    dvec2 dz = complex_mul(complex_mul(complex_mul(series.coefficients[2], dc)
+        + series.coefficients[1], dc) + series.coefficients[0], dc);
+
+    // Производная ряда по dc - это и производная z на пропущенных итерациях
+    bool distance = tracks_distance();
+    double pixel_size = ldexp(double(view_position.scale), view_position.scale_exponent)
+        * 2.0LF / double(imageSize(img).y);
+    dvec2 derivative = complex_mul(complex_mul(3.0LF * series.coefficients[2], dc)
+        + 2.0LF * series.coefficients[1], dc) + series.coefficients[0];
+    derivative *= pixel_size;
+    dvec2 derivative_step = view_position.julia != 0u ? dvec2(0.0LF) : dvec2(pixel_size, 0.0LF);
+
+    // Ряд ловушке ничего не пропускает, см. series_approximation.rs
+    bool trapping = tracks_trap();
+    bool cycles = tracks_cycles();
+    float trap = NO_TRAP;
+    vec4 trap_color = vec4(0.0);
+
+    int iterations = n;
+    float radius = 0.0;
+    dvec2 saved = reference_orbit.points[n] + dz;
+    int saved_at = iterations;
+    int window = 1;
+    int period = 0;
+    while (iterations < view_position.quality)
+    {
+        if (distance)
+        {
+            derivative = 2.0LF * complex_mul(reference_orbit.points[n] + dz, derivative) 
+                + derivative_step;
+        }
+        // z = Z + dz, тогда dz' = 2 * Z * dz + dz^2 + dc
+        dz = complex_mul(2.0LF * reference_orbit.points[n] + dz, dz) + dc_step;
+        n += 1;
+        dvec2 z = reference_orbit.points[n] + dz;
+
+        radius = length(vec2(z));
+        if (radius > bailout) break;
+        iterations += 1;
+        if (trapping) trap_step(vec2(z), trap, trap_color);
+
+        if (cycles && length(z - saved) < pixel_size * PERIOD_EPSILON)
+        {
+            period = iterations - saved_at;
+            iterations = int(view_position.quality);
+            saved = z;
+            break;
+        }
+        if (iterations == saved_at + window)
+        {
+            saved = z;
+            saved_at = iterations;
+            window *= 2;
+        }
+
+        // Глитч: точка ближе к нулю, чем к опорной орбите, и отклонение теряет
+        // точность. Тогда, как и при конце орбиты, отсчёт идёт заново от Z(0).
+        if (dot(z, z) < dot(dz, dz) || n == last)
+        {
+            dz = z - reference_orbit.points[0];
+            n = 0;
+        }
+    }
+    float estimate = distance ? distance_estimate(radius, float(length(derivative))) : 0.0;
+    dvec2 c = reference_constant() + dc_step;
+    if (period != 0) period = cycle_period(saved, c, period, pixel_size);
+    float interior = period != 0 && has_interior_distance() 
+        ? interior_distance(vec2(saved), vec2(c), period) : 0.0;
+    return Escape(iterations, radius, estimate, trap, trap_color, period, interior);
+}
+
+#elif defined(PRECISION_DOUBLE)
+
+#define complex dvec2
+
+complex pixel_to_complex(vec2 screen, float actual_zoom) {
+    dvec2 center = dvec2(
+        double(view_position.center_x[0]) + double(view_position.center_x[1])
+            + double(view_position.center_x[2]),
+        double(view_position.center_y[0]) + double(view_position.center_y[1])
+            + double(view_position.center_y[2])
+    );
+    return center + dvec2(screen / actual_zoom);
+}
+
+complex julia_constant() {
+    return dvec2(
+        double(view_position.julia_x[0]) + double(view_position.julia_x[1])
+            + double(view_position.julia_x[2]),
+        double(view_position.julia_y[0]) + double(view_position.julia_y[1])
+            + double(view_position.julia_y[2])
+    );
+}
+
+complex complex_sqr_add(complex z, complex c) {
+    return dvec2(
+        (z.x * z.x - z.y * z.y) + c.x,
+        (2.0LF * z.x * z.y) + c.y
+    );
+}
+
+complex complex_mul(complex a, complex b) {
+    return dvec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+complex complex_add(complex a, complex b) { return a + b; }
+
+complex complex_abs(complex z) { return abs(z); }
+
+complex complex_abs_re(complex z) { return dvec2(abs(z.x), z.y); }
+
+complex complex_conj(complex z) { return dvec2(z.x, -z.y); }
+
+float complex_distance(complex a, complex b) { return float(length(a - b)); }
+
+vec2 complex_to_vec2(complex z) { return vec2(z); }
+
+complex complex_from_vec2(vec2 z) { return dvec2(z); }
+
+#elif defined(PRECISION_DOUBLE_SINGLE)
+
+// Число хранится как vec2(hi, lo), комплексное как vec4(re.hi, re.lo, im.hi, im.lo).
+// precise запрещает компилятору переставлять операции, иначе ошибка округления теряется.
+#define complex vec4
+
+vec2 ds_two_sum(float a, float b) {
+    precise float s = a + b;
+    precise float v = s - a;
+    precise float e = (a - (s - v)) + (b - v);
+    return vec2(s, e);
+}
+
+vec2 ds_quick_two_sum(float a, float b) {
+    precise float s = a + b;
+    precise float e = b - (s - a);
+    return vec2(s, e);
+}
+
+vec2 ds_split(float a) {
+    precise float t = a * 4097.0;
+    precise float hi = t - (t - a);
+    precise float lo = a - hi;
+    return vec2(hi, lo);
+}
+
+vec2 ds_two_prod(float a, float b) {
+    precise float p = a * b;
+    vec2 a_split = ds_split(a);
+    vec2 b_split = ds_split(b);
+    precise float e = ((a_split.x * b_split.x - p) + a_split.x * b_split.y
+        + a_split.y * b_split.x) + a_split.y * b_split.y;
+    return vec2(p, e);
+}
+
+vec2 ds_add(vec2 a, vec2 b) {
+    vec2 s = ds_two_sum(a.x, b.x);
+    vec2 t = ds_two_sum(a.y, b.y);
+    precise float s_lo = s.y + t.x;
+    s = ds_quick_two_sum(s.x, s_lo);
+    precise float s_lo2 = s.y + t.y;
+    return ds_quick_two_sum(s.x, s_lo2);
+}
+
+vec2 ds_sub(vec2 a, vec2 b) { return ds_add(a, -b); }
+
+vec2 ds_mul(vec2 a, vec2 b) {
+    vec2 p = ds_two_prod(a.x, b.x);
+    precise float p_lo = p.y + (a.x * b.y + a.y * b.x);
+    return ds_quick_two_sum(p.x, p_lo);
+}
+
+vec2 ds_abs(vec2 a) { return a.x < 0.0 ? -a : a; }
+
+vec2 ds_from_parts(float parts[3]) {
+    return ds_add(ds_quick_two_sum(parts[0], parts[1]), vec2(parts[2], 0.0));
+}
+
+complex pixel_to_complex(vec2 screen, float actual_zoom) {
+    vec2 delta = screen / actual_zoom;
+    return vec4(
+        ds_add(ds_from_parts(view_position.center_x), vec2(delta.x, 0.0)),
+        ds_add(ds_from_parts(view_position.center_y), vec2(delta.y, 0.0))
+    );
+}
+
+complex julia_constant() {
+    return vec4(ds_from_parts(view_position.julia_x), ds_from_parts(view_position.julia_y));
+}
+
+complex complex_sqr_add(complex z, complex c) {
+    vec2 re = ds_add(ds_sub(ds_mul(z.xy, z.xy), ds_mul(z.zw, z.zw)), c.xy);
+    vec2 im = ds_add(ds_mul(2.0 * z.xy, z.zw), c.zw);
+    return vec4(re, im);
+}
+
+complex complex_mul(complex a, complex b) {
+    vec2 re = ds_sub(ds_mul(a.xy, b.xy), ds_mul(a.zw, b.zw));
+    vec2 im = ds_add(ds_mul(a.xy, b.zw), ds_mul(a.zw, b.xy));
+    return vec4(re, im);
+}
+
+complex complex_add(complex a, complex b) { return vec4(ds_add(a.xy, b.xy), ds_add(a.zw, b.zw)); }
+
+complex complex_abs(complex z) { return vec4(ds_abs(z.xy), ds_abs(z.zw)); }
+
+complex complex_abs_re(complex z) { return vec4(ds_abs(z.xy), z.zw); }
+
+complex complex_conj(complex z) { return vec4(z.xy, -z.zw); }
+
+float complex_distance(complex a, complex b) {
+    return length(vec2(ds_sub(a.xy, b.xy).x, ds_sub(a.zw, b.zw).x));
+}
+
+vec2 complex_to_vec2(complex z) { return z.xz; }
+
+complex complex_from_vec2(vec2 z) { return vec4(z.x, 0.0, z.y, 0.0); }
+
+#else
+
+#define complex vec2
+
+complex pixel_to_complex(vec2 screen, float actual_zoom) {
     vec2 actual_pos = vec2(
-        (view_position.pos_x * 0.001) * actual_zoom,
-        (view_position.pos_y * 0.001) * actual_zoom
+        view_position.center_x[0] * actual_zoom,
+        view_position.center_y[0] * actual_zoom
+    );
+    return vec2(
+        (screen.x + actual_pos.x) / actual_zoom,
+        (screen.y + actual_pos.y) / actual_zoom
     );
-    c.x = (c.x + actual_pos.x) / actual_zoom;
-    c.y = (c.y + actual_pos.y) / actual_zoom;
+}
+
+complex julia_constant() {
+    return vec2(view_position.julia_x[0], view_position.julia_y[0]);
+}
+
+complex complex_sqr_add(complex z, complex c) {
+    return vec2(
+        (z.x * z.x - z.y * z.y) + c.x,
+        (2.0 * z.x * z.y) + c.y
+    );
+}
+
+complex complex_mul(complex a, complex b) {
+    return vec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+complex complex_add(complex a, complex b) { return a + b; }
+
+complex complex_abs(complex z) { return abs(z); }
+
+complex complex_abs_re(complex z) { return vec2(abs(z.x), z.y); }
+
+complex complex_conj(complex z) { return vec2(z.x, -z.y); }
+
+float complex_distance(complex a, complex b) { return length(a - b); }
+
+vec2 complex_to_vec2(complex z) { return z; }
+
+complex complex_from_vec2(vec2 z) { return z; }
+
+#endif
+
+
+#if !defined(PRECISION_PERTURBATION)
+
+// Целые степени считаются умножениями в полной точности, дробные - через
+// полярную форму во float, для double других функций нет
+complex complex_pow(complex z, float power) {
+    if (power >= 1.0 && power == floor(power))
+    {
+        complex result = z;
+        for (int i = 1; i < int(power); i++) result = complex_mul(result, z);
+        return result;
+    }
+    vec2 w = complex_to_vec2(z);
+    if (w == vec2(0.0)) return complex(0.0);
+    float angle = atan(w.y, w.x) * power;
+    return complex_from_vec2(pow(length(w), power) * vec2(cos(angle), sin(angle)));
+}
+
+#if defined(USER_FORMULA)
+
+complex complex_div(complex a, complex b) {
+    return vec2(a.x * b.x + a.y * b.y, a.y * b.x - a.x * b.y) / dot(b, b);
+}
+
+complex complex_exp(complex z) { return exp(z.x) * vec2(cos(z.y), sin(z.y)); }
+
+complex complex_log(complex z) { return vec2(log(length(z)), atan(z.y, z.x)); }
+
+complex complex_pow_complex(complex z, complex w) {
+    if (z == vec2(0.0)) return z;
+    return complex_exp(complex_mul(w, complex_log(z)));
+}
+
+complex complex_sin(complex z) { return vec2(sin(z.x) * cosh(z.y), cos(z.x) * sinh(z.y)); }
+
+complex complex_cos(complex z) { return vec2(cos(z.x) * cosh(z.y), -sin(z.x) * sinh(z.y)); }
+
+complex complex_sinh(complex z) { return vec2(sinh(z.x) * cos(z.y), cosh(z.x) * sin(z.y)); }
+
+complex complex_cosh(complex z) { return vec2(cosh(z.x) * cos(z.y), sinh(z.x) * sin(z.y)); }
+
+complex complex_sqrt(complex z) { return complex_pow(z, 0.5); }
+
+complex complex_re(complex z) { return vec2(z.x, 0.0); }
+
+complex complex_im(complex z) { return vec2(z.y, 0.0); }
+
+complex formula_step(complex z, complex c) { return USER_FORMULA; }
+
+// Производная своей формулы неизвестна, её дают конечные разности по z и по c
+vec2 derivative_step(complex z, complex c, vec2 derivative, float dc) {
+    float h = 1e-3 * max(1.0, length(z));
+    complex f = formula_step(z, c);
+    complex f_z = (formula_step(z + vec2(h, 0.0), c) - f) / h;
+    complex f_c = (formula_step(z, c + vec2(h, 0.0)) - f) / h;
+    return vec2_mul(f_z, derivative) + dc * f_c;
+}
+
+#else
+
+complex formula_step(complex z, complex c) {
+    switch (FORMULA)
+    {
+        case FORMULA_BURNING_SHIP: return complex_sqr_add(complex_abs(z), c);
+        case FORMULA_TRICORN: return complex_sqr_add(complex_conj(z), c);
+        case FORMULA_MULTIBROT: return complex_add(complex_pow(z, view_position.power), c);
+        case FORMULA_CELTIC: return complex_add(complex_abs_re(complex_sqr_add(z, complex(0.0))), c);
+        case FORMULA_BUFFALO: return complex_add(complex_abs(complex_sqr_add(z, complex(0.0))), c);
+        default: return complex_sqr_add(z, c);
+    }
+}
+
+// dz' = f'(z) dz + dc. У формул с модулем и сопряжением производной нет, берётся
+// производная z^2: отражения не меняют её длину, а оценке нужна только длина
+vec2 derivative_step(complex z, complex c, vec2 derivative, float dc) {
+    vec2 w = complex_to_vec2(z);
+    vec2 f_z = FORMULA == FORMULA_MULTIBROT
+        ? view_position.power * vec2_pow(w, view_position.power - 1.0) : 2.0 * w;
+    return vec2_mul(f_z, derivative) + vec2(dc, 0.0);
+}
+
+#endif
+
+int cycle_period(complex z, complex c, int period, float tolerance) {
+    complex w = z;
+    for (int divisor = 1; divisor < period; divisor++)
+    {
+        w = formula_step(w, c);
+        if (period % divisor == 0 && complex_distance(w, z) < tolerance) return divisor;
+    }
+    return period;
+}
+
+Escape escape_time(vec2 screen) {
+    float bailout = escape_radius();
+    float actual_zoom = exp(view_position.zoom / 10.0);
+    complex c = pixel_to_complex(screen, actual_zoom);
+    complex z = complex(0.0);
+    // Производная в пикселях, см. distance_estimate
+    bool distance = tracks_distance();
+    float pixel_size = 2.0 / (float(imageSize(img).y) * actual_zoom);
+    vec2 derivative = vec2(0.0);
+    float dc = pixel_size;
+    if (view_position.julia != 0u)
+    {
+        z = c;
+        c = julia_constant();
+        derivative = vec2(pixel_size, 0.0);
+        dc = 0.0;
+    }
+    bool trapping = tracks_trap();
+    bool cycles = tracks_cycles();
+    float trap = NO_TRAP;
+    vec4 trap_color = vec4(0.0);
 
     int iterations = 0;
+    float radius = 0.0;
+    complex saved = z;
+    int saved_at = 0;
+    int window = 1;
+    int period = 0;
     while (iterations < view_position.quality)
     {
-        z = vec2(
-            (z.x * z.x - z.y * z.y) + c.x,
-            (2.0 * z.x * z.y) + c.y
-        );
+        if (distance) derivative = derivative_step(z, c, derivative, dc);
+        z = formula_step(z, c);
 
-        if (length(z) > 4.0) break;
+        radius = length(complex_to_vec2(z));
+        if (radius > bailout) break;
         iterations += 1;
+        if (trapping) trap_step(complex_to_vec2(z), trap, trap_color);
+
+        if (cycles && complex_distance(z, saved) < pixel_size * PERIOD_EPSILON)
+        {
+            period = iterations - saved_at;
+            iterations = int(view_position.quality);
+            break;
+        }
+        if (iterations == saved_at + window)
+        {
+            saved = z;
+            saved_at = iterations;
+            window *= 2;
+        }
+    }
+    float estimate = distance ? distance_estimate(radius, length(derivative)) : 0.0;
+    if (period != 0) period = cycle_period(z, c, period, pixel_size);
+    float interior = period != 0 && has_interior_distance() 
+        ? interior_distance(complex_to_vec2(z), complex_to_vec2(c), period) : 0.0;
+    return Escape(iterations, radius, estimate, trap, trap_color, period, interior);
+}
+
+#endif
+
+
+// Число итераций для палитры, у плавной раскраски и гистограммы с дробной частью
+float iteration_value(Escape escape) {
+    if (view_position.coloring == COLORING_ITERATION_COUNT) return float(escape.iterations);
+
+    // За радиусом выхода |z| растёт как bailout^(degree^t), дробная часть итерации - это t
+    float degree = FORMULA == FORMULA_MULTIBROT ? view_position.power : 2.0;
+    float fraction = log(log(escape.radius) / log(escape_radius())) / log(degree);
+    if (isnan(fraction)) fraction = 0.0;
+    return float(escape.iterations) + 1.0 - clamp(fraction, 0.0, 1.0);
+}
+
+// Цвет градиента для доли итераций value, между остановками линейно
+vec3 palette_color(float value) {
+    if (palette.count == 0u) return vec3(0.0);
+    float t = value * palette.scale + palette.offset;
+    // Без циклического сдвига конец повтора берёт последний цвет, а не первый
+    t = palette.cycling == 0u && t > 0.0 && fract(t) == 0.0 ? 1.0 : fract(t);
+
+    vec4 previous = palette.stops[0];
+    if (t <= previous.w) return previous.rgb;
+    for (uint i = 1u; i < palette.count; i++)
+    {
+        vec4 next = palette.stops[i];
+        if (t <= next.w) return mix(previous.rgb, next.rgb, (t - previous.w) / (next.w - previous.w));
+        previous = next;
+    }
+    return previous.rgb;
+}
+
+// 0 на границе множества и 1 в двух пикселях от неё, так границы и нити
+// тоньше пикселя остаются видны
+float boundary_shade(float distance) {
+    return smoothstep(0.0, 2.0, distance);
+}
+
+// Фон рисунка границы - последний цвет палитры
+vec3 line_art_background() {
+    return palette.stops[max(palette.count, 1u) - 1u].rgb;
+}
+
+// Золотое сечение разводит циклы соседних длин по далёким цветам палитры
+#define PERIOD_COLOR_STEP 0.618034
+
+// Цвет точки внутри множества. Расстояние изнутри берётся в долях высоты экрана.
+vec3 interior_color(Escape escape) {
+    switch (view_position.interior)
+    {
+        case INTERIOR_PERIOD:
+            if (escape.period != 0) return palette_color(float(escape.period) * PERIOD_COLOR_STEP);
+            break;
+        case INTERIOR_MAGNITUDE:
+            return palette_color(escape.radius / 2.0);
+        case INTERIOR_DISTANCE:
+            float view_height = ldexp(2.0 * view_position.scale, view_position.scale_exponent);
+            float value = escape.interior_distance / view_height;
+            if (escape.period != 0 && has_interior_distance() && !isinf(value) && !isnan(value)) 
+                return palette_color(value);
+            break;
+    }
+    return view_position.fract_color;
+}
+
+// Высота рельефа: значение итераций для палитры или логарифм оценки расстояния в пикселях
+#define MIN_RELIEF_DISTANCE 1e-3
+float relief_height(Escape escape) {
+    if (view_position.light_height == HEIGHT_DISTANCE) 
+        return log(max(escape.distance, MIN_RELIEF_DISTANCE));
+    return iteration_value(escape);
+}
+
+#if defined(HISTOGRAM_PASS)
+
+// Второй проход собирается из одного шейдера на обе стадии, поэтому у них
+// общая раскладка дескрипторов: сначала накопление на одной рабочей группе,
+// затем раскраска по пикселю на поток
+layout(constant_id = 1) const uint HISTOGRAM_STAGE = 0u;
+#define HISTOGRAM_STAGE_CUMULATIVE 0u
+#define HISTOGRAM_STAGE_COLOR 1u
+
+// Группа не больше 16 x 16 со стороной - степенью двойки, поэтому её размер делит HISTOGRAM_BINS
+#define GROUP_SIZE (gl_WorkGroupSize.x * gl_WorkGroupSize.y)
+shared uint group_sums[GROUP_SIZE];
+
+// counts[i] становится числом точек во всех долях до i, counts[HISTOGRAM_BINS] - всех точек
+void cumulative_histogram() {
+    uint index = gl_LocalInvocationIndex;
+    uint per_thread = HISTOGRAM_BINS / GROUP_SIZE;
+    uint first = index * per_thread;
+
+    uint sum = 0u;
+    for (uint i = first; i < first + per_thread; i++) sum += histogram.counts[i];
+    group_sums[index] = sum;
+    memoryBarrierShared();
+    barrier();
+
+    uint total = 0u;
+    for (uint i = 0u; i < index; i++) total += group_sums[i];
+    for (uint i = first; i < first + per_thread; i++)
+    {
+        uint count = histogram.counts[i];
+        histogram.counts[i] = total;
+        total += count;
     }
+    if (index == GROUP_SIZE - 1u) histogram.counts[HISTOGRAM_BINS] = total;
+}
+
+// Доля точек, вышедших раньше этого пикселя, внутри доли гистограммы линейно
+void equalized_color() {
+    if (any(greaterThanEqual(gl_GlobalInvocationID.xy, uvec2(imageSize(img))))) return;
+
+    uint pixel = gl_GlobalInvocationID.y * uint(imageSize(img).x) + gl_GlobalInvocationID.x;
+    float value = histogram.values[pixel];
+    // Внутренние точки раскрасил первый проход
+    if (value < 0.0) return;
+
+    float position = clamp(value, 0.0, 1.0) * float(HISTOGRAM_BINS);
+    uint bin = min(uint(position), HISTOGRAM_BINS - 1u);
+    float below = float(histogram.counts[bin]);
+    float above = float(histogram.counts[bin + 1u]);
+    float total = max(float(histogram.counts[HISTOGRAM_BINS]), 1.0);
+    float equalized = mix(below, above, position - float(bin)) / total;
+    imageStore(img, ivec2(gl_GlobalInvocationID.xy), vec4(palette_color(equalized).bgr, 1.0));
+}
+
+void main() {
+    if (HISTOGRAM_STAGE == HISTOGRAM_STAGE_CUMULATIVE) cumulative_histogram();
+    else equalized_color();
+}
+
+#elif defined(LIGHTING_PASS)
+
+// Освещение по Блинну-Фонгу: рассеянный свет не опускается ниже AMBIENT,
+// блик белый, смотрим на картинку сверху
+#define AMBIENT 0.3
+#define SHININESS 32.0
 
-    if (iterations == view_position.quality)
+// Высота соседа, на краю картинки и внутри множества - своя
+float neighbour_height(ivec2 position, float own) {
+    ivec2 clamped = clamp(position, ivec2(0), imageSize(img) - 1);
+    float height = heights.values[clamped.y * imageSize(img).x + clamped.x];
+    return height == NO_HEIGHT ? own : height;
+}
+
+void main() {
+    ivec2 position = ivec2(gl_GlobalInvocationID.xy);
+    if (any(greaterThanEqual(position, imageSize(img)))) return;
+
+    float height = heights.values[position.y * imageSize(img).x + position.x];
+    if (height == NO_HEIGHT) return;
+
+    vec2 slope = 0.5 * view_position.light_relief * vec2(
+        neighbour_height(position + ivec2(1, 0), height) - neighbour_height(position - ivec2(1, 0), height),
+        neighbour_height(position + ivec2(0, 1), height) - neighbour_height(position - ivec2(0, 1), height)
+    );
+    vec3 normal = normalize(vec3(-slope, 1.0));
+    // y картинки растёт вниз, а азимут PI / 2 - свет с верха экрана
+    float azimuth = view_position.light_azimuth;
+    float elevation = view_position.light_elevation;
+    vec3 light = vec3(cos(elevation) * vec2(cos(azimuth), -sin(azimuth)), sin(elevation));
+    float diffuse = max(dot(normal, light), 0.0);
+    vec3 half_way = normalize(light + vec3(0.0, 0.0, 1.0));
+    float highlight = pow(max(dot(normal, half_way), 0.0), SHININESS);
+
+    vec4 color = imageLoad(img, position);
+    color.rgb = color.rgb * mix(AMBIENT, 1.0, diffuse) + view_position.light_specular * highlight;
+    imageStore(img, position, color);
+}
+
+#else
+
+// Плитка, с которой начинается вызов ядра, в клетках прохода, см. refinement.rs
+layout(push_constant) uniform Tile {
+    uvec2 origin;
+} tile;
+
+// Точка пикселя: цвет для усреднения и сравнения с соседями, значение итераций для
+// гистограммы (-1 внутри множества) и высота рельефа
+struct PixelSample {
+    vec3 color;
+    float value;
+    float height;
+};
+
+PixelSample take_sample(vec2 pixel) {
+    vec2 screen = (2.0 * pixel - vec2(imageSize(img))) / float(imageSize(img).y);
+    Escape escape = escape_time(screen);
+
+    if (escape.iterations == view_position.quality)
+    {
+        vec3 color = view_position.coloring == COLORING_LINE_ART 
+            ? line_art_background() : interior_color(escape);
+        return PixelSample(color, -1.0, NO_HEIGHT);
+    }
+    float value = iteration_value(escape) / view_position.quality;
+    vec3 color;
+    if (view_position.coloring == COLORING_ORBIT_TRAP)
     {
-        vec4 to_write = vec4(view_position.fract_color.bgr, 1.0);
-        imageStore(img, ivec2(gl_GlobalInvocationID.xy), to_write);
+        // Пиксель картинки, который поймал орбиту, иначе палитра по расстоянию до ловушки
+        color = escape.trap_color.a != 0.0 ? escape.trap_color.rgb : palette_color(escape.trap);
     }
     else
     {
-        float iters = float(iterations) / view_position.quality;
-        vec4 to_write = vec4(view_position.color.bgr * iters, 1.0);
-        imageStore(img, ivec2(gl_GlobalInvocationID.xy), to_write);
+        // У гистограммы цвет до выравнивания нужен только для сравнения с соседями
+        color = palette_color(value);
+        // Граница в цвете множества поверх палитры или на пустом фоне
+        if (view_position.coloring == COLORING_DISTANCE)
+            color = mix(view_position.fract_color, color, boundary_shade(escape.distance));
+        else if (view_position.coloring == COLORING_LINE_ART)
+            color = mix(view_position.fract_color, line_art_background(), boundary_shade(escape.distance));
     }
+    return PixelSample(color, value, relief_height(escape));
 }
+
+// Перемешивание битов для сдвигов точек, одинаковое на CPU, см. cpu_renderer.rs
+uint hash(uint x) {
+    x ^= x >> 16;
+    x *= 0x7feb352du;
+    x ^= x >> 15;
+    x *= 0x846ca68bu;
+    x ^= x >> 16;
+    return x;
+}
+
+// Сдвиг точки (i, j) внутри пикселя: середина клетки сетки side x side или, вразброс,
+// случайное место в ней
+vec2 sample_offset(uvec2 pixel, uint i, uint j, uint side) {
+    vec2 inside_cell = vec2(0.5);
+    if (view_position.sample_pattern == PATTERN_JITTERED)
+    {
+        uint seed = hash(pixel.x + hash(pixel.y + hash(j * side + i)));
+        inside_cell = vec2(hash(seed) >> 8, hash(seed + 1u) >> 8) / 16777216.0;
+    }
+    return (vec2(i, j) + inside_cell) / float(side);
+}
+
+// Среднее всех точек пикселя. Значение и высота усредняются только по вышедшим точкам,
+// поэтому на границе у гистограммы сглаживания нет.
+PixelSample average_samples(uvec2 pixel) {
+    uint side = min(view_position.samples, MAX_SAMPLES);
+    vec3 color = vec3(0.0);
+    float value = 0.0;
+    float height = 0.0;
+    uint escaped = 0u;
+    for (uint j = 0u; j < side; j++)
+    {
+        for (uint i = 0u; i < side; i++)
+        {
+            PixelSample point_sample = take_sample(vec2(pixel) + sample_offset(pixel, i, j, side));
+            color += point_sample.color;
+            if (point_sample.value < 0.0) continue;
+            value += point_sample.value;
+            height += point_sample.height;
+            escaped += 1u;
+        }
+    }
+    color /= float(side * side);
+    if (escaped == 0u) return PixelSample(color, -1.0, NO_HEIGHT);
+    return PixelSample(color, value / float(escaped), height / float(escaped));
+}
+
+bool in_image(ivec2 pixel) {
+    return all(greaterThanEqual(pixel, ivec2(0))) && all(lessThan(pixel, imageSize(img)));
+}
+
+// Адаптивная выборка: сначала одна точка на пиксель, остальные - только если цвет
+// заметно отличается от соседнего. Первые точки рабочей группы и рамки в пиксель
+// вокруг неё лежат в общей памяти.
+#define ADAPTIVE_CONTRAST 0.1
+// Рабочие группы квадратные
+#define HALO_SIDE (int(gl_WorkGroupSize.x) + 2)
+shared PixelSample halo[HALO_SIDE * HALO_SIDE];
+
+bool differs_from_neighbours(ivec2 pixel, ivec2 local) {
+    PixelSample own = halo[local.y * HALO_SIDE + local.x];
+    ivec2 offsets[4] = ivec2[4](ivec2(1, 0), ivec2(-1, 0), ivec2(0, 1), ivec2(0, -1));
+    for (int k = 0; k < 4; k++)
+    {
+        if (!in_image(pixel + offsets[k] * int(view_position.block_size))) continue;
+        ivec2 neighbour = local + offsets[k];
+        vec3 difference = abs(halo[neighbour.y * HALO_SIDE + neighbour.x].color - own.color);
+        if (max(difference.r, max(difference.g, difference.b)) > ADAPTIVE_CONTRAST) return true;
+    }
+    return false;
+}
+
+void write_pixel(ivec2 pixel, PixelSample result) {
+    uint index = uint(pixel.y * imageSize(img).x + pixel.x);
+    if (view_position.lighting != 0u) heights.values[index] = result.height;
+
+    if (view_position.coloring == COLORING_HISTOGRAM)
+    {
+        // Цвет ставит второй проход, когда гистограмма всего кадра готова
+        if (result.value >= 0.0)
+        {
+            uint bin = min(uint(result.value * float(HISTOGRAM_BINS)), HISTOGRAM_BINS - 1u);
+            atomicAdd(histogram.counts[bin], 1u);
+        }
+        else imageStore(img, pixel, vec4(result.color.bgr, 1.0));
+        histogram.values[index] = result.value;
+    }
+    else imageStore(img, pixel, vec4(result.color.bgr, 1.0));
+}
+
+// В проходах уточнения окна поток считает левый верхний пиксель блока и закрашивает весь блок
+void write_block(ivec2 pixel, PixelSample result) {
+    ivec2 block_end = min(pixel + int(view_position.block_size), imageSize(img));
+    for (int y = pixel.y; y < block_end.y; y++)
+    {
+        for (int x = pixel.x; x < block_end.x; x++) write_pixel(ivec2(x, y), result);
+    }
+}
+
+void main() {
+    ivec2 cell = ivec2(tile.origin + gl_GlobalInvocationID.xy);
+    ivec2 pixel = cell * int(view_position.block_size);
+    bool supersampled = view_position.samples > 1u;
+    // Чётные клетки совпадают с клетками прошлого, вдвое более грубого прохода
+    bool computed = view_position.keep_previous != 0u && all(equal(cell % 2, ivec2(0)));
+
+    if (supersampled && view_position.adaptive != 0u)
+    {
+        ivec2 origin = ivec2(tile.origin + gl_WorkGroupID.xy * gl_WorkGroupSize.xy) - 1;
+        uint group_size = gl_WorkGroupSize.x * gl_WorkGroupSize.y;
+        for (uint i = gl_LocalInvocationIndex; i < HALO_SIDE * HALO_SIDE; i += group_size)
+        {
+            ivec2 halo_pixel = (origin + ivec2(i % HALO_SIDE, i / HALO_SIDE)) 
+                * int(view_position.block_size);
+            if (in_image(halo_pixel)) halo[i] = take_sample(vec2(halo_pixel));
+        }
+        memoryBarrierShared();
+        barrier();
+
+        if (!in_image(pixel) || computed) return;
+        ivec2 local = ivec2(gl_LocalInvocationID.xy) + 1;
+        write_block(pixel, differs_from_neighbours(pixel, local) 
+            ? average_samples(uvec2(pixel)) : halo[local.y * HALO_SIDE + local.x]);
+        return;
+    }
+
+    if (!in_image(pixel) || computed) return;
+    write_block(pixel, supersampled ? average_samples(uvec2(pixel)) : take_sample(vec2(pixel)));
+}
+
+#endif
@@ -0,0 +1,2 @@
+pub mod rvm;
+mod ui;
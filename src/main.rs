@@ -1,15 +1,35 @@
-mod rvm;
-mod ui;
+mod cli;
+
+use std::process;
+
+use clap::Parser;
+
+use cli::{ Cli, Command };
+use rvm::rvm::{ headless, device_list, main_old };
+use rvm::rvm::view_position::ViewPosition;
+use rvm::rvm::refinement::DEFAULT_FRAME_BUDGET;
 
 fn main() {
-    // let event_loop = EventLoop::new();
-    // let window_builder = WindowBuilder::new()
-    //     .with_title(format!("RVM {}", VERSION))
-    //     .with_inner_size(PhysicalSize::new(1000, 800));
-    // let window = match window_builder.build(&event_loop) {
-    //     Ok(win) => Arc::new(win),
-    //     Err(err) => { println!("Window creating error: {:?}", err); return; }
-    // };
-
-    rvm::main_old::main_old();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Render { view, size, out, cpu, precision }) => {
+            let (width, height) = size;
+            let view_position = view.view_position();
+            let result = headless::render_to_png(
+                &view_position, width, height, &out, cpu, precision);
+            if let Err(err) = result {
+                println!("Headless rendering error: {:?}", err);
+                process::exit(1);
+            }
+        }
+        Some(Command::View { view, watch_shader, frame_budget }) => 
+            main_old::main_old(view.view_position(), watch_shader, frame_budget),
+        Some(Command::Devices) => {
+            if let Err(err) = device_list::print_devices() {
+                println!("Devices listing error: {:?}", err);
+                process::exit(1);
+            }
+        }
+        None => main_old::main_old(ViewPosition::new(), None, DEFAULT_FRAME_BUDGET),
+    }
 }
@@ -0,0 +1,92 @@
+/// How escaped points are colored, `COLORING` of `compute.glsl`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Coloring {
+    /// `iterations / quality`, in visible bands.
+    #[default]
+    IterationCount,
+    /// Normalized iteration count: the fraction of an iteration is taken from
+    /// how far past the bailout `z` landed, so the bands blend into each other.
+    Smooth,
+    /// Histogram equalization of the smooth values: a point takes its share of
+    /// the escaped points that escaped earlier, so every zoom spreads over
+    /// the whole palette. Needs a second pass over the finished frame.
+    Histogram,
+    /// Smooth coloring with the boundary drawn in the color of the set:
+    /// the derivative of `z` is iterated too, which gives the distance to
+    /// the set, so filaments thinner than a pixel stay visible.
+    Distance,
+    /// Only the boundary, found by the distance estimate, in the color of
+    /// the set over the last color of the palette.
+    LineArt,
+    /// The palette at the closest the orbit came to `ViewPosition::trap`.
+    OrbitTrap,
+}
+
+impl Coloring {
+    pub const ALL: [Coloring; 6] = [Coloring::IterationCount, Coloring::Smooth, 
+        Coloring::Histogram, Coloring::Distance, Coloring::LineArt, Coloring::OrbitTrap];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Coloring::IterationCount => "iteration-count",
+            Coloring::Smooth => "smooth",
+            Coloring::Histogram => "histogram",
+            Coloring::Distance => "distance",
+            Coloring::LineArt => "line-art",
+            Coloring::OrbitTrap => "orbit-trap",
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Coloring::IterationCount => 0,
+            Coloring::Smooth => 1,
+            Coloring::Histogram => 2,
+            Coloring::Distance => 3,
+            Coloring::LineArt => 4,
+            Coloring::OrbitTrap => 5,
+        }
+    }
+}
+
+/// How points inside the set are colored, `INTERIOR_*` of `compute.glsl`.
+/// Only the modes that color by the cycle look for the one the orbit falls
+/// into, and they stop iterating once it is found, so the views that are
+/// mostly inside render faster.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Interior {
+    /// `fract_color` everywhere.
+    #[default]
+    Flat,
+    /// The palette by the length of the cycle, `fract_color` if none was found.
+    Period,
+    /// The palette by the last `|z|`, once over the disk of radius 2.
+    Magnitude,
+    /// The palette by the distance to the boundary from inside, as a share
+    /// of the view height. Only `z^2 + c` has it, other formulas and Julia
+    /// sets get `fract_color`.
+    Distance,
+}
+
+impl Interior {
+    pub const ALL: [Interior; 4] = [Interior::Flat, Interior::Period, Interior::Magnitude, 
+        Interior::Distance];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interior::Flat => "flat",
+            Interior::Period => "period",
+            Interior::Magnitude => "magnitude",
+            Interior::Distance => "distance",
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Interior::Flat => 0,
+            Interior::Period => 1,
+            Interior::Magnitude => 2,
+            Interior::Distance => 3,
+        }
+    }
+}
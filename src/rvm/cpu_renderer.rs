@@ -0,0 +1,1015 @@
+use super::view_position::{ ViewPosition, GpuViewPosition, join_f64 };
+use super::precision::Precision;
+use super::formula::Formula;
+use super::coloring::{ Coloring, Interior };
+use super::palette::{ Palette, MAX_STOPS };
+use super::histogram::Histogram;
+use super::orbit_trap::{ TrapShape, TrapImage };
+use super::lighting::HeightField;
+use super::supersampling::{ SamplePattern, MAX_SAMPLES };
+use super::reference_orbit::ReferenceOrbit;
+use super::series_approximation::SeriesApproximation;
+
+use std::thread;
+
+use num_traits::Float;
+
+/// Reference implementation of `compute.glsl` on the CPU.
+/// Follows the shader step by step, starting from the same `GpuViewPosition`
+/// the kernel gets, so the output matches the GPU up to rounding differences
+/// of the driver. `DoubleSingle` is computed in `f64`, which it emulates.
+pub fn render(view_position: &ViewPosition, width: u32, height: u32, precision: Precision)
+-> Vec<u8> {
+    let precision = precision.supported_by(&view_position.formula);
+    let gpu_view_position = GpuViewPosition::from(view_position);
+    let reference_orbit = match precision {
+        Precision::Perturbation => {
+            let reference_orbit = ReferenceOrbit::new(view_position);
+            let series = SeriesApproximation::for_screen(
+                &reference_orbit, view_position, width, height);
+            Some((reference_orbit, series))
+        }
+        _ => None,
+    };
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    if pixels.is_empty() { return pixels }
+
+    let image = Image::new(&gpu_view_position, reference_orbit.as_ref(), precision, 
+        view_position, (width, height));
+    let supersampling = view_position.supersampling;
+    // Первые точки всех пикселей, с которыми адаптивная выборка сравнивает соседей
+    let first_samples = (supersampling.is_enabled() && supersampling.adaptive)
+        .then(|| render_in_parallel(width, height, |x, y| image.take_sample([x as f32, y as f32])));
+    let samples = render_in_parallel(width, height, 
+        |x, y| image.pixel_sample(x, y, first_samples.as_deref()));
+    for (pixel, sample) in pixels.chunks_exact_mut(4).zip(&samples) {
+        pixel.copy_from_slice(&to_pixel(sample.color));
+    }
+
+    // Второй проход: гистограмма готова, только когда посчитан весь кадр
+    if view_position.coloring == Coloring::Histogram {
+        // Значения пикселей, как в histogram.values шейдера
+        let values: Vec<f32> = samples.iter().map(|sample| sample.value).collect();
+        let histogram = Histogram::new(&values);
+        for (pixel, value) in pixels.chunks_exact_mut(4).zip(&values) {
+            if *value < 0.0 { continue }
+            pixel.copy_from_slice(&to_pixel(sample_color(&gpu_view_position, 
+                &view_position.palette, histogram.equalize(*value), Escape::default())));
+        }
+    }
+    if view_position.lighting.enabled {
+        let heights: Vec<f32> = samples.iter().map(|sample| sample.height).collect();
+        light(&gpu_view_position, &mut pixels, &heights, width, height);
+    }
+    pixels
+}
+
+/// `pixel(x, y)` for every pixel of the image, computed by all threads.
+fn render_in_parallel<T, F>(width: u32, height: u32, pixel: F) -> Vec<T>
+where T: Default + Clone + Send, F: Fn(u32, u32) -> T + Sync {
+    let mut output = vec![T::default(); width as usize * height as usize];
+    let threads_count = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(height as usize);
+
+    // Строки раздаются через одну, чтобы тяжёлая середина множества
+    // делилась между всеми потоками поровну
+    let mut thread_rows: Vec<Vec<(u32, &mut [T])>> = (0..threads_count).map(|_| vec![]).collect();
+    for (y, row) in output.chunks_mut(width as usize).enumerate() {
+        thread_rows[y % threads_count].push((y as u32, row));
+    }
+    thread::scope(|scope| {
+        for rows in thread_rows {
+            let pixel = &pixel;
+            scope.spawn(move || {
+                for (y, row) in rows {
+                    for (x, output) in row.iter_mut().enumerate() {
+                        *output = pixel(x as u32, y);
+                    }
+                }
+            });
+        }
+    });
+    output
+}
+
+/// `PixelSample` of the shader: the color of a point, its value for the
+/// histogram, -1 inside the set, and its height for the lighting.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct PixelSample {
+    pub color: [f32; 3],
+    pub value: f32,
+    pub height: f32,
+}
+
+/// Colors of neighbouring first samples that differ by more than this in
+/// any channel make adaptive supersampling take the rest of the samples.
+pub const ADAPTIVE_CONTRAST: f32 = 0.1;
+
+/// Everything the pixels of one image share.
+struct Image<'a> {
+    view_position: &'a GpuViewPosition,
+    reference_orbit: Option<&'a (ReferenceOrbit, SeriesApproximation)>,
+    precision: Precision,
+    formula: &'a Formula,
+    palette: &'a Palette,
+    size: (u32, u32),
+    bailout: f32,
+    distance: bool,
+    tracking: Tracking<'a>,
+}
+
+impl<'a> Image<'a> {
+    fn new(gpu_view_position: &'a GpuViewPosition, 
+        reference_orbit: Option<&'a (ReferenceOrbit, SeriesApproximation)>, precision: Precision, 
+        view_position: &'a ViewPosition, size: (u32, u32))
+    -> Self {
+        let trap = Trap::new(gpu_view_position, &view_position.trap.image);
+        let interior_distance = has_interior_distance(gpu_view_position, &view_position.formula);
+        let actual_zoom = (gpu_view_position.zoom / 10.0).exp();
+        let cycle_epsilon = tracks_cycles(gpu_view_position)
+            .then_some((2.0 / (size.1 as f32 * actual_zoom) * PERIOD_EPSILON) as f64);
+        Image {
+            view_position: gpu_view_position,
+            reference_orbit,
+            precision,
+            formula: &view_position.formula,
+            palette: &view_position.palette,
+            size,
+            bailout: escape_radius(gpu_view_position),
+            distance: tracks_distance(gpu_view_position),
+            tracking: Tracking { trap, cycle_epsilon, interior_distance, 
+                ..Default::default() },
+        }
+    }
+
+    /// One point of the image, `pixel` in pixels from the top left corner.
+    fn take_sample(&self, pixel: [f32; 2]) -> PixelSample {
+        let Image { view_position, reference_orbit, precision, formula, palette, size, bailout, 
+            distance, tracking } = *self;
+        let julia = view_position.julia != 0;
+        let screen = pixel_to_screen(size.0, size.1, pixel);
+        let escape = match (precision, reference_orbit) {
+            (Precision::Perturbation, Some((reference_orbit, series))) => {
+                let dc = screen_to_delta(view_position, screen);
+                let pixel_size = (view_position.scale as f64 
+                    * 2f64.powi(view_position.scale_exponent)) * 2.0 / size.1 as f64;
+                escape_time_perturbation(reference_orbit, series, dc, view_position.quality, 
+                    bailout, distance.then_some(pixel_size), 
+                    Tracking { cycle_epsilon: tracking.cycle_epsilon
+                        .map(|_| pixel_size * PERIOD_EPSILON as f64), ..tracking })
+            }
+            (Precision::Single, _) => {
+                let point = screen_to_complex(view_position, screen);
+                let (z, c) = if julia {
+                    (point, [view_position.julia_x[0], view_position.julia_y[0]])
+                }
+                else { ([0.0, 0.0], point) };
+                let derivative = distance.then(|| Derivative::start(view_position, size.1, julia));
+                escape_time(z, c, formula, view_position.power, view_position.quality, bailout, 
+                    Tracking { derivative, ..tracking })
+            }
+            _ => {
+                let point = screen_to_complex_f64(view_position, screen);
+                let (z, c) = if julia {
+                    (point, [join_f64(view_position.julia_x), join_f64(view_position.julia_y)])
+                }
+                else { ([0.0, 0.0], point) };
+                let derivative = distance.then(|| Derivative::start(view_position, size.1, julia));
+                escape_time_f64(z, c, formula, view_position.power, view_position.quality, 
+                    bailout, Tracking { derivative, ..tracking })
+            }
+        };
+        let degree = formula.degree(view_position.power);
+        let value = pixel_value(view_position, degree, escape);
+        let height = if escape.iterations != view_position.quality {
+            relief_height(view_position, degree, escape)
+        }
+        else { NO_HEIGHT };
+        PixelSample { color: sample_color(view_position, palette, value, escape), value, height }
+    }
+
+    /// The pixel as the kernel writes it: one sample, the average of all of
+    /// them, or with `first_samples` of adaptive supersampling all of them
+    /// only where a neighbour differs.
+    fn pixel_sample(&self, x: u32, y: u32, first_samples: Option<&[PixelSample]>) -> PixelSample {
+        if self.view_position.samples <= 1 { return self.take_sample([x as f32, y as f32]) }
+        let Some(first_samples) = first_samples else { return self.average_samples(x, y) };
+
+        let (width, height) = (self.size.0 as i64, self.size.1 as i64);
+        let own = first_samples[(y * self.size.0 + x) as usize];
+        let differs = [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|(dx, dy)| {
+            let (x, y) = (x as i64 + dx, y as i64 + dy);
+            if x < 0 || y < 0 || x >= width || y >= height { return false }
+            let neighbour = first_samples[(y * width + x) as usize];
+            (0..3).any(|i| (neighbour.color[i] - own.color[i]).abs() > ADAPTIVE_CONTRAST)
+        });
+        if differs { self.average_samples(x, y) } else { own }
+    }
+
+    /// `average_samples` of the shader: value and height only over the
+    /// escaped samples.
+    fn average_samples(&self, x: u32, y: u32) -> PixelSample {
+        let side = self.view_position.samples.min(MAX_SAMPLES);
+        let mut color = [0.0; 3];
+        let (mut value, mut height, mut escaped) = (0.0, 0.0, 0);
+        for j in 0..side {
+            for i in 0..side {
+                let offset = sample_offset(self.view_position, [x, y], [i, j]);
+                let sample = self.take_sample([x as f32 + offset[0], y as f32 + offset[1]]);
+                color = [0, 1, 2].map(|channel| color[channel] + sample.color[channel]);
+                if sample.value < 0.0 { continue }
+                value += sample.value;
+                height += sample.height;
+                escaped += 1;
+            }
+        }
+        let color = color.map(|channel| channel / (side * side) as f32);
+        if escaped == 0 { return PixelSample { color, value: -1.0, height: NO_HEIGHT } }
+        PixelSample { color, value: value / escaped as f32, height: height / escaped as f32 }
+    }
+}
+
+/// Offset of the sample `cell` of a pixel from its top left corner: the
+/// middle of the cell of the grid, or a point in it picked by a hash of
+/// `pixel` for the jittered pattern.
+pub fn sample_offset(view_position: &GpuViewPosition, pixel: [u32; 2], cell: [u32; 2]) -> [f32; 2] {
+    let side = view_position.samples.clamp(1, MAX_SAMPLES);
+    let inside_cell = if view_position.sample_pattern == SamplePattern::Jittered.id() {
+        let seed = hash(pixel[0].wrapping_add(hash(pixel[1].wrapping_add(hash(cell[1] * side + cell[0])))));
+        [hash(seed) >> 8, hash(seed.wrapping_add(1)) >> 8].map(|bits| bits as f32 / 16777216.0)
+    }
+    else { [0.5, 0.5] };
+    [
+        (cell[0] as f32 + inside_cell[0]) / side as f32, 
+        (cell[1] as f32 + inside_cell[1]) / side as f32,
+    ]
+}
+
+/// `hash` of the shader.
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+/// `pixel` in pixels from the top left corner of the image.
+pub fn pixel_to_screen(width: u32, height: u32, pixel: [f32; 2]) -> [f32; 2] {
+    let size = [width as f32, height as f32];
+    [
+        (2.0 * pixel[0] - size[0]) / size[1],
+        (2.0 * pixel[1] - size[1]) / size[1],
+    ]
+}
+
+pub fn screen_to_complex(view_position: &GpuViewPosition, screen: [f32; 2]) -> [f32; 2] {
+    let actual_zoom = (view_position.zoom / 10.0).exp();
+    let actual_pos = [
+        view_position.center_x[0] * actual_zoom,
+        view_position.center_y[0] * actual_zoom,
+    ];
+    [
+        (screen[0] + actual_pos[0]) / actual_zoom,
+        (screen[1] + actual_pos[1]) / actual_zoom,
+    ]
+}
+
+pub fn screen_to_complex_f64(view_position: &GpuViewPosition, screen: [f32; 2]) -> [f64; 2] {
+    let actual_zoom = (view_position.zoom / 10.0).exp();
+    [
+        join_f64(view_position.center_x) + (screen[0] / actual_zoom) as f64,
+        join_f64(view_position.center_y) + (screen[1] / actual_zoom) as f64,
+    ]
+}
+
+/// Offset of the pixel from the view centre.
+pub fn screen_to_delta(view_position: &GpuViewPosition, screen: [f32; 2]) -> [f64; 2] {
+    let exponent = 2f64.powi(view_position.scale_exponent);
+    [
+        (screen[0] * view_position.scale) as f64 * exponent,
+        (screen[1] * view_position.scale) as f64 * exponent,
+    ]
+}
+
+/// Iterations a point made before escaping, `quality` if it never did.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Escape {
+    pub iterations: u32,
+    /// `|z|` at the first iteration past the bailout.
+    pub radius: f32,
+    /// Estimated distance to the set in pixels, 0 unless it was tracked.
+    pub distance: f32,
+    /// Closest the orbit came to the trap, `NO_TRAP` if it never got checked.
+    pub trap: f32,
+    /// Opaque pixel of the trap image the orbit hit first.
+    pub trap_color: Option<[f32; 3]>,
+    /// Length of the cycle an inside point fell into, 0 if none was found.
+    pub period: u32,
+    /// Distance to the boundary from inside, if it was tracked and a cycle found.
+    pub interior_distance: f32,
+}
+
+/// `Escape::trap` of an orbit that never met the trap.
+pub const NO_TRAP: f32 = 1e20;
+
+/// What is followed along the orbit besides `z`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Tracking<'a> {
+    pub derivative: Option<Derivative>,
+    pub trap: Option<Trap<'a>>,
+    /// How close the orbit has to come back to a point it passed to count
+    /// as caught in a cycle, which ends it as an inside point. `None` unless
+    /// the interior is colored by the cycle.
+    pub cycle_epsilon: Option<f64>,
+    /// Whether to estimate `Escape::interior_distance` of the cycle.
+    pub interior_distance: bool,
+}
+
+/// Share of the pixel size that makes `Tracking::cycle_epsilon`.
+pub const PERIOD_EPSILON: f32 = 1e-3;
+
+/// Derivative of `z` over `c`, or over the starting `z` for Julia sets,
+/// measured in pixels, see `distance_estimate`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Derivative {
+    pub value: [f32; 2],
+    /// Added on every step: the pixel size, or 0 for Julia sets.
+    pub dc: f32,
+}
+
+impl Derivative {
+    pub fn start(view_position: &GpuViewPosition, height: u32, julia: bool) -> Self {
+        let actual_zoom = (view_position.zoom / 10.0).exp();
+        let pixel_size = 2.0 / (height as f32 * actual_zoom);
+        if julia { Derivative { value: [pixel_size, 0.0], dc: 0.0 } }
+        else { Derivative { value: [0.0, 0.0], dc: pixel_size } }
+    }
+}
+
+/// The derivative of `tracking` is iterated too for `Escape::distance`,
+/// every point of the orbit before the escape is checked against the trap.
+pub fn escape_time(z: [f32; 2], c: [f32; 2], formula: &Formula, power: f32, quality: u32, 
+    bailout: f32, tracking: Tracking) -> Escape {
+    let mut z = z;
+    let Tracking { mut derivative, trap, cycle_epsilon, interior_distance: interior } = tracking;
+    let mut trap_state = TrapState::default();
+    let mut cycle = cycle_epsilon.map(|epsilon| CycleSearch::new(z, 0, epsilon as f32));
+    let mut period = 0;
+    let mut iterations = 0;
+    let mut radius = 0.0;
+    while iterations < quality {
+        if let Some(Derivative { value, dc }) = &mut derivative {
+            *value = derivative_step(z, c, *value, *dc, formula, power);
+        }
+        z = formula_step(z, c, formula, power);
+
+        radius = (z[0] * z[0] + z[1] * z[1]).sqrt();
+        if radius > bailout { break }
+        iterations += 1;
+        if let Some(trap) = &trap { trap_state.step(trap, z) }
+
+        if let Some(found) = cycle.as_mut().and_then(|cycle| cycle.step(z, iterations)) {
+            period = found;
+            iterations = quality;
+            break;
+        }
+    }
+    let distance = derivative.map_or(0.0, |derivative| distance_estimate(radius, 
+        (derivative.value[0] * derivative.value[0] + derivative.value[1] * derivative.value[1]).sqrt()));
+    if let (true, Some(epsilon)) = (period != 0, cycle_epsilon) {
+        period = cycle_period(z, c, formula, power, period, (epsilon / PERIOD_EPSILON as f64) as f32);
+    }
+    let interior_distance = if period != 0 && interior { interior_distance(z, c, period) } 
+        else { 0.0 };
+    Escape { iterations, radius, distance, trap: trap_state.distance, trap_color: trap_state.color, 
+        period, interior_distance }
+}
+
+pub fn escape_time_f64(z: [f64; 2], c: [f64; 2], formula: &Formula, power: f32, quality: u32, 
+    bailout: f32, tracking: Tracking) -> Escape {
+    let mut z = z;
+    let Tracking { mut derivative, trap, cycle_epsilon, interior_distance: interior } = tracking;
+    let mut trap_state = TrapState::default();
+    let mut cycle = cycle_epsilon.map(|epsilon| CycleSearch::new(z, 0, epsilon));
+    let mut period = 0;
+    let mut iterations = 0;
+    let mut radius = 0.0;
+    while iterations < quality {
+        if let Some(Derivative { value, dc }) = &mut derivative {
+            let to_f32 = |value: [f64; 2]| [value[0] as f32, value[1] as f32];
+            *value = derivative_step(to_f32(z), to_f32(c), *value, *dc, formula, power);
+        }
+        z = formula_step(z, c, formula, power);
+
+        let z_f32 = [z[0] as f32, z[1] as f32];
+        radius = (z_f32[0] * z_f32[0] + z_f32[1] * z_f32[1]).sqrt();
+        if radius > bailout { break }
+        iterations += 1;
+        if let Some(trap) = &trap { trap_state.step(trap, z_f32) }
+
+        if let Some(found) = cycle.as_mut().and_then(|cycle| cycle.step(z, iterations)) {
+            period = found;
+            iterations = quality;
+            break;
+        }
+    }
+    let distance = derivative.map_or(0.0, |derivative| distance_estimate(radius, 
+        (derivative.value[0] * derivative.value[0] + derivative.value[1] * derivative.value[1]).sqrt()));
+    if let (true, Some(epsilon)) = (period != 0, cycle_epsilon) {
+        period = cycle_period(z, c, formula, power, period, epsilon / PERIOD_EPSILON as f64);
+    }
+    let to_f32 = |value: [f64; 2]| [value[0] as f32, value[1] as f32];
+    let interior_distance = if period != 0 && interior { interior_distance(to_f32(z), to_f32(c), period) } 
+        else { 0.0 };
+    Escape { iterations, radius, distance, trap: trap_state.distance, trap_color: trap_state.color, 
+        period, interior_distance }
+}
+
+/// `dz' = f'(z) dz + dc` like `derivative_step` of the shader: the derivative
+/// of `z^2` stands in for the formulas with absolute values and conjugation,
+/// user formulas are differentiated numerically.
+pub fn derivative_step(z: [f32; 2], c: [f32; 2], derivative: [f32; 2], dc: f32, 
+    formula: &Formula, power: f32) -> [f32; 2] {
+    let mul = |a: [f32; 2], b: [f32; 2]| [a[0] * b[0] - a[1] * b[1], a[0] * b[1] + a[1] * b[0]];
+    match formula {
+        Formula::User(user_formula) => {
+            let h = 1e-3 * (z[0] * z[0] + z[1] * z[1]).sqrt().max(1.0);
+            let f = user_formula.step(z, c);
+            let f_z = user_formula.step([z[0] + h, z[1]], c);
+            let f_c = user_formula.step(z, [c[0] + h, c[1]]);
+            let f_z = [(f_z[0] - f[0]) / h, (f_z[1] - f[1]) / h];
+            let f_c = [(f_c[0] - f[0]) / h, (f_c[1] - f[1]) / h];
+            let w = mul(f_z, derivative);
+            [w[0] + dc * f_c[0], w[1] + dc * f_c[1]]
+        }
+        _ => {
+            let f_z = if *formula == Formula::Multibrot {
+                complex_pow(z, power - 1.0).map(|part| power * part)
+            }
+            else { [2.0 * z[0], 2.0 * z[1]] };
+            let w = mul(f_z, derivative);
+            [w[0] + dc, w[1]]
+        }
+    }
+}
+
+/// `|z| ln|z| / |dz|`, in pixels when the derivative is.
+pub fn distance_estimate(radius: f32, derivative: f32) -> f32 {
+    radius * radius.ln() / derivative
+}
+
+/// One iteration of `formula` in `f32` or `f64`. Fractional powers of
+/// `Formula::Multibrot` go through `f32` in both, like in the shader.
+pub fn formula_step<T: Float>(z: [T; 2], c: [T; 2], formula: &Formula, power: f32) -> [T; 2] {
+    let two = T::one() + T::one();
+    let sqr_add = |z: [T; 2], c: [T; 2]| [
+        (z[0] * z[0] - z[1] * z[1]) + c[0],
+        (two * z[0] * z[1]) + c[1],
+    ];
+    match formula {
+        Formula::Mandelbrot => sqr_add(z, c),
+        Formula::BurningShip => sqr_add([z[0].abs(), z[1].abs()], c),
+        Formula::Tricorn => sqr_add([z[0], -z[1]], c),
+        Formula::Multibrot => {
+            let w = complex_pow(z, power);
+            [w[0] + c[0], w[1] + c[1]]
+        }
+        Formula::Celtic => {
+            let w = sqr_add(z, [T::zero(), T::zero()]);
+            [w[0].abs() + c[0], w[1] + c[1]]
+        }
+        Formula::Buffalo => {
+            let w = sqr_add(z, [T::zero(), T::zero()]);
+            [w[0].abs() + c[0], w[1].abs() + c[1]]
+        }
+        Formula::User(user_formula) => {
+            let to_f32 = |value: [T; 2]| value.map(|part| part.to_f32().unwrap_or(0.0));
+            user_formula.step(to_f32(z), to_f32(c)).map(|part| T::from(part).unwrap_or_else(T::zero))
+        }
+    }
+}
+
+/// Integer powers by multiplication in `T`, fractional ones in polar form in `f32`.
+fn complex_pow<T: Float>(z: [T; 2], power: f32) -> [T; 2] {
+    if power >= 1.0 && power == power.floor() {
+        let mut w = z;
+        for _ in 1..power as i32 {
+            w = [w[0] * z[0] - w[1] * z[1], w[0] * z[1] + w[1] * z[0]];
+        }
+        w
+    }
+    else {
+        polar_pow(z.map(|part| part.to_f32().unwrap_or(0.0)), power)
+            .map(|part| T::from(part).unwrap_or_else(T::zero))
+    }
+}
+
+fn polar_pow(z: [f32; 2], power: f32) -> [f32; 2] {
+    if z == [0.0, 0.0] { return z }
+    let angle = z[1].atan2(z[0]) * power;
+    let length = (z[0] * z[0] + z[1] * z[1]).sqrt().powf(power);
+    [length * angle.cos(), length * angle.sin()]
+}
+
+/// Iterates `dz = z - Z` against the reference orbit `Z`, starting where
+/// the series leaves off and rebasing to the start of the orbit when `z`
+/// gets closer to zero than to `Z` (a glitch) or the orbit ends.
+/// With `pixel_size` the derivative of `z` is iterated too, in `f64`.
+/// `tracking.derivative` is not used: with `pixel_size` the derivative is
+/// started from the series and iterated in `f64`.
+pub fn escape_time_perturbation(reference_orbit: &ReferenceOrbit, series: &SeriesApproximation, 
+    dc: [f64; 2], quality: u32, bailout: f32, pixel_size: Option<f64>, tracking: Tracking) 
+-> Escape {
+    let reference = &reference_orbit.points;
+    let julia = reference_orbit.julia;
+    let mul = |a: [f64; 2], b: [f64; 2]| [a[0] * b[0] - a[1] * b[1], a[0] * b[1] + a[1] * b[0]];
+    let Tracking { trap, cycle_epsilon, interior_distance: interior, .. } = tracking;
+    let mut trap_state = TrapState::default();
+    let dc_step = if julia { [0.0, 0.0] } else { dc };
+    let last = reference.len() - 1;
+    let mut dz = series.delta(dc);
+    let mut n = series.skipped as usize;
+    let mut iterations = series.skipped;
+    let start = [reference[n][0] + dz[0], reference[n][1] + dz[1]];
+    let mut cycle = cycle_epsilon.map(|epsilon| CycleSearch::new(start, iterations, epsilon));
+    let mut period = 0;
+    let mut radius = 0.0;
+    // Производная ряда по dc - это и производная z на пропущенных итерациях
+    let mut derivative = pixel_size.map(|pixel_size| {
+        let [a, b, c] = series.coefficients;
+        let w = mul([3.0 * c[0], 3.0 * c[1]], dc);
+        let w = mul([w[0] + 2.0 * b[0], w[1] + 2.0 * b[1]], dc);
+        let value = [(w[0] + a[0]) * pixel_size, (w[1] + a[1]) * pixel_size];
+        (value, if julia { 0.0 } else { pixel_size })
+    });
+    while iterations < quality {
+        if let Some((value, step)) = &mut derivative {
+            let z = [reference[n][0] + dz[0], reference[n][1] + dz[1]];
+            let w = mul(z, *value);
+            *value = [2.0 * w[0] + *step, 2.0 * w[1]];
+        }
+        let a = [2.0 * reference[n][0] + dz[0], 2.0 * reference[n][1] + dz[1]];
+        dz = [
+            (a[0] * dz[0] - a[1] * dz[1]) + dc_step[0],
+            (a[0] * dz[1] + a[1] * dz[0]) + dc_step[1],
+        ];
+        n += 1;
+        let z = [reference[n][0] + dz[0], reference[n][1] + dz[1]];
+
+        let z_f32 = [z[0] as f32, z[1] as f32];
+        radius = (z_f32[0] * z_f32[0] + z_f32[1] * z_f32[1]).sqrt();
+        if radius > bailout { break }
+        iterations += 1;
+        if let Some(trap) = &trap { trap_state.step(trap, z_f32) }
+
+        if let Some(found) = cycle.as_mut().and_then(|cycle| cycle.step(z, iterations)) {
+            period = found;
+            iterations = quality;
+            break;
+        }
+
+        if z[0] * z[0] + z[1] * z[1] < dz[0] * dz[0] + dz[1] * dz[1] || n == last {
+            dz = [z[0] - reference[0][0], z[1] - reference[0][1]];
+            n = 0;
+        }
+    }
+    let distance = derivative.map_or(0.0, |(value, _)| 
+        distance_estimate(radius, (value[0] * value[0] + value[1] * value[1]).sqrt() as f32));
+    let constant = reference_orbit.constant();
+    let c = [constant[0] + dc_step[0], constant[1] + dc_step[1]];
+    let interior_distance = match (&cycle, cycle_epsilon) {
+        (Some(cycle), Some(epsilon)) if period != 0 => {
+            period = cycle_period(cycle.saved, c, &Formula::Mandelbrot, 2.0, period, 
+                epsilon / PERIOD_EPSILON as f64);
+            if interior {
+                interior_distance([cycle.saved[0] as f32, cycle.saved[1] as f32], 
+                    [c[0] as f32, c[1] as f32], period)
+            }
+            else { 0.0 }
+        }
+        _ => 0.0,
+    };
+    Escape { iterations, radius, distance, trap: trap_state.distance, trap_color: trap_state.color, 
+        period, interior_distance }
+}
+
+/// Brent's cycle search of the escape loops: the orbit is compared with a
+/// point saved at doubling intervals until it comes back to it.
+#[derive(Debug, Copy, Clone)]
+struct CycleSearch<T> {
+    saved: [T; 2],
+    saved_at: u32,
+    window: u32,
+    epsilon: T,
+}
+
+impl<T: Float> CycleSearch<T> {
+    fn new(z: [T; 2], iterations: u32, epsilon: T) -> Self {
+        CycleSearch { saved: z, saved_at: iterations, window: 1, epsilon }
+    }
+
+    /// Length of the cycle once `z` is back at the saved point.
+    fn step(&mut self, z: [T; 2], iterations: u32) -> Option<u32> {
+        let (x, y) = (z[0] - self.saved[0], z[1] - self.saved[1]);
+        if (x * x + y * y).sqrt() < self.epsilon {
+            // Точка цикла нужна для внутренней дистанции
+            self.saved = z;
+            return Some(iterations - self.saved_at);
+        }
+        if iterations == self.saved_at + self.window {
+            self.saved = z;
+            self.saved_at = iterations;
+            self.window *= 2;
+        }
+        None
+    }
+}
+
+/// `period` cut down to its smallest divisor that brings the cycle point `z`
+/// back within `tolerance`: an orbit that spirals in can come back to the
+/// saved point only after a few turns.
+pub fn cycle_period<T: Float>(z: [T; 2], c: [T; 2], formula: &Formula, power: f32, period: u32, 
+    tolerance: T) -> u32 {
+    let mut w = z;
+    for divisor in 1..period {
+        w = formula_step(w, c, formula, power);
+        let (x, y) = (w[0] - z[0], w[1] - z[1]);
+        if period.is_multiple_of(divisor) && (x * x + y * y).sqrt() < tolerance { return divisor }
+    }
+    period
+}
+
+/// Whether `interior_distance` is tracked, like `has_interior_distance` of
+/// the shader: only `z^2 + c` has the derivatives it needs.
+pub fn has_interior_distance(view_position: &GpuViewPosition, formula: &Formula) -> bool {
+    view_position.interior == Interior::Distance.id() && view_position.julia == 0 
+        && matches!(formula, Formula::Mandelbrot)
+}
+
+/// Distance from inside to the boundary of the set for the cycle of `period`
+/// through `z0`, in complex units.
+pub fn interior_distance(z0: [f32; 2], c: [f32; 2], period: u32) -> f32 {
+    let mul = |a: [f32; 2], b: [f32; 2]| [a[0] * b[0] - a[1] * b[1], a[0] * b[1] + a[1] * b[0]];
+    let add = |a: [f32; 2], b: [f32; 2]| [a[0] + b[0], a[1] + b[1]];
+    let twice = |a: [f32; 2]| [2.0 * a[0], 2.0 * a[1]];
+    let mut z = z0;
+    let mut dz = [1.0, 0.0];
+    let mut dc = [0.0, 0.0];
+    let mut dzdz = [0.0, 0.0];
+    let mut dcdz = [0.0, 0.0];
+    for _ in 0..period {
+        dcdz = twice(add(mul(z, dcdz), mul(dc, dz)));
+        dzdz = twice(add(mul(dz, dz), mul(z, dzdz)));
+        dc = add(twice(mul(z, dc)), [1.0, 0.0]);
+        dz = twice(mul(z, dz));
+        z = add(mul(z, z), c);
+    }
+    let one_minus = [1.0 - dz[0], -dz[1]];
+    let denominator = one_minus[0] * one_minus[0] + one_minus[1] * one_minus[1];
+    let quotient = mul(mul(dzdz, dc), [one_minus[0] / denominator, -one_minus[1] / denominator]);
+    let sum = add(dcdz, quotient);
+    (1.0 - (dz[0] * dz[0] + dz[1] * dz[1])) / (sum[0] * sum[0] + sum[1] * sum[1]).sqrt()
+}
+
+/// The trap as the kernel sees it, in `f32` from `GpuViewPosition`.
+#[derive(Debug, Copy, Clone)]
+pub struct Trap<'a> {
+    shape: u32,
+    center: [f32; 2],
+    radius: f32,
+    angle: f32,
+    image: &'a TrapImage,
+}
+
+impl<'a> Trap<'a> {
+    /// `None` unless the coloring needs the trap.
+    pub fn new(view_position: &GpuViewPosition, image: &'a TrapImage) -> Option<Self> {
+        if view_position.coloring != Coloring::OrbitTrap.id() { return None }
+        Some(Trap {
+            shape: view_position.trap_shape,
+            center: [view_position.trap_x, view_position.trap_y],
+            radius: view_position.trap_radius,
+            angle: view_position.trap_angle,
+            image,
+        })
+    }
+
+    /// `z` from the centre of the trap, turned by its angle.
+    fn local(&self, z: [f32; 2]) -> [f32; 2] {
+        let offset = [z[0] - self.center[0], z[1] - self.center[1]];
+        let (s, c) = self.angle.sin_cos();
+        [c * offset[0] + s * offset[1], c * offset[1] - s * offset[0]]
+    }
+
+    pub fn distance(&self, z: [f32; 2]) -> f32 {
+        let p = self.local(z);
+        let length = (p[0] * p[0] + p[1] * p[1]).sqrt();
+        match self.shape {
+            shape if shape == TrapShape::Line.id() => p[1].abs(),
+            shape if shape == TrapShape::Cross.id() => p[0].abs().min(p[1].abs()),
+            shape if shape == TrapShape::Circle.id() => (length - self.radius).abs(),
+            _ => length,
+        }
+    }
+
+    /// Opaque pixel of the image under `z`, the longer side of the image
+    /// spans two radii of the trap.
+    pub fn image_color(&self, z: [f32; 2]) -> Option<[f32; 3]> {
+        let image = self.image;
+        if self.shape != TrapShape::Image.id() || image.width() == 0 { return None }
+        let size = [image.width() as f32, image.height() as f32];
+        let p = self.local(z);
+        let scale = size[0].max(size[1]) / (2.0 * self.radius);
+        let pixel = [p[0] * scale + size[0] / 2.0, p[1] * scale + size[1] / 2.0];
+        if pixel[0] < 0.0 || pixel[1] < 0.0 || pixel[0] >= size[0] || pixel[1] >= size[1] {
+            return None;
+        }
+        let [r, g, b, a] = image.pixel(pixel[0] as u32, pixel[1] as u32);
+        (a >= 128).then(|| [r, g, b].map(|channel| channel as f32 / 255.0))
+    }
+}
+
+/// `trap` and `trap_color` of the escape loops in the shader.
+struct TrapState {
+    distance: f32,
+    color: Option<[f32; 3]>,
+}
+
+impl Default for TrapState {
+    fn default() -> Self {
+        TrapState { distance: NO_TRAP, color: None }
+    }
+}
+
+impl TrapState {
+    fn step(&mut self, trap: &Trap, z: [f32; 2]) {
+        self.distance = self.distance.min(trap.distance(z));
+        if self.color.is_none() { self.color = trap.image_color(z) }
+    }
+}
+
+/// Smooth coloring needs `|z|` far from the fractal, where only the highest
+/// power of `z` in the formula matters.
+pub fn escape_radius(view_position: &GpuViewPosition) -> f32 {
+    if view_position.coloring != Coloring::IterationCount.id() { 256.0 } else { 4.0 }
+}
+
+/// Whether the interior is colored by the cycle, `tracks_cycles` of the shader.
+pub fn tracks_cycles(view_position: &GpuViewPosition) -> bool {
+    view_position.interior == Interior::Period.id() || view_position.interior == Interior::Distance.id()
+}
+
+/// Whether the derivative has to be iterated for the coloring.
+pub fn tracks_distance(view_position: &GpuViewPosition) -> bool {
+    view_position.coloring == Coloring::Distance.id() 
+        || view_position.coloring == Coloring::LineArt.id()
+        || (view_position.lighting != 0 && view_position.light_height == HeightField::Distance.id())
+}
+
+/// `iteration_value` of the shader: the iteration count for the palette,
+/// with a fractional part in the smooth and histogram colorings.
+pub fn iteration_value(view_position: &GpuViewPosition, degree: f32, escape: Escape) -> f32 {
+    if view_position.coloring == Coloring::IterationCount.id() { return escape.iterations as f32 }
+
+    let fraction = (escape.radius.ln() / escape_radius(view_position).ln()).ln() / degree.ln();
+    let fraction = if fraction.is_nan() { 0.0 } else { fraction.clamp(0.0, 1.0) };
+    escape.iterations as f32 + 1.0 - fraction
+}
+
+/// Iteration value over `quality`, `PixelSample.value` of the shader, -1
+/// inside the set.
+pub fn pixel_value(view_position: &GpuViewPosition, degree: f32, escape: Escape) -> f32 {
+    if escape.iterations == view_position.quality { return -1.0 }
+    iteration_value(view_position, degree, escape) / view_position.quality as f32
+}
+
+/// `escape` is only used by the distance colorings and the orbit trap.
+pub fn sample_color(view_position: &GpuViewPosition, palette: &Palette, value: f32, 
+    escape: Escape) -> [f32; 3] {
+    let coloring = view_position.coloring;
+    let fract_color = view_position.fract_color;
+    let distance = escape.distance;
+    if value < 0.0 {
+        if coloring == Coloring::LineArt.id() { line_art_background(palette) } 
+        else { interior_color(view_position, palette, escape) }
+    }
+    else if coloring == Coloring::Distance.id() {
+        mix(fract_color, palette.color_at(value), boundary_shade(distance))
+    }
+    else if coloring == Coloring::LineArt.id() {
+        mix(fract_color, line_art_background(palette), boundary_shade(distance))
+    }
+    else if coloring == Coloring::OrbitTrap.id() {
+        escape.trap_color.unwrap_or_else(|| palette.color_at(escape.trap))
+    }
+    else { palette.color_at(value) }
+}
+
+fn to_pixel(color: [f32; 3]) -> [u8; 4] {
+    [to_unorm8(color[0]), to_unorm8(color[1]), to_unorm8(color[2]), 255]
+}
+
+/// Step between the palette colors of neighbouring periods.
+pub const PERIOD_COLOR_STEP: f32 = 0.618034;
+
+/// `interior_color` of the shader, `fract_color` if the point has nothing to color by.
+fn interior_color(view_position: &GpuViewPosition, palette: &Palette, escape: Escape) -> [f32; 3] {
+    let interior = view_position.interior;
+    if interior == Interior::Period.id() && escape.period != 0 {
+        return palette.color_at(escape.period as f32 * PERIOD_COLOR_STEP);
+    }
+    if interior == Interior::Magnitude.id() { return palette.color_at(escape.radius / 2.0) }
+    if interior == Interior::Distance.id() && escape.period != 0 && escape.interior_distance != 0.0 {
+        let view_height = 2.0 * view_position.scale * 2f32.powi(view_position.scale_exponent);
+        let value = escape.interior_distance / view_height;
+        if value.is_finite() { return palette.color_at(value) }
+    }
+    view_position.fract_color
+}
+
+/// 0 on the boundary of the set, 1 from two pixels away.
+pub fn boundary_shade(distance: f32) -> f32 {
+    let t = (distance / 2.0).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// The last color of the palette.
+fn line_art_background(palette: &Palette) -> [f32; 3] {
+    palette.stops[..palette.stops.len().min(MAX_STOPS)].last().map_or([0.0; 3], |stop| stop.color)
+}
+
+/// Height of the pixels inside the set, which stay flat.
+pub const NO_HEIGHT: f32 = -1e30;
+
+/// Smallest distance estimate the distance relief takes the logarithm of.
+pub const MIN_RELIEF_DISTANCE: f32 = 1e-3;
+
+/// `relief_height` of the shader for an escaped point.
+pub fn relief_height(view_position: &GpuViewPosition, degree: f32, escape: Escape) -> f32 {
+    if view_position.light_height == HeightField::Distance.id() {
+        return escape.distance.max(MIN_RELIEF_DISTANCE).ln();
+    }
+    iteration_value(view_position, degree, escape)
+}
+
+/// The lighting pass of the shader over a finished image: the slopes of
+/// `heights` light the pixels with Blinn-Phong, inside points are left as they are.
+pub fn light(view_position: &GpuViewPosition, pixels: &mut [u8], heights: &[f32], width: u32, 
+    height: u32) {
+    const AMBIENT: f32 = 0.3;
+    const SHININESS: f32 = 32.0;
+    let (azimuth, elevation) = (view_position.light_azimuth, view_position.light_elevation);
+    let light = normalize([
+        elevation.cos() * azimuth.cos(), 
+        -elevation.cos() * azimuth.sin(), 
+        elevation.sin()
+    ]);
+    let half_way = normalize([light[0], light[1], light[2] + 1.0]);
+    let height_at = |x: i64, y: i64, own: f32| {
+        let x = x.clamp(0, width as i64 - 1) as usize;
+        let y = y.clamp(0, height as i64 - 1) as usize;
+        let height = heights[y * width as usize + x];
+        if height == NO_HEIGHT { own } else { height }
+    };
+    let dot = |a: [f32; 3], b: [f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+    for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+        let own = heights[i];
+        if own == NO_HEIGHT { continue }
+        let (x, y) = ((i % width as usize) as i64, (i / width as usize) as i64);
+        let slope = [
+            0.5 * view_position.light_relief * (height_at(x + 1, y, own) - height_at(x - 1, y, own)),
+            0.5 * view_position.light_relief * (height_at(x, y + 1, own) - height_at(x, y - 1, own)),
+        ];
+        let normal = normalize([-slope[0], -slope[1], 1.0]);
+        let diffuse = dot(normal, light).max(0.0);
+        let highlight = dot(normal, half_way).max(0.0).powf(SHININESS);
+        let shade = AMBIENT * (1.0 - diffuse) + diffuse;
+        for channel in &mut pixel[..3] {
+            let color = *channel as f32 / 255.0;
+            *channel = to_unorm8(color * shade + view_position.light_specular * highlight);
+        }
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|part| part / length)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [0, 1, 2].map(|i| a[i] * (1.0 - t) + b[i] * t)
+}
+
+fn to_unorm8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fraction stays within its iteration, so smooth colors never leave the band.
+    #[test]
+    fn smooth_value_stays_in_its_band() {
+        let view_position = GpuViewPosition::from(&ViewPosition { coloring: Coloring::Smooth, 
+            ..ViewPosition::new() });
+        let bailout = escape_radius(&view_position);
+        for radius in [bailout * 1.001, bailout * 2.0, bailout * bailout, f32::INFINITY] {
+            let escape = Escape { iterations: 10, radius, ..Default::default() };
+            let value = iteration_value(&view_position, 2.0, escape);
+            assert!((10.0..=11.0).contains(&value), "{}: {}", radius, value);
+        }
+    }
+
+    /// The Julia set of 0 is the unit circle, the estimate of `|z| ln|z|` is
+    /// within a few percent of the true distance close to it.
+    #[test]
+    fn distance_estimate_of_the_unit_circle() {
+        for radius in [1.01f32, 1.05, 1.1] {
+            let derivative = Derivative { value: [1.0, 0.0], dc: 0.0 };
+            let escape = escape_time([radius, 0.0], [0.0, 0.0], &Formula::Mandelbrot, 
+                2.0, 1000, 256.0, Tracking { derivative: Some(derivative), ..Default::default() });
+            let expected = radius - 1.0;
+            assert!((escape.distance - expected).abs() < expected * 0.06, 
+                "{}: {} != {}", radius, escape.distance, expected);
+        }
+    }
+
+    /// The centres of the cardioid, the period 2 disk and the rabbit bulb, a
+    /// cycle found while the orbit still spirals in is cut down to its period.
+    #[test]
+    fn cycle_periods() {
+        let tracking = Tracking { cycle_epsilon: Some(1e-5), ..Default::default() };
+        for (c, period) in [([0.0, 0.0], 1), ([-1.0, 0.0], 2), ([-0.122_561, 0.744_862], 3), 
+            ([-0.3, 0.5], 1), ([-0.7, 0.2], 1)] {
+            let escape = escape_time([0.0, 0.0], c, &Formula::Mandelbrot, 2.0, 1000, 2.0, tracking);
+            assert_eq!(escape.iterations, 1000, "{:?} is inside", c);
+            assert_eq!(escape.period, period, "period of {:?}", c);
+        }
+    }
+
+    /// The true distance from 0 to the cardioid is 1/4, the estimate is no more
+    /// than four times it.
+    #[test]
+    fn interior_distance_of_the_cardioid_centre() {
+        let estimate = interior_distance([0.0, 0.0], [0.0, 0.0], 1);
+        assert!((0.25..=1.0).contains(&estimate), "{}", estimate);
+    }
+
+    /// A flat relief under a light straight above keeps its colors, a slope is
+    /// brighter facing the light and points inside are not lit.
+    #[test]
+    fn lighting_of_a_slope() {
+        let above = GpuViewPosition {
+            lighting: 1,
+            light_elevation: std::f32::consts::FRAC_PI_2,
+            light_relief: 1.0,
+            ..Default::default()
+        };
+        let mut pixels = [100u8; 12];
+        light(&above, &mut pixels, &[0.0; 3], 3, 1);
+        assert_eq!(pixels, [100; 12]);
+
+        // Rises to the right, so it faces left
+        let slope = [0.0, 1.0, 2.0];
+        let lit = |azimuth: f32| {
+            let mut pixels = [100u8; 12];
+            let light_position = GpuViewPosition { light_azimuth: azimuth, light_elevation: 0.5, ..above };
+            light(&light_position, &mut pixels, &slope, 3, 1);
+            pixels[4]
+        };
+        assert!(lit(std::f32::consts::PI) > lit(0.0));
+
+        let mut pixels = [100u8; 8];
+        light(&GpuViewPosition { light_elevation: 0.5, ..above }, &mut pixels, &[NO_HEIGHT, 5.0], 2, 1);
+        assert_eq!(pixels[..4], [100; 4]);
+    }
+
+    /// Every sample lands in its own cell of the pixel, the jittered ones off
+    /// the middle and differently in neighbouring pixels.
+    #[test]
+    fn sample_offsets_stay_in_their_cells() {
+        for pattern in SamplePattern::ALL {
+            for samples in 1..=4 {
+                let view_position = GpuViewPosition { samples, sample_pattern: pattern.id(), 
+                    ..Default::default() };
+                for (i, j) in (0..samples).flat_map(|j| (0..samples).map(move |i| (i, j))) {
+                    let offset = sample_offset(&view_position, [5, 7], [i, j]);
+                    let cell = offset.map(|part| part * samples as f32);
+                    assert!(cell[0] >= i as f32 && cell[0] < (i + 1) as f32, "{:?}", offset);
+                    assert!(cell[1] >= j as f32 && cell[1] < (j + 1) as f32, "{:?}", offset);
+                }
+            }
+        }
+        let jittered = GpuViewPosition { samples: 2, sample_pattern: SamplePattern::Jittered.id(), 
+            ..Default::default() };
+        let offset = sample_offset(&jittered, [5, 7], [0, 0]);
+        assert_ne!(offset, [0.25, 0.25]);
+        assert_ne!(offset, sample_offset(&jittered, [6, 7], [0, 0]));
+    }
+}
@@ -8,6 +8,19 @@ pub struct DeviceInitInfo {
 }
 
 impl DeviceInitInfo {
+    /// Device for offscreen rendering, the swapchain is not needed.
+    pub fn headless() -> Self {
+        let default = DeviceInitInfo::default();
+        DeviceInitInfo {
+            required_extensions: DeviceExtensions::empty(),
+            preferred_extensions: DeviceExtensions {
+                ext_full_screen_exclusive: false,
+                ..default.preferred_extensions
+            },
+            ..default
+        }
+    }
+
     pub fn confirm_extensions(&self, supported_extensions: &DeviceExtensions) 
     -> Result<DeviceExtensions, DeviceCreationError> {
         if supported_extensions.contains(&self.required_extensions) == false {
@@ -35,12 +48,12 @@ impl Default for DeviceInitInfo {
     fn default() -> Self {
         let required_extensions = DeviceExtensions {
             khr_swapchain: true,
-            ext_full_screen_exclusive: true,
             ..DeviceExtensions::empty()
         };
         let preferred_extensions = DeviceExtensions {
             khr_dedicated_allocation: true,
             khr_device_group: true,
+            ext_full_screen_exclusive: cfg!(target_os = "windows"),
             ..DeviceExtensions::empty()
         };
 
@@ -49,6 +62,7 @@ impl Default for DeviceInitInfo {
         };
         let preferred_features = Features {
             buffer_device_address: true,
+            shader_float64: true,
             ..Features::empty()
         };
         
@@ -0,0 +1,43 @@
+use super::instance_init_info::InstanceInitInfo;
+use super::device_init_info::DeviceInitInfo;
+use super::setup::{ create_vulkan_instance, sort_physical_devices_by_device_type, 
+    find_correct_physical_devices };
+
+use std::error::Error;
+use std::sync::Arc;
+
+use vulkano::VulkanLibrary;
+use vulkano::device::physical::PhysicalDevice;
+
+pub fn print_devices() -> Result<(), Box<dyn Error>> {
+    let library = VulkanLibrary::new()?;
+    println!("Vulkan library version: {}", library.api_version());
+
+    // На машинах без экрана расширений поверхности нет, но устройства перечислить можно
+    let has_surface = library.supported_extensions().khr_surface;
+    let instance_init_info = if has_surface { InstanceInitInfo::with_window_surface(&library) } 
+        else { InstanceInitInfo::headless() };
+    let instance = create_vulkan_instance(library, instance_init_info)?;
+    let physical_devices: Vec<Arc<PhysicalDevice>> = instance
+        .enumerate_physical_devices()?.collect();
+    let physical_devices = sort_physical_devices_by_device_type(physical_devices);
+
+    for (i, device) in physical_devices.iter().enumerate() {
+        let properties = device.properties();
+        let single_device = vec![device.clone()];
+        let window_ready = has_surface && !find_correct_physical_devices(
+            &single_device, &DeviceInitInfo::default()).is_empty();
+        let headless_ready = !find_correct_physical_devices(
+            &single_device, &DeviceInitInfo::headless()).is_empty();
+
+        println!("{}: {} ({:?}, API {})", 
+            i, properties.device_name, properties.device_type, properties.api_version);
+        println!("    viewer: {}, headless: {}", 
+            if window_ready { "yes" } else { "no" },
+            if headless_ready { "yes" } else { "no" });
+    }
+    if physical_devices.is_empty() {
+        println!("No physical devices found");
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use std::ops::{ Add, Sub };
+use std::str::FromStr;
+
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+
+/// Binary fixed-point number of arbitrary precision: `mantissa * 2^-bits`.
+/// Holds the view centre and the reference orbit beyond the reach of `f64`.
+/// Addition is exact, multiplication rounds to the requested number of bits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FixedPoint {
+    mantissa: BigInt,
+    bits: u32,
+}
+
+impl FixedPoint {
+    pub fn zero() -> Self {
+        FixedPoint::default()
+    }
+
+    /// Exact conversion, every finite `f64` is a binary fraction.
+    pub fn from_f64(value: f64) -> Self {
+        if value == 0.0 || !value.is_finite() { return FixedPoint::zero() }
+
+        let raw = value.to_bits();
+        let exponent = ((raw >> 52) & 0x7ff) as i32;
+        let fraction = raw & ((1 << 52) - 1);
+        // Денормализованные числа не имеют скрытой единицы
+        let (significand, exponent) = if exponent == 0 { (fraction, -1074) }
+            else { (fraction | (1 << 52), exponent - 1075) };
+
+        let mut mantissa = BigInt::from(significand);
+        if value < 0.0 { mantissa = -mantissa }
+        if exponent >= 0 { FixedPoint { mantissa: mantissa << exponent as u32, bits: 0 } }
+        else { FixedPoint { mantissa, bits: (-exponent) as u32 } }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        // Лишние младшие биты отбрасываются, чтобы BigInt не переполнял f64
+        let excess = self.mantissa.bits().saturating_sub(64) as u32;
+        let mantissa = (&self.mantissa >> excess).to_f64().unwrap_or(0.0);
+        let exponent = excess as i64 - self.bits as i64;
+        mantissa * 2f64.powi(exponent.clamp(i32::MIN as i64, i32::MAX as i64) as i32)
+    }
+
+    /// Rounds (towards negative infinity) or extends to `bits` fractional bits.
+    pub fn with_bits(&self, bits: u32) -> Self {
+        let mantissa = if bits >= self.bits { &self.mantissa << (bits - self.bits) }
+            else { &self.mantissa >> (self.bits - bits) };
+        FixedPoint { mantissa, bits }
+    }
+
+    /// Product rounded to `bits` fractional bits.
+    pub fn mul(&self, other: &FixedPoint, bits: u32) -> Self {
+        FixedPoint {
+            mantissa: &self.mantissa * &other.mantissa,
+            bits: self.bits + other.bits,
+        }.with_bits(bits)
+    }
+
+    pub fn double(&self) -> Self {
+        FixedPoint { mantissa: &self.mantissa << 1u32, bits: self.bits }
+    }
+
+    fn aligned(&self, other: &FixedPoint) -> (BigInt, BigInt, u32) {
+        let bits = self.bits.max(other.bits);
+        (self.with_bits(bits).mantissa, other.with_bits(bits).mantissa, bits)
+    }
+}
+
+impl Add for &FixedPoint {
+    type Output = FixedPoint;
+
+    fn add(self, other: &FixedPoint) -> FixedPoint {
+        let (a, b, bits) = self.aligned(other);
+        FixedPoint { mantissa: a + b, bits }
+    }
+}
+
+impl Sub for &FixedPoint {
+    type Output = FixedPoint;
+
+    fn sub(self, other: &FixedPoint) -> FixedPoint {
+        let (a, b, bits) = self.aligned(other);
+        FixedPoint { mantissa: a - b, bits }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFixedPointError(String);
+
+impl fmt::Display for ParseFixedPointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid number '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseFixedPointError {}
+
+/// Parses a decimal number like `-0.7436438870371587047521915061`, keeping
+/// enough bits for every digit of it.
+impl FromStr for FixedPoint {
+    type Err = ParseFixedPointError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || ParseFixedPointError(String::from(value));
+        let (negative, digits) = match value.trim().strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, value.trim().trim_start_matches('+')),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() { return Err(error()) }
+        if !integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(error())
+        }
+
+        let numerator: BigInt = format!("0{}{}", integer, fraction).parse().map_err(|_| error())?;
+        let denominator = BigInt::from(10u32).pow(fraction.len() as u32);
+        // log2(10) < 3.33, плюс запас, чтобы последняя цифра не потерялась
+        let bits = (fraction.len() as u32 * 10).div_ceil(3) + 64;
+        let mut mantissa = (numerator << bits) / denominator;
+        if negative { mantissa = -mantissa }
+        Ok(FixedPoint { mantissa, bits })
+    }
+}
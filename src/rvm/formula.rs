@@ -0,0 +1,70 @@
+use super::user_formula::UserFormula;
+
+use std::ops::RangeInclusive;
+
+/// Powers `Formula::Multibrot` takes. Integer ones are iterated by repeated
+/// multiplication, so the upper end bounds the cost of a step.
+pub const POWER_RANGE: RangeInclusive<f64> = 2.0..=16.0;
+
+/// Function iterated for every pixel, `z = f(z) + c`.
+/// Selects the `FORMULA` specialization constant of `compute.glsl`,
+/// or a kernel compiled at runtime for a `UserFormula`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Formula {
+    /// `z^2 + c`.
+    #[default]
+    Mandelbrot,
+    /// `(|Re z| + i |Im z|)^2 + c`.
+    BurningShip,
+    /// `conj(z)^2 + c`, also called Mandelbar.
+    Tricorn,
+    /// `z^power + c`, any real `power` of the view.
+    Multibrot,
+    /// `|Re z^2| + i Im z^2 + c`.
+    Celtic,
+    /// `|Re z^2| + i |Im z^2| + c`.
+    Buffalo,
+    /// Any expression of `z` and `c`, the whole right-hand side of `z = f(z, c)`.
+    User(UserFormula),
+}
+
+impl Formula {
+    /// Built-in formulas.
+    pub const ALL: [Formula; 6] = [Formula::Mandelbrot, Formula::BurningShip,
+        Formula::Tricorn, Formula::Multibrot, Formula::Celtic, Formula::Buffalo];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Formula::Mandelbrot => "mandelbrot",
+            Formula::BurningShip => "burning-ship",
+            Formula::Tricorn => "tricorn",
+            Formula::Multibrot => "multibrot",
+            Formula::Celtic => "celtic",
+            Formula::Buffalo => "buffalo",
+            Formula::User(_) => "user",
+        }
+    }
+
+    /// Value of `FORMULA` in the shader, user kernels ignore it.
+    pub fn id(&self) -> u32 {
+        match self {
+            Formula::Mandelbrot | Formula::User(_) => 0,
+            Formula::BurningShip => 1,
+            Formula::Tricorn => 2,
+            Formula::Multibrot => 3,
+            Formula::Celtic => 4,
+            Formula::Buffalo => 5,
+        }
+    }
+
+    /// Exponent of the growth of `|z|` far from the origin, for smooth coloring.
+    /// User formulas are taken as quadratic.
+    pub fn degree(&self, power: f32) -> f32 {
+        if *self == Formula::Multibrot { power } else { 2.0 }
+    }
+
+    /// Perturbation needs the formula's delta iteration, only `z^2 + c` has one.
+    pub fn supports_perturbation(&self) -> bool {
+        *self == Formula::Mandelbrot
+    }
+}
@@ -0,0 +1,291 @@
+use super::instance_init_info::InstanceInitInfo;
+use super::device_init_info::DeviceInitInfo;
+use super::view_position::{ ViewPosition, GpuViewPosition };
+use super::precision::Precision;
+use super::palette::GpuPalette;
+use super::coloring::Coloring;
+use super::histogram::{ HistogramPass, create_histogram_buffer };
+use super::orbit_trap::GpuTrapImage;
+use super::lighting::{ LightingPass, create_height_buffer };
+use super::refinement::GpuTile;
+use super::pipelines::ComputePipelines;
+use super::reference_orbit::ReferenceOrbit;
+use super::series_approximation::{ SeriesApproximation, GpuSeriesApproximation };
+use super::cpu_renderer;
+use super::setup::{ create_vulkan_instance, get_right_devices, create_device_connection };
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+use vulkano::VulkanLibrary;
+use vulkano::device::{ Device, Queue };
+use vulkano::memory::allocator::{ GenericMemoryAllocator, FreeListAllocator };
+use vulkano::buffer::{ BufferUsage, CpuAccessibleBuffer };
+use vulkano::image::{ StorageImage, ImageUsage, ImageDimensions, ImageCreateFlags };
+use vulkano::image::view::ImageView;
+use vulkano::format::Format;
+use vulkano::pipeline::{ Pipeline, PipelineBindPoint };
+use vulkano::descriptor_set::{ PersistentDescriptorSet, WriteDescriptorSet };
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+use vulkano::command_buffer::{ AutoCommandBufferBuilder, CommandBufferUsage,
+    CopyImageToBufferInfo };
+use vulkano::sync::{ self, GpuFuture };
+
+/// Renders the fractal into an offscreen storage image without any window,
+/// surface or swapchain. Works on software drivers like lavapipe.
+pub struct HeadlessRenderer {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    /// Pipelines of the formula rendered last, built on the first render.
+    pipelines: RefCell<Option<ComputePipelines>>,
+    memory_allocator: GenericMemoryAllocator<Arc<FreeListAllocator>>,
+    descriptor_allocator: StandardDescriptorSetAllocator,
+    command_buffer_allocator: StandardCommandBufferAllocator,
+}
+
+impl HeadlessRenderer {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let library = VulkanLibrary::new()?;
+        let instance = create_vulkan_instance(library, InstanceInitInfo::headless())?;
+
+        let device_init_info = DeviceInitInfo::headless();
+        let physical_devices = get_right_devices(instance, &device_init_info)?;
+        let (device, queues) = create_device_connection(
+            physical_devices[0].clone(),
+            &device_init_info
+        )?;
+
+        Ok(HeadlessRenderer {
+            queue: queues[0].clone(),
+            pipelines: RefCell::new(None),
+            memory_allocator: GenericMemoryAllocator::<Arc<FreeListAllocator>>
+                ::new_default(device.clone()),
+            descriptor_allocator: StandardDescriptorSetAllocator::new(device.clone()),
+            command_buffer_allocator: StandardCommandBufferAllocator::new(
+                device.clone(),
+                Default::default()
+            ),
+            device,
+        })
+    }
+
+    pub fn precision_for(&self, view_position: &ViewPosition) -> Precision {
+        Precision::for_view(view_position, self.device.enabled_features().shader_float64)
+    }
+
+    /// Returns the image as tightly packed RGBA8 rows.
+    pub fn render(&self, view_position: &ViewPosition, width: u32, height: u32, 
+        precision: Precision)
+    -> Result<Vec<u8>, Box<dyn Error>> {
+        // Конвейеры пересобираются, только когда меняется формула
+        let mut cached_pipelines = self.pipelines.borrow_mut();
+        let pipelines = match cached_pipelines.take() {
+            Some(pipelines) if *pipelines.formula() == view_position.formula => pipelines,
+            _ => ComputePipelines::new(self.device.clone(), &view_position.formula)?,
+        };
+        let pipelines = cached_pipelines.insert(pipelines);
+        let precision = pipelines.resolve(precision);
+        let pipeline = pipelines.get(precision);
+        let queue_family_index = self.queue.queue_family_index();
+
+        let image = StorageImage::with_usage(
+            &self.memory_allocator,
+            ImageDimensions::Dim2d { width, height, array_layers: 1 },
+            Format::R8G8B8A8_UNORM,
+            ImageUsage {
+                transfer_src: true,
+                storage: true,
+                ..Default::default()
+            },
+            ImageCreateFlags::default(),
+            [queue_family_index]
+        )?;
+        let image_view = ImageView::new_default(image.clone())?;
+        let histogram_buffer = create_histogram_buffer(&self.memory_allocator, width, height, 
+            queue_family_index)?;
+        let height_buffer = create_height_buffer(&self.memory_allocator, width, height, 
+            queue_family_index)?;
+
+        let view_pos_buffer = CpuAccessibleBuffer::from_data(
+            &self.memory_allocator,
+            BufferUsage {
+                storage_buffer: true,
+                ..Default::default()
+            },
+            false,
+            GpuViewPosition::from(view_position)
+        )?;
+        let palette_buffer = CpuAccessibleBuffer::from_data(
+            &self.memory_allocator,
+            BufferUsage {
+                storage_buffer: true,
+                ..Default::default()
+            },
+            false,
+            GpuPalette::from(&view_position.palette)
+        )?;
+        let trap_image_buffer = CpuAccessibleBuffer::from_data(
+            &self.memory_allocator,
+            BufferUsage {
+                storage_buffer: true,
+                ..Default::default()
+            },
+            false,
+            GpuTrapImage::from(&*view_position.trap.image)
+        )?;
+        let output_buffer = CpuAccessibleBuffer::from_iter(
+            &self.memory_allocator,
+            BufferUsage {
+                transfer_dst: true,
+                ..Default::default()
+            },
+            false,
+            (0..width * height * 4).map(|_| 0u8)
+        )?;
+
+        let mut descriptor_writes = vec![
+            WriteDescriptorSet::image_view(0, image_view.clone()),
+            WriteDescriptorSet::buffer(1, view_pos_buffer.clone()),
+            WriteDescriptorSet::buffer(2, palette_buffer.clone()),
+            WriteDescriptorSet::buffer(5, histogram_buffer.clone()),
+            WriteDescriptorSet::buffer(6, trap_image_buffer),
+            WriteDescriptorSet::buffer(7, height_buffer.clone()),
+        ];
+        if precision == Precision::Perturbation {
+            let reference_orbit = ReferenceOrbit::new(view_position);
+            let series = SeriesApproximation::for_screen(
+                &reference_orbit, view_position, width, height);
+            let series_buffer = CpuAccessibleBuffer::from_data(
+                &self.memory_allocator,
+                BufferUsage {
+                    storage_buffer: true,
+                    ..Default::default()
+                },
+                false,
+                GpuSeriesApproximation::from(&series)
+            )?;
+            let orbit_buffer = CpuAccessibleBuffer::from_iter(
+                &self.memory_allocator,
+                BufferUsage {
+                    storage_buffer: true,
+                    ..Default::default()
+                },
+                false,
+                reference_orbit.points
+            )?;
+            descriptor_writes.push(WriteDescriptorSet::buffer(3, orbit_buffer));
+            descriptor_writes.push(WriteDescriptorSet::buffer(4, series_buffer));
+        }
+        let descriptor_set = PersistentDescriptorSet::new(
+            &self.descriptor_allocator,
+            pipelines.descriptor_set_layout(precision),
+            descriptor_writes
+        )?;
+
+        let histogram_pass = if view_position.coloring == Coloring::Histogram {
+            Some(HistogramPass::new(&self.descriptor_allocator, pipelines, image_view.clone(), 
+                palette_buffer, histogram_buffer)?)
+        }
+        else { None };
+        let lighting_pass = if view_position.lighting.enabled {
+            Some(LightingPass::new(&self.descriptor_allocator, pipelines, image_view, 
+                view_pos_buffer, height_buffer)?)
+        }
+        else { None };
+
+        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+            &self.command_buffer_allocator,
+            queue_family_index,
+            CommandBufferUsage::OneTimeSubmit
+        )?;
+        let workgroup_side = pipelines.workgroup_side();
+        let group_counts = [
+            width.div_ceil(workgroup_side),
+            height.div_ceil(workgroup_side),
+            1
+        ];
+        if let Some(histogram_pass) = &histogram_pass {
+            histogram_pass.record_clear(&mut command_buffer_builder)?;
+        }
+        command_buffer_builder.bind_pipeline_compute(pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                pipeline.layout().clone(),
+                0,
+                descriptor_set
+            )
+            .push_constants(pipeline.layout().clone(), 0, GpuTile::default())
+            .dispatch(group_counts)?;
+        if let Some(histogram_pass) = &histogram_pass {
+            histogram_pass.record(&mut command_buffer_builder, group_counts)?;
+        }
+        if let Some(lighting_pass) = &lighting_pass {
+            lighting_pass.record(&mut command_buffer_builder, group_counts)?;
+        }
+        command_buffer_builder
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                image,
+                output_buffer.clone()
+            ))?;
+        let command_buffer = command_buffer_builder.build()?;
+
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        // Шейдер пишет каналы в порядке BGRA под формат свопчейна
+        let content = output_buffer.read()?;
+        let mut pixels = Vec::with_capacity(content.len());
+        for bgra in content.chunks_exact(4) {
+            pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+        Ok(pixels)
+    }
+}
+
+pub fn save_png(path: impl AsRef<Path>, width: u32, height: u32, pixels: &[u8])
+-> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// Renders on the first suitable Vulkan device, or on the CPU if there is 
+/// none or `force_cpu` is set. Without `precision` it is chosen by the zoom.
+pub fn render_to_png(view_position: &ViewPosition, width: u32, height: u32, path: &Path, 
+    force_cpu: bool, precision: Option<Precision>) 
+-> Result<(), Box<dyn Error>> {
+    let renderer = if force_cpu { None }
+    else {
+        match HeadlessRenderer::new() {
+            Ok(renderer) => Some(renderer),
+            Err(err) => {
+                println!("No suitable Vulkan device ({:?}), rendering on the CPU", err);
+                None
+            }
+        }
+    };
+
+    let pixels = match renderer {
+        Some(renderer) => {
+            let precision = precision.unwrap_or(renderer.precision_for(view_position));
+            renderer.render(view_position, width, height, precision)?
+        }
+        None => {
+            let precision = precision.unwrap_or(Precision::for_view(view_position, true));
+            cpu_renderer::render(view_position, width, height, precision)
+        }
+    };
+    save_png(path, width, height, &pixels)
+}
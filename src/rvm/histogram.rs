@@ -0,0 +1,163 @@
+use super::pipelines::ComputePipelines;
+use super::palette::GpuPalette;
+
+use std::error::Error;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use vulkano::memory::allocator::MemoryAllocator;
+use vulkano::buffer::{ BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer };
+use vulkano::image::StorageImage;
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{ Pipeline, ComputePipeline, PipelineBindPoint };
+use vulkano::descriptor_set::{ PersistentDescriptorSet, WriteDescriptorSet };
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::command_buffer::{ AutoCommandBufferBuilder, PrimaryAutoCommandBuffer,
+    FillBufferInfo };
+
+/// Bins the iteration values are counted in, `HISTOGRAM_BINS` of `compute.glsl`.
+pub const HISTOGRAM_BINS: usize = 4096;
+
+/// Length in `u32` of the `Histogram` buffer for an image: the counts and
+/// one value per pixel.
+pub fn histogram_buffer_len(width: u32, height: u32) -> u64 {
+    (HISTOGRAM_BINS + 1) as u64 + width as u64 * height as u64
+}
+
+fn bin(value: f32) -> usize {
+    ((value * HISTOGRAM_BINS as f32) as usize).min(HISTOGRAM_BINS - 1)
+}
+
+/// Iteration histogram of a whole frame, as the `Histogram` buffer holds it
+/// after the cumulative stage: for every bin the number of escaped points
+/// in the bins before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    cumulative: Vec<u32>,
+}
+
+impl Histogram {
+    /// `values` are iteration values over `quality`, negative inside the set.
+    pub fn new(values: &[f32]) -> Self {
+        let mut cumulative = vec![0u32; HISTOGRAM_BINS + 1];
+        for value in values.iter().filter(|value| **value >= 0.0) {
+            cumulative[bin(*value)] += 1;
+        }
+        let mut total = 0;
+        for count in cumulative.iter_mut() {
+            (*count, total) = (total, total + *count);
+        }
+        Histogram { cumulative }
+    }
+
+    /// Number of escaped points.
+    pub fn total(&self) -> u32 {
+        self.cumulative[HISTOGRAM_BINS]
+    }
+
+    /// `equalized_color` of the shader before the palette: the share of
+    /// escaped points below `value`, interpolated inside its bin.
+    pub fn equalize(&self, value: f32) -> f32 {
+        let position = value.clamp(0.0, 1.0) * HISTOGRAM_BINS as f32;
+        let bin = (position as usize).min(HISTOGRAM_BINS - 1);
+        let below = self.cumulative[bin] as f32;
+        let above = self.cumulative[bin + 1] as f32;
+        let a = position - bin as f32;
+        (below * (1.0 - a) + above * a) / (self.total() as f32).max(1.0)
+    }
+}
+
+pub fn create_histogram_buffer(allocator: &(impl MemoryAllocator + ?Sized), width: u32,
+    height: u32, queue_family_index: u32)
+-> Result<Arc<DeviceLocalBuffer<[u32]>>, Box<dyn Error>> {
+    let buffer = DeviceLocalBuffer::array(
+        allocator,
+        histogram_buffer_len(width, height),
+        BufferUsage {
+            storage_buffer: true,
+            transfer_dst: true,
+            ..Default::default()
+        },
+        [queue_family_index]
+    )?;
+    Ok(buffer)
+}
+
+/// Second pass of `Coloring::Histogram` for one image. The kernel itself
+/// runs with the same `buffer` at binding 5 in between `record_clear`
+/// and `record`.
+pub struct HistogramPass {
+    cumulative: Arc<ComputePipeline>,
+    color: Arc<ComputePipeline>,
+    buffer: Arc<DeviceLocalBuffer<[u32]>>,
+    descriptor_set: Arc<PersistentDescriptorSet>,
+}
+
+impl HistogramPass {
+    pub fn new(
+        descriptor_allocator: &StandardDescriptorSetAllocator,
+        pipelines: &ComputePipelines,
+        image_view: Arc<ImageView<StorageImage>>,
+        palette_buffer: Arc<CpuAccessibleBuffer<GpuPalette>>,
+        buffer: Arc<DeviceLocalBuffer<[u32]>>)
+    -> Result<Self, Box<dyn Error>> {
+        let (cumulative, color) = pipelines.histogram();
+        let descriptor_set = PersistentDescriptorSet::new(
+            descriptor_allocator,
+            color.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::image_view(0, image_view),
+                WriteDescriptorSet::buffer(2, palette_buffer),
+                WriteDescriptorSet::buffer(5, buffer.clone()),
+            ]
+        )?;
+        Ok(HistogramPass { cumulative, color, buffer, descriptor_set })
+    }
+
+    /// Zeroes the counts, the values are all overwritten by the kernel.
+    pub fn record_clear(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>)
+    -> Result<(), Box<dyn Error>> {
+        builder.fill_buffer(FillBufferInfo {
+            size: ((HISTOGRAM_BINS + 1) * size_of::<u32>()) as u64,
+            ..FillBufferInfo::dst_buffer(self.buffer.clone())
+        })?;
+        Ok(())
+    }
+
+    /// Accumulates the counts on one workgroup, then colors the image
+    /// with as many workgroups as the kernel was dispatched with.
+    pub fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        group_counts: [u32; 3])
+    -> Result<(), Box<dyn Error>> {
+        builder.bind_pipeline_compute(self.cumulative.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.cumulative.layout().clone(),
+                0,
+                self.descriptor_set.clone()
+            )
+            .dispatch([1, 1, 1])?
+            .bind_pipeline_compute(self.color.clone())
+            .dispatch(group_counts)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_spreads_values() {
+        // Nearly all points escape within the first percent of the iterations
+        let mut values: Vec<f32> = (0..1000).map(|i| i as f32 / 100_000.0).collect();
+        values.extend([0.5, -1.0, -1.0]);
+        let histogram = Histogram::new(&values);
+        assert_eq!(histogram.total(), 1001, "points inside the set are not counted");
+        assert_eq!(histogram.equalize(0.0), 0.0);
+        assert!((histogram.equalize(0.005) - 0.5).abs() < 0.01, "{}", histogram.equalize(0.005));
+        assert!(histogram.equalize(0.4) > 0.99);
+        assert!(values.windows(2).all(|pair| pair[0] > pair[1] 
+            || histogram.equalize(pair[0]) <= histogram.equalize(pair[1])), "keeps the order");
+    }
+}
@@ -1,3 +1,4 @@
+use vulkano::VulkanLibrary;
 use vulkano::instance::{ InstanceExtensions, InstanceCreationError };
 
 pub struct InstanceInitInfo {
@@ -6,6 +7,28 @@ pub struct InstanceInitInfo {
 }
 
 impl InstanceInitInfo {
+    /// Adds the surface extensions the current platform needs to present 
+    /// into a winit window (Win32, Xlib, XCB, Wayland, ...).
+    pub fn with_window_surface(library: &VulkanLibrary) -> Self {
+        let default = InstanceInitInfo::default();
+        InstanceInitInfo {
+            preferred_extensions: default.preferred_extensions
+                .union(&vulkano_win::required_extensions(library)),
+            ..default
+        }
+    }
+
+    /// Instance for offscreen rendering, no surface extensions at all.
+    pub fn headless() -> Self {
+        InstanceInitInfo {
+            required_extensions: InstanceExtensions::empty(),
+            preferred_extensions: InstanceExtensions {
+                khr_get_physical_device_properties2: true,
+                ..InstanceExtensions::empty()
+            },
+        }
+    }
+
     pub fn confirm_extensions(&self, supported_extensions: &InstanceExtensions) 
     -> Result<InstanceExtensions, InstanceCreationError> {
         if supported_extensions.contains(&self.required_extensions) == false {
@@ -21,13 +44,12 @@ impl InstanceInitInfo {
 impl Default for InstanceInitInfo {
     fn default() -> Self {
         let required_extensions = InstanceExtensions {
-            khr_get_surface_capabilities2: true,
             khr_surface: true,
-            khr_win32_surface: true,
             ..InstanceExtensions::empty()
         };
         let preferred_extensions = InstanceExtensions {
             khr_get_physical_device_properties2: true,
+            khr_get_surface_capabilities2: true,
             ..InstanceExtensions::empty()
         };
         InstanceInitInfo { required_extensions, preferred_extensions }
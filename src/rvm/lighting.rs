@@ -0,0 +1,135 @@
+use super::pipelines::ComputePipelines;
+use super::view_position::GpuViewPosition;
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use vulkano::memory::allocator::MemoryAllocator;
+use vulkano::buffer::{ BufferUsage, CpuAccessibleBuffer, DeviceLocalBuffer };
+use vulkano::image::StorageImage;
+use vulkano::image::view::ImageView;
+use vulkano::pipeline::{ Pipeline, ComputePipeline, PipelineBindPoint };
+use vulkano::descriptor_set::{ PersistentDescriptorSet, WriteDescriptorSet };
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::command_buffer::{ AutoCommandBufferBuilder, PrimaryAutoCommandBuffer };
+
+/// What the relief of `Lighting` is raised from, `HEIGHT_*` of `compute.glsl`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum HeightField {
+    /// The iteration value the palette takes, smooth unless the coloring
+    /// counts whole iterations.
+    #[default]
+    Iterations,
+    /// Logarithm of the distance estimate: steep slopes run down to the
+    /// boundary of the set.
+    Distance,
+}
+
+impl HeightField {
+    pub const ALL: [HeightField; 2] = [HeightField::Iterations, HeightField::Distance];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeightField::Iterations => "iterations",
+            HeightField::Distance => "distance",
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            HeightField::Iterations => 0,
+            HeightField::Distance => 1,
+        }
+    }
+}
+
+/// Relief shading of escaped points on top of any coloring: a post-process
+/// pass turns the height field into surface normals and lights them with
+/// a directional light.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lighting {
+    pub enabled: bool,
+    pub height: HeightField,
+    /// Direction the light comes from in radians, counterclockwise from the
+    /// right side of the screen.
+    pub azimuth: f64,
+    /// Angle of the light over the screen in radians, `PI / 2` straight above.
+    pub elevation: f64,
+    /// Strength of the highlights, 0 for a matte surface.
+    pub specular: f64,
+    /// Steepness of the relief.
+    pub relief: f64,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Lighting {
+            enabled: false,
+            height: HeightField::Iterations,
+            azimuth: PI * 0.75,
+            elevation: PI * 0.25,
+            specular: 0.3,
+            relief: 1.0,
+        }
+    }
+}
+
+pub fn create_height_buffer(allocator: &(impl MemoryAllocator + ?Sized), width: u32,
+    height: u32, queue_family_index: u32)
+-> Result<Arc<DeviceLocalBuffer<[f32]>>, Box<dyn Error>> {
+    let buffer = DeviceLocalBuffer::array(
+        allocator,
+        (width as u64 * height as u64).max(1),
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        [queue_family_index]
+    )?;
+    Ok(buffer)
+}
+
+/// Lighting pass for one image. The kernel leaves the height of every pixel
+/// in `buffer` at binding 7, the pass runs after it and after the
+/// histogram pass, when the colors are final.
+pub struct LightingPass {
+    pipeline: Arc<ComputePipeline>,
+    descriptor_set: Arc<PersistentDescriptorSet>,
+}
+
+impl LightingPass {
+    pub fn new(
+        descriptor_allocator: &StandardDescriptorSetAllocator,
+        pipelines: &ComputePipelines,
+        image_view: Arc<ImageView<StorageImage>>,
+        view_pos_buffer: Arc<CpuAccessibleBuffer<GpuViewPosition>>,
+        buffer: Arc<DeviceLocalBuffer<[f32]>>)
+    -> Result<Self, Box<dyn Error>> {
+        let pipeline = pipelines.lighting();
+        let descriptor_set = PersistentDescriptorSet::new(
+            descriptor_allocator,
+            pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::image_view(0, image_view),
+                WriteDescriptorSet::buffer(1, view_pos_buffer),
+                WriteDescriptorSet::buffer(7, buffer),
+            ]
+        )?;
+        Ok(LightingPass { pipeline, descriptor_set })
+    }
+
+    pub fn record(&self, builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        group_counts: [u32; 3])
+    -> Result<(), Box<dyn Error>> {
+        builder.bind_pipeline_compute(self.pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.pipeline.layout().clone(),
+                0,
+                self.descriptor_set.clone()
+            )
+            .dispatch(group_counts)?;
+        Ok(())
+    }
+}
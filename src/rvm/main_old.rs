@@ -1,29 +1,44 @@
 
 use super::instance_init_info::InstanceInitInfo;
 use super::device_init_info::DeviceInitInfo;
-use super::shader_module;
+use super::view_position::{ ViewPosition, GpuViewPosition, MIN_ZOOM };
+use super::pipelines::ComputePipelines;
+use super::precision::Precision;
+use super::formula::{ Formula, POWER_RANGE };
+use super::coloring::{ Coloring, Interior };
+use super::palette::{ Palette, GpuPalette, PRESETS };
+use super::palette_file::{ load_palette, save_palette };
+use super::histogram::{ HistogramPass, create_histogram_buffer };
+use super::orbit_trap::{ TrapShape, TrapImage, GpuTrapImage };
+use super::lighting::{ HeightField, LightingPass, create_height_buffer };
+use super::supersampling::{ SamplePattern, MAX_SAMPLES };
+use super::refinement::{ Refinement, RefinementPass, FrameTiles, GpuTile, TILE_SIZE, FRAME_BUDGET_RANGE };
+use super::user_formula::{ UserFormula, USER_FORMULA_NAMES };
+use super::reference_orbit::ReferenceOrbit;
+use super::series_approximation::{ SeriesApproximation, GpuSeriesApproximation };
+use super::shader_watcher::ShaderWatcher;
+use super::setup::{ create_vulkan_instance, get_right_devices, create_device_connection };
 
 use std::error::Error;
+use std::path::{ Path, PathBuf };
 use std::sync::Arc;
 use std::cmp;
+use std::f64::consts::PI;
 use std::mem::{ size_of, size_of_val };
-use std::time::{ self, Instant };
+use std::time::{ self, Instant, Duration };
 
-use bytemuck::{ Pod, Zeroable };
+use bytemuck::bytes_of;
 
 use winit::event::{ Event, WindowEvent, StartCause, KeyboardInput, ScanCode, 
-    DeviceEvent, ElementState };
+    DeviceEvent, ElementState, MouseButton };
 use winit::event_loop::{ ControlFlow, EventLoop, DeviceEventFilter };
 use winit::window::WindowBuilder;
-use winit::platform::windows::WindowExtWindows;
 use winit::dpi::PhysicalSize;
 use winit::window::Fullscreen;
 
-use vulkano::{ VulkanLibrary, VulkanError };
-use vulkano::instance::{ Instance, InstanceCreateInfo };
-use vulkano::device::physical::{ PhysicalDevice, PhysicalDeviceType, 
-    PhysicalDeviceError };
-use vulkano::device::{ Device, DeviceCreateInfo, QueueCreateInfo, Queue };
+use vulkano::VulkanLibrary;
+use vulkano::device::physical::{ PhysicalDevice, PhysicalDeviceError };
+use vulkano::device::Device;
 use vulkano::memory::allocator::{ GenericMemoryAllocator, 
     GenericMemoryAllocatorCreateInfo, AllocationType, MemoryUsage };
 use vulkano::memory::allocator::suballocator::{ FreeListAllocator, BumpAllocator, 
@@ -35,15 +50,15 @@ use vulkano::image::{ StorageImage, SwapchainImage, ImageAccess, ImageUsage,
     ImageLayout, SampleCount, ImageDimensions, ImageCreateFlags };
 use vulkano::image::view::{ ImageViewAbstract, ImageView, ImageViewCreationError };
 use vulkano::format::Format;
-use vulkano::swapchain::{ self, Surface, SurfaceInfo, SurfaceCapabilities, Win32Monitor,
-    ColorSpace, PresentMode, FullScreenExclusive, Swapchain, SwapchainCreateInfo, 
+use vulkano::swapchain::{ self, Surface, SurfaceInfo, SurfaceCapabilities,
+    ColorSpace, PresentMode, Swapchain, SwapchainCreateInfo, 
     SwapchainPresentInfo, SwapchainCreationError, AcquireError };
 use vulkano::render_pass::{ RenderPass, RenderPassCreateInfo, RenderPassCreationError, 
     SubpassDescription, AttachmentDescription, AttachmentReference, LoadOp, StoreOp, 
     Framebuffer, FramebufferCreateInfo, FramebufferCreationError };
 use vulkano::pipeline::{ Pipeline, ComputePipeline, PipelineBindPoint };
-use vulkano::shader::spirv::SpirvError;
 use vulkano::descriptor_set::{ PersistentDescriptorSet, WriteDescriptorSet, DescriptorSet, DescriptorBindingResources };
+use vulkano::descriptor_set::layout::DescriptorSetLayout;
 use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
 use vulkano::command_buffer::allocator::{ StandardCommandBufferAllocator, 
     StandardCommandBufferAllocatorCreateInfo };
@@ -61,36 +76,19 @@ use vulkano_shaders;
 use super::super::ui;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
-
-#[repr(C)]
-#[derive(Debug, Default, Copy, Clone, Zeroable, Pod)]
-struct ViewPosition {
-    pub color: [f32; 3],
-    pub quality: u32,
-    pub fract_color: [f32; 3],
-    pub zoom: f32,
-    pub pos_x: f32,
-    pub pos_y: f32,
-}
-impl ViewPosition {
-    fn new() -> Self {
-        ViewPosition {
-            quality: 500,
-            zoom: 1.0,
-            pos_x: -500.0,
-            pos_y: 0.0,
-            color: [0.0, 1.0, 0.0],
-            fract_color: [0.0, 0.0, 0.0],
-        }
-    }
-
-    fn reset(self) -> Self {
-        ViewPosition {
-            color: self.color,
-            fract_color: self.fract_color,
-            ..ViewPosition::new()
-        }
-    }
+const JULIA_PREVIEW_SIZE: [u32; 2] = [320, 240];
+const JULIA_PREVIEW_MARGIN: u32 = 10;
+/// How often the shader file is checked while the window is idle.
+const SHADER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest step of the palette cycling, the first frame after idling is late.
+const MAX_FRAME_TIME: f32 = 0.1;
+
+/// Julia set of the point under the cursor, drawn over a corner of the view.
+struct JuliaPreview {
+    pipeline: Arc<ComputePipeline>,
+    image_view: Arc<ImageView<StorageImage>>,
+    view_pos_buffer: Arc<CpuAccessibleBuffer<GpuViewPosition>>,
+    descriptor_set: Arc<PersistentDescriptorSet>,
 }
 
 fn byte_size(byte: u64) -> String {
@@ -108,67 +106,6 @@ fn byte_size(byte: u64) -> String {
     return format!("{:.2} {}", result, size_sign[sign_index]);
 }
 
-fn create_vulkan_instance() -> Result<Arc<Instance>, Box<dyn Error>> {
-    let library = VulkanLibrary::new()?;
-    let supported_extensions = library.supported_extensions();
-    let enabled_extensions = InstanceInitInfo::default().confirm_extensions(supported_extensions)?;
-
-    let layers: Vec<_> = library.layer_properties().unwrap()
-        .filter(|l| l.name().contains("VK_LAYER_LUNARG_monitor"))
-        .collect();
-
-    let instance = Instance::new(
-        library,
-        InstanceCreateInfo {
-            enabled_extensions,
-            enabled_layers: layers.iter().map(|l| l.name().to_owned()).collect(),
-            ..InstanceCreateInfo::application_from_cargo_toml()
-        },
-    )?;
-    Ok(instance)
-}
-
-fn get_right_devices(instance: Arc<Instance>) -> Result<Vec<Arc<PhysicalDevice>>, VulkanError> {
-    let physical_devices: Vec<Arc<PhysicalDevice>> = instance
-        .enumerate_physical_devices()?.collect();
-
-    let priority_devices = sort_physical_devices_by_device_type(physical_devices);
-    let correct_devices = find_correct_physical_devices(&priority_devices);
-    if correct_devices.len() == 0 { Err(VulkanError::DeviceLost) }
-    else { Ok(correct_devices) }
-}
-
-fn sort_physical_devices_by_device_type(physical_devices: Vec<Arc<PhysicalDevice>>) 
--> Vec<Arc<PhysicalDevice>> {
-    let mut priority_devices: Vec<Arc<PhysicalDevice>> = vec![];
-    for device in physical_devices {
-        match device.properties().device_type {
-            PhysicalDeviceType::DiscreteGpu => priority_devices.insert(0, device.clone()),
-            PhysicalDeviceType::IntegratedGpu => priority_devices.push(device.clone()),
-            _ => priority_devices.push(device.clone()),
-        }
-    }
-    priority_devices
-}
-
-fn find_correct_physical_devices(physical_devices: &Vec<Arc<PhysicalDevice>>) 
--> Vec<Arc<PhysicalDevice>> {
-    let mut correct_devices: Vec<Arc<PhysicalDevice>> = vec![];
-    for device in physical_devices {
-        let supported_extensions = device.supported_extensions();
-        if let Err(_) = DeviceInitInfo::default().confirm_extensions(supported_extensions) {
-            continue;
-        }
-
-        let supported_features = device.supported_features();
-        if let Err(_) = DeviceInitInfo::default().confirm_features(supported_features) {
-            continue;
-        }
-        correct_devices.push(device.clone());
-    }
-    correct_devices
-}
-
 fn get_physical_device_local_memory(physical_device: Arc<PhysicalDevice>) -> u64 {
     let memory_prop = physical_device.memory_properties();
 
@@ -179,54 +116,8 @@ fn get_physical_device_local_memory(physical_device: Arc<PhysicalDevice>) -> u64
     local_memory
 }
 
-fn get_device_queue_create_infos(physical_device: Arc<PhysicalDevice>)
--> Result<Vec<QueueCreateInfo>, Box<dyn Error>> {
-    let queue_family_properties = physical_device.queue_family_properties();
-
-    let mut queue_family_indices: Vec<u32> = vec![];
-    for (i, q) in queue_family_properties.iter().enumerate() {
-        if q.queue_flags.compute && q.queue_flags.transfer {
-            queue_family_indices.push(i as u32);
-        }
-    }
-    if queue_family_indices.len() < 1 {
-        return Err(Box::new(VulkanError::InitializationFailed));
-    }
-
-    let queue_create_infos = queue_family_indices.into_iter()
-    .map(|queue_family_index| {
-        QueueCreateInfo {
-            queue_family_index,
-            ..Default::default()
-        }
-    }).collect();
-
-    Ok(queue_create_infos)
-}
-
-fn create_device_connection(physical_device: Arc<PhysicalDevice>)
--> Result<(Arc<Device>, Vec<Arc<Queue>>), Box<dyn Error>> {
-    let supported_extensions = physical_device.supported_extensions();
-    let enabled_extensions = DeviceInitInfo::default().confirm_extensions(supported_extensions)?;
-
-    let supported_features = physical_device.supported_features();
-    let enabled_features = DeviceInitInfo::default().confirm_features(supported_features)?;
-
-    let queue_create_infos = get_device_queue_create_infos(physical_device.clone())?;
-
-    let (device, queues) = Device::new(
-        physical_device,
-        DeviceCreateInfo {
-            enabled_extensions,
-            enabled_features,
-            queue_create_infos,
-            ..Default::default()
-        }
-    )?;
-    Ok((device, queues.collect()))
-}
-
-fn get_app_monitor(window: Arc<winit::window::Window>) -> Option<Win32Monitor> {
+#[cfg(target_os = "windows")]
+fn get_app_monitor(window: Arc<winit::window::Window>) -> Option<vulkano::swapchain::Win32Monitor> {
     if let Some(monitor) = window.current_monitor() {
         Some(vulkano_win::create_win32_monitor_from_winit(&monitor))
     }
@@ -236,16 +127,40 @@ fn get_app_monitor(window: Arc<winit::window::Window>) -> Option<Win32Monitor> {
     else { None }
 }
 
-fn create_swapchain(surface: Arc<Surface>, device: Arc<Device>, monitor: Option<Win32Monitor>)
--> Result<(Arc<Swapchain>, Vec<Arc<SwapchainImage>>), Box<dyn Error>> {
-    let surface_info = match monitor {
+#[cfg(target_os = "windows")]
+fn get_surface_info(device: Arc<Device>, window: Arc<winit::window::Window>) -> SurfaceInfo {
+    use vulkano::swapchain::FullScreenExclusive;
+
+    if !device.enabled_extensions().ext_full_screen_exclusive {
+        return SurfaceInfo::default()
+    }
+    match get_app_monitor(window) {
         Some(monitor) => SurfaceInfo { 
             full_screen_exclusive: FullScreenExclusive::ApplicationControlled, 
             win32_monitor: Some(monitor), 
             ..Default::default()
         },
         None => SurfaceInfo::default()
-    };
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn get_surface_info(_device: Arc<Device>, _window: Arc<winit::window::Window>) -> SurfaceInfo {
+    SurfaceInfo::default()
+}
+
+fn get_fullscreen_mode(window: Arc<winit::window::Window>) -> Option<Fullscreen> {
+    let current_monitor = window.current_monitor()?;
+    if cfg!(target_os = "windows") {
+        let video_mode = current_monitor.video_modes().next()?;
+        Some(Fullscreen::Exclusive(video_mode))
+    }
+    else { Some(Fullscreen::Borderless(Some(current_monitor))) }
+}
+
+fn create_swapchain(surface: Arc<Surface>, device: Arc<Device>, window: Arc<winit::window::Window>)
+-> Result<(Arc<Swapchain>, Vec<Arc<SwapchainImage>>), Box<dyn Error>> {
+    let surface_info = get_surface_info(device.clone(), window.clone());
     //let full_screen_exclusive = surface_info.full_screen_exclusive;
 
     let surface_capabilities = device.physical_device().surface_capabilities(
@@ -253,7 +168,9 @@ fn create_swapchain(surface: Arc<Surface>, device: Arc<Device>, monitor: Option<
         surface_info
     )?;
 
-    let image_extent = surface_capabilities.current_extent.unwrap_or([0, 0]);
+    // Wayland не сообщает текущий размер поверхности, берём размер окна
+    let image_extent = surface_capabilities.current_extent
+        .unwrap_or([window.inner_size().width, window.inner_size().height]);
     let min_image_count = match surface_capabilities.max_image_count {
         None => cmp::max(3, surface_capabilities.min_image_count),
         Some(limit) => cmp::min(cmp::max(3, surface_capabilities.min_image_count), limit)
@@ -341,49 +258,30 @@ fn create_framebuffers(render_pass: Arc<RenderPass>, images: &Vec<Arc<SwapchainI
     Ok(result)
 }
 
-fn create_pipeline(device: Arc<Device>) -> Result<Arc<ComputePipeline>, Box<dyn Error>> {
-    let shader = shader_module::cs::load(device.clone())?;
-    let entry_point = if let Some(ep) = shader.entry_point("main") { ep }
-    else { return Err(Box::new(SpirvError::InvalidHeader)) };
-    
-    let pipeline = ComputePipeline::new(
-        device.clone(),
-        entry_point,
-        &(),
-        None,     // Добавить кеш!!!
-        |_| {}
-    )?;
-    Ok(pipeline)
-}
-
-fn create_storage_images_views(
+/// One image for all the swapchain images: frames go one at a time, and
+/// the refinement passes build the picture up in it over several frames.
+fn create_storage_image_view(
     allocator: &GenericMemoryAllocator::<Arc<BumpAllocator>>,
     swapchain: Arc<Swapchain>,
     queue_family_index: u32)
--> Result<Vec<Arc<ImageView<StorageImage>>>, Box<dyn Error>> {
-
-    let mut images_views = vec![];
-    for _ in 0..swapchain.image_count() {
-        let image = StorageImage::with_usage(
+-> Result<Arc<ImageView<StorageImage>>, Box<dyn Error>> {
+    let image = StorageImage::with_usage(
             allocator,
-            ImageDimensions::Dim2d { 
-                width: swapchain.image_extent()[0],
-                height: swapchain.image_extent()[1],
-                array_layers: swapchain.image_array_layers()
-            },
-            Format::R8G8B8A8_UNORM,
-            ImageUsage {
-                transfer_src: true,
-                storage: true,
-                ..Default::default()
-            },
-            ImageCreateFlags::default(),
-            [queue_family_index]
-        )?;
-        let view = ImageView::new_default(image)?;
-        images_views.push(view);
-    }
-    Ok(images_views)
+        ImageDimensions::Dim2d { 
+            width: swapchain.image_extent()[0],
+            height: swapchain.image_extent()[1],
+            array_layers: swapchain.image_array_layers()
+        },
+        Format::R8G8B8A8_UNORM,
+        ImageUsage {
+            transfer_src: true,
+            storage: true,
+            ..Default::default()
+        },
+        ImageCreateFlags::default(),
+        [queue_family_index]
+    )?;
+    Ok(ImageView::new_default(image)?)
 }
 
 fn create_swapchain_images_views(images: &Vec<Arc<SwapchainImage>>) 
@@ -396,67 +294,294 @@ fn create_swapchain_images_views(images: &Vec<Arc<SwapchainImage>>)
     Ok(images_views)
 }
 
-fn create_descriptor_sets_for_swapchain(
+fn create_descriptor_set(
+    descriptor_allocator: &StandardDescriptorSetAllocator,
+    descriptor_set_layout: Arc<DescriptorSetLayout>,
+    image_view: Arc<ImageView<StorageImage>>,
+    view_pos_buffer: Arc<CpuAccessibleBuffer<GpuViewPosition>>,
+    palette_buffer: Arc<CpuAccessibleBuffer<GpuPalette>>,
+    histogram_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+    trap_image_buffer: Arc<CpuAccessibleBuffer<GpuTrapImage>>,
+    height_buffer: Arc<DeviceLocalBuffer<[f32]>>) 
+-> Result<Arc<PersistentDescriptorSet>, Box<dyn Error>> {
+    let descriptor_set = PersistentDescriptorSet::new(
+        descriptor_allocator,
+        descriptor_set_layout,
+        [
+            WriteDescriptorSet::image_view(0, image_view),
+            WriteDescriptorSet::buffer(1, view_pos_buffer),
+            WriteDescriptorSet::buffer(2, palette_buffer),
+            WriteDescriptorSet::buffer(5, histogram_buffer),
+            WriteDescriptorSet::buffer(6, trap_image_buffer),
+            WriteDescriptorSet::buffer(7, height_buffer),
+        ]
+    )?;
+    Ok(descriptor_set)
+}
+
+/// What depends on the size of the window or on the pipelines: the
+/// buffers of the passes after the kernel, the descriptor set of the kernel
+/// and the passes themselves.
+struct FrameResources {
+    histogram_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+    height_buffer: Arc<DeviceLocalBuffer<[f32]>>,
+    descriptor_set: Arc<PersistentDescriptorSet>,
+    histogram_pass: HistogramPass,
+    lighting_pass: LightingPass,
+}
+
+/// Resources of frames rendered into `image_view` with `pipelines`, the
+/// same at startup, after a resize and after a shader reload.
+fn create_frame_resources(
+    allocator: &GenericMemoryAllocator::<Arc<FreeListAllocator>>,
     descriptor_allocator: &StandardDescriptorSetAllocator,
+    pipelines: &ComputePipelines,
+    image_view: Arc<ImageView<StorageImage>>,
+    view_pos_buffer: Arc<CpuAccessibleBuffer<GpuViewPosition>>,
+    palette_buffer: Arc<CpuAccessibleBuffer<GpuPalette>>,
+    trap_image_buffer: Arc<CpuAccessibleBuffer<GpuTrapImage>>,
+    queue_family_index: u32)
+-> Result<FrameResources, Box<dyn Error>> {
+    let [width, height] = image_view.image().dimensions().width_height();
+    // Значения пикселей для раскраски по гистограмме и высоты для освещения
+    let histogram_buffer = create_histogram_buffer(allocator, width, height, queue_family_index)?;
+    let height_buffer = create_height_buffer(allocator, width, height, queue_family_index)?;
+    let descriptor_set = create_descriptor_set(
+        descriptor_allocator, 
+        pipelines.descriptor_set_layout(Precision::Single), 
+        image_view.clone(),
+        view_pos_buffer.clone(),
+        palette_buffer.clone(),
+        histogram_buffer.clone(),
+        trap_image_buffer,
+        height_buffer.clone()
+    )?;
+    let histogram_pass = HistogramPass::new(descriptor_allocator, pipelines, image_view.clone(), 
+        palette_buffer, histogram_buffer.clone())?;
+    let lighting_pass = LightingPass::new(descriptor_allocator, pipelines, image_view, 
+        view_pos_buffer, height_buffer.clone())?;
+    Ok(FrameResources { histogram_buffer, height_buffer, descriptor_set, histogram_pass, lighting_pass })
+}
+
+fn create_reference_orbit_buffer(
+    allocator: &GenericMemoryAllocator::<Arc<FreeListAllocator>>,
+    reference_orbit: &ReferenceOrbit)
+-> Result<Arc<CpuAccessibleBuffer<[[f64; 2]]>>, Box<dyn Error>> {
+    let buffer = CpuAccessibleBuffer::from_iter(
+        allocator,
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        false,
+        reference_orbit.points.iter().copied()
+    )?;
+    Ok(buffer)
+}
+
+fn create_perturbation_descriptor_set(
+    descriptor_allocator: &StandardDescriptorSetAllocator,
+    descriptor_set_layout: Arc<DescriptorSetLayout>,
+    image_view: Arc<ImageView<StorageImage>>,
+    view_pos_buffer: Arc<CpuAccessibleBuffer<GpuViewPosition>>,
+    palette_buffer: Arc<CpuAccessibleBuffer<GpuPalette>>,
+    orbit_buffer: Arc<CpuAccessibleBuffer<[[f64; 2]]>>,
+    series_buffer: Arc<CpuAccessibleBuffer<GpuSeriesApproximation>>,
+    histogram_buffer: Arc<DeviceLocalBuffer<[u32]>>,
+    trap_image_buffer: Arc<CpuAccessibleBuffer<GpuTrapImage>>,
+    height_buffer: Arc<DeviceLocalBuffer<[f32]>>)
+-> Result<Arc<PersistentDescriptorSet>, Box<dyn Error>> {
+    let descriptor_set = PersistentDescriptorSet::new(
+        descriptor_allocator,
+        descriptor_set_layout,
+        [
+            WriteDescriptorSet::image_view(0, image_view),
+            WriteDescriptorSet::buffer(1, view_pos_buffer),
+            WriteDescriptorSet::buffer(2, palette_buffer),
+            WriteDescriptorSet::buffer(3, orbit_buffer),
+            WriteDescriptorSet::buffer(4, series_buffer),
+            WriteDescriptorSet::buffer(5, histogram_buffer),
+            WriteDescriptorSet::buffer(6, trap_image_buffer),
+            WriteDescriptorSet::buffer(7, height_buffer),
+        ]
+    )?;
+    Ok(descriptor_set)
+}
+
+fn create_julia_preview(
+    allocator: &GenericMemoryAllocator::<Arc<FreeListAllocator>>,
+    descriptor_allocator: &StandardDescriptorSetAllocator,
+    pipelines: &ComputePipelines,
+    palette_buffer: Arc<CpuAccessibleBuffer<GpuPalette>>,
+    trap_image_buffer: Arc<CpuAccessibleBuffer<GpuTrapImage>>,
+    queue_family_index: u32)
+-> Result<JuliaPreview, Box<dyn Error>> {
+    let image = StorageImage::with_usage(
+        allocator,
+        ImageDimensions::Dim2d { 
+            width: JULIA_PREVIEW_SIZE[0],
+            height: JULIA_PREVIEW_SIZE[1],
+            array_layers: 1
+        },
+        Format::R8G8B8A8_UNORM,
+        ImageUsage {
+            transfer_src: true,
+            storage: true,
+            ..Default::default()
+        },
+        ImageCreateFlags::default(),
+        [queue_family_index]
+    )?;
+    let image_view = ImageView::new_default(image)?;
+    let view_pos_buffer = CpuAccessibleBuffer::from_data(
+        allocator,
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        false,
+        GpuViewPosition::from(&ViewPosition::julia([0.0, 0.0]))
+    )?;
+    // Превью раскрашивается по числу итераций без освещения, буферы гистограммы,
+    // ловушки и высот нужны только раскладке
+    let histogram_buffer = create_histogram_buffer(allocator, JULIA_PREVIEW_SIZE[0], 
+        JULIA_PREVIEW_SIZE[1], queue_family_index)?;
+    let height_buffer = create_height_buffer(allocator, JULIA_PREVIEW_SIZE[0], 
+        JULIA_PREVIEW_SIZE[1], queue_family_index)?;
+    let descriptor_set = PersistentDescriptorSet::new(
+        descriptor_allocator,
+        pipelines.descriptor_set_layout(Precision::Single),
+        [
+            WriteDescriptorSet::image_view(0, image_view.clone()),
+            WriteDescriptorSet::buffer(1, view_pos_buffer.clone()),
+            WriteDescriptorSet::buffer(2, palette_buffer),
+            WriteDescriptorSet::buffer(5, histogram_buffer),
+            WriteDescriptorSet::buffer(6, trap_image_buffer),
+            WriteDescriptorSet::buffer(7, height_buffer),
+        ]
+    )?;
+    Ok(JuliaPreview {
+        pipeline: pipelines.get(Precision::Single),
+        image_view,
+        view_pos_buffer,
+        descriptor_set,
+    })
+}
+
+/// The kernel over `frame_tiles` of the refinement pass, alone in its
+/// command buffer so that the frame budget measures only it. Dispatches are
+/// rounded up to whole workgroups of `workgroup_side`, the shader skips the
+/// invocations past the image.
+fn create_tiles_command_buffer(
+    command_buffer_allocator: &StandardCommandBufferAllocator,
     pipeline: Arc<ComputePipeline>,
-    images_views: &Vec<Arc<ImageView<StorageImage>>>,
-    view_pos_buffer: Arc<CpuAccessibleBuffer<ViewPosition>>) 
--> Result<Vec<Arc<PersistentDescriptorSet>>, Box<dyn Error>> {
-    let mut result = vec![];
-    for image_view in images_views {
-        let descriptor_set_layout = pipeline.layout().set_layouts().get(0)
-            .expect("DescriptorSetLayout not found by index 0");
-        let descriptor_set = PersistentDescriptorSet::new(
-            descriptor_allocator,
-            descriptor_set_layout.clone(),
-            [
-                WriteDescriptorSet::image_view(0, image_view.clone()),
-                WriteDescriptorSet::buffer(1, view_pos_buffer.clone()),
-            ]
-        )?;
-        result.push(descriptor_set);
+    workgroup_side: u32,
+    frame_tiles: &FrameTiles,
+    descriptor_set: Arc<PersistentDescriptorSet>,
+    histogram_pass: Option<&HistogramPass>,
+    queue_family_index: u32)
+-> Result<PrimaryAutoCommandBuffer, Box<dyn Error>> {
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        queue_family_index,
+        CommandBufferUsage::OneTimeSubmit
+    )?;
+
+    if let (Some(histogram_pass), true) = (histogram_pass, frame_tiles.is_first) {
+        histogram_pass.record_clear(&mut command_buffer_builder)?;
     }
-    Ok(result)
+    command_buffer_builder.bind_pipeline_compute(pipeline.clone())
+        .bind_descriptor_sets(
+            PipelineBindPoint::Compute,
+            pipeline.layout().clone(),
+            0,
+            descriptor_set
+        );
+    // Поток на клетку, плитки у краёв могут быть неполными
+    for origin in &frame_tiles.tiles {
+        let size = [0, 1].map(|i| (frame_tiles.cells[i] - origin[i]).min(TILE_SIZE));
+        command_buffer_builder
+            .push_constants(pipeline.layout().clone(), 0, GpuTile { origin: *origin })
+            .dispatch([size[0].div_ceil(workgroup_side), size[1].div_ceil(workgroup_side), 1])?;
+    }
+
+    Ok(command_buffer_builder.build()?)
 }
 
-fn create_render_command_buffers(
+/// The rest of the frame after the tiles of `frame_tiles`, `None` if the
+/// image is complete and only has to be shown.
+fn create_render_command_buffer(
     command_buffer_allocator: &StandardCommandBufferAllocator,
-    pipeline: Arc<ComputePipeline>,
     extent: (u32, u32),
-    descriptor_sets: &Vec<Arc<PersistentDescriptorSet>>,
-    render_images_views: &Vec<Arc<ImageView<StorageImage>>>,
-    present_images: &Vec<Arc<ImageView<SwapchainImage>>>,
+    workgroup_side: u32,
+    frame_tiles: Option<&FrameTiles>,
+    histogram_pass: Option<&HistogramPass>,
+    lighting_pass: Option<&LightingPass>,
+    render_image_view: Arc<ImageView<StorageImage>>,
+    present_image: Arc<ImageView<SwapchainImage>>,
+    julia_preview: Option<&JuliaPreview>,
     queue_family_index: u32)
--> Result<Vec<Arc<PrimaryAutoCommandBuffer>>, Box<dyn Error>> {
-
-    let mut command_buffers = vec![];
-    for i in 0..descriptor_sets.len() {
-        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
-            command_buffer_allocator,
-            queue_family_index,
-            CommandBufferUsage::SimultaneousUse
-        )?;
+-> Result<PrimaryAutoCommandBuffer, Box<dyn Error>> {
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        command_buffer_allocator,
+        queue_family_index,
+        CommandBufferUsage::OneTimeSubmit
+    )?;
 
-        command_buffer_builder.bind_pipeline_compute(pipeline.clone())
+    let group_counts = [extent.0.div_ceil(workgroup_side), extent.1.div_ceil(workgroup_side), 1];
+    if let Some(frame_tiles) = frame_tiles {
+        // Гистограмма и освещение идут по всему кадру, когда посчитан весь проход
+        if frame_tiles.is_last {
+            if let Some(histogram_pass) = histogram_pass {
+                histogram_pass.record(&mut command_buffer_builder, group_counts)?;
+            }
+            if let Some(lighting_pass) = lighting_pass {
+                lighting_pass.record(&mut command_buffer_builder, group_counts)?;
+            }
+        }
+    }
+    command_buffer_builder
+        .copy_image(CopyImageInfo::images(
+            render_image_view.image().clone(), 
+            present_image.image().clone()
+        ))?;
+
+    // Превью в правом нижнем углу, если окно для него не слишком маленькое
+    let [preview_width, preview_height] = JULIA_PREVIEW_SIZE;
+    let fits = extent.0 >= preview_width + 2 * JULIA_PREVIEW_MARGIN
+        && extent.1 >= preview_height + 2 * JULIA_PREVIEW_MARGIN;
+    if let (Some(preview), true) = (julia_preview, fits) {
+        let mut copy_info = CopyImageInfo::images(
+            preview.image_view.image().clone(), 
+            present_image.image().clone()
+        );
+        copy_info.regions[0].dst_offset = [
+            extent.0 - preview_width - JULIA_PREVIEW_MARGIN,
+            extent.1 - preview_height - JULIA_PREVIEW_MARGIN,
+            0
+        ];
+        command_buffer_builder.bind_pipeline_compute(preview.pipeline.clone())
             .bind_descriptor_sets(
                 PipelineBindPoint::Compute,
-                pipeline.layout().clone(),
+                preview.pipeline.layout().clone(),
                 0,
-                vec![descriptor_sets[i].clone()]
+                preview.descriptor_set.clone()
             )
-            .dispatch([extent.0 / 16, extent.1 / 16, 1])?
-            .copy_image(CopyImageInfo::images(
-                render_images_views[i].image().clone(), 
-                present_images[i].image().clone()
-            ))?;
-
-        let command_buffer = command_buffer_builder.build()?;
-        command_buffers.push(Arc::new(command_buffer));
-    };
-    Ok(command_buffers)
+            .push_constants(preview.pipeline.layout().clone(), 0, GpuTile::default())
+            .dispatch([
+                preview_width.div_ceil(workgroup_side), 
+                preview_height.div_ceil(workgroup_side), 
+                1
+            ])?
+            .copy_image(copy_info)?;
+    }
+
+    Ok(command_buffer_builder.build()?)
 }
 
-pub fn main_old() {
+/// `frame_budget` is the time the kernel may take in a frame, a view that
+/// needs more is rendered over several frames.
+pub fn main_old(start_position: ViewPosition, watch_shader: Option<PathBuf>, frame_budget: Duration) {
     let event_loop = EventLoop::new();
     let window_builder = WindowBuilder::new()
         .with_title(format!("RVM {}", VERSION))
@@ -466,7 +591,12 @@ pub fn main_old() {
         Err(err) => { println!("Window creating error: {:?}", err); return; }
     };
 
-    let instance = match create_vulkan_instance() {
+    let library = match VulkanLibrary::new() {
+        Ok(library) => library,
+        Err(err) => { println!("Vulkan library loading error: {:?}", err); return; }
+    };
+    let instance_init_info = InstanceInitInfo::with_window_surface(&library);
+    let instance = match create_vulkan_instance(library, instance_init_info) {
         Ok(inst) => inst,
         Err(err) => { println!("Vulkan instance creating error: {:?}", err); return; }
     };
@@ -476,19 +606,19 @@ pub fn main_old() {
         Err(err) => { println!("Surface creating error: {:?}", err); return; }
     };
 
-    let physical_devices = match get_right_devices(instance.clone()) {
+    let device_init_info = DeviceInitInfo::default();
+    let physical_devices = match get_right_devices(instance.clone(), &device_init_info) {
         Ok(pd) => pd,
         Err(err) => { println!("Physical devices error: {:?}", err); return; }
     };
 
-    let (device, queues) = match create_device_connection(physical_devices[0].clone()) {
+    let (device, queues) = match create_device_connection(physical_devices[0].clone(), &device_init_info) {
         Ok(device) => device,
         Err(err) => { println!("Device creating error: {:?}", err); return; }
     };
     let main_queue = queues[0].clone();
 
-    let win32_monitor = get_app_monitor(window.clone());
-    let (mut swapchain, mut images) = match create_swapchain(surface.clone(), device.clone(), win32_monitor) {
+    let (mut swapchain, mut images) = match create_swapchain(surface.clone(), device.clone(), window.clone()) {
         Ok(swapchain_images) => swapchain_images,
         Err(err) => { println!("Swapchain creating error: {:?}", err); return; }
     };
@@ -503,8 +633,8 @@ pub fn main_old() {
     //     Err(err) => { println!("Framebuffers creating error: {}", err); return; }
     // };
 
-    let mut pipeline = match create_pipeline(device.clone()) {
-        Ok(pipeline) => pipeline,
+    let mut pipelines = match ComputePipelines::new(device.clone(), &start_position.formula) {
+        Ok(pipelines) => pipelines,
         Err(err) => { println!("Pipeline creating error: {:?}", err); return; }
     };
 
@@ -524,13 +654,13 @@ pub fn main_old() {
 
     
 
-    let mut storage_images_views = match create_storage_images_views(
+    let mut storage_image_view = match create_storage_image_view(
         &storage_images_allocator,
         swapchain.clone(),
         main_queue.queue_family_index()
     ) {
-        Ok(views) => views,
-        Err(err) => { println!("Storage images views creating error: {:?}", err); return; }
+        Ok(view) => view,
+        Err(err) => { println!("Storage image view creating error: {:?}", err); return; }
     };
 
     let mut swapchain_images_views = match create_swapchain_images_views(&images) {
@@ -538,7 +668,12 @@ pub fn main_old() {
         Err(err) => { println!("Swapchain images views creating error: {:?}", err); return; }
     };
 
-    let mut view_position = ViewPosition::new();
+    let mut view_position = start_position;
+    // Проходы уточнения, состояние и то, с чем сравнивается следующий кадр
+    let mut refinement = Refinement::default();
+    let mut frame_budget_ms = frame_budget.as_millis() as u64;
+    let mut drawn_view_position = GpuViewPosition::from(&view_position);
+    let mut drawn_palette = GpuPalette::from(&view_position.palette);
     let view_pos_buffer = CpuAccessibleBuffer::from_data(
         &view_position_allocator,
         BufferUsage {
@@ -546,34 +681,55 @@ pub fn main_old() {
             ..Default::default()
         },
         false,
-        view_position
+        refinement.apply(drawn_view_position)
     ).expect("Failed to create buffer");
-
-
-    let mut descriptor_sets = match create_descriptor_sets_for_swapchain(
-        &descriptor_allocator, 
-        pipeline.clone(), 
-        &storage_images_views,
-        view_pos_buffer.clone()
-    ) {
-        Ok(sets) => sets,
-        Err(err) => { println!("Descriptor sets creating error: {:?}", err); return; }
-    };
-
-    let mut command_buffers = match create_render_command_buffers(
-        &command_buffer_allocator,
-        pipeline.clone(), 
-        (window.inner_size().width, window.inner_size().height),
-        &descriptor_sets,
-        &storage_images_views,
-        &swapchain_images_views,
+    let palette_buffer = CpuAccessibleBuffer::from_data(
+        &view_position_allocator,
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        false,
+        GpuPalette::from(&view_position.palette)
+    ).expect("Failed to create buffer");
+    // Картинка ловушки большая, поэтому переписывается, только когда её сменили
+    let trap_image_buffer = CpuAccessibleBuffer::from_data(
+        &view_position_allocator,
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        false,
+        GpuTrapImage::from(&*view_position.trap.image)
+    ).expect("Failed to create buffer");
+    let mut uploaded_trap_image = view_position.trap.image.clone();
+    // Пересчитывается, только когда меняется центр, качество или нужная точность
+    let mut reference_orbit: Option<(ReferenceOrbit, Arc<CpuAccessibleBuffer<[[f64; 2]]>>)> = None;
+    let series_buffer = CpuAccessibleBuffer::from_data(
+        &view_position_allocator,
+        BufferUsage {
+            storage_buffer: true,
+            ..Default::default()
+        },
+        false,
+        GpuSeriesApproximation::default()
+    ).expect("Failed to create buffer");
+    // Набор с орбитой пересоздаётся вместе с ней или с буферами кадра, а не каждый кадр
+    let mut perturbation_descriptor_set: Option<Arc<PersistentDescriptorSet>> = None;
+    let mut frame = match create_frame_resources(
+        &view_position_allocator,
+        &descriptor_allocator,
+        &pipelines,
+        storage_image_view.clone(),
+        view_pos_buffer.clone(),
+        palette_buffer.clone(),
+        trap_image_buffer.clone(),
         main_queue.queue_family_index()
     ) {
-        Ok(command_buffers) => command_buffers,
-        Err(err) => { println!("Command buffers creating error: {:?}", err); return; }
+        Ok(frame) => frame,
+        Err(err) => { println!("Frame resources creating error: {:?}", err); return; }
     };
 
-
     let mut gui = Gui::new(
         &event_loop,
         surface.clone(),
@@ -581,6 +737,37 @@ pub fn main_old() {
         main_queue.clone(),
         true
     );
+    let mut julia_preview = match create_julia_preview(
+        &view_position_allocator,
+        &descriptor_allocator,
+        &pipelines,
+        palette_buffer.clone(),
+        trap_image_buffer.clone(),
+        main_queue.queue_family_index()
+    ) {
+        Ok(preview) => preview,
+        Err(err) => { println!("Julia preview creating error: {:?}", err); return; }
+    };
+    let mut is_julia_preview = false;
+    let mut formula_text = match &view_position.formula {
+        Formula::User(user_formula) => String::from(user_formula.source()),
+        _ => String::from("z^3 + c*exp(z)"),
+    };
+    let mut formula_error: Option<String> = None;
+    let mut shader_watcher = watch_shader.map(ShaderWatcher::new);
+    let mut shader_log: Option<String> = None;
+    let mut selected_stop = 0;
+    let mut palette_path = String::from("palette.json");
+    let mut palette_error: Option<String> = None;
+    let mut trap_image_path = String::from("trap.png");
+    let mut trap_image_error: Option<String> = None;
+    // Скорость сдвига палитры, градиентов в секунду
+    let mut palette_cycling = 0.0f32;
+    let mut last_frame_time = Instant::now();
+    // Окно перерисовывается только после событий, которые могли что-то поменять
+    let mut is_redraw_needed = true;
+    let mut cursor_position = None;
+
     let mut is_show_infos = false;
     let mut is_full_screen = false;
     let mut is_mouse_move_active = false;
@@ -595,7 +782,8 @@ pub fn main_old() {
         match event {
             Event::NewEvents(start_cause) => {},
             Event::WindowEvent { event, window_id } if window_id == window.id() => {
-                let _pass_events_to_game = !gui.update(&event);
+                let pass_events_to_game = !gui.update(&event);
+                is_redraw_needed = true;
                 match event {
                     WindowEvent::Resized(_) => {
                         match recreate_swapchain(swapchain.clone(), window.clone()) {
@@ -606,49 +794,54 @@ pub fn main_old() {
                         //     Ok(fb) => framebuffers = fb,
                         //     Err(err) => println!("Framebuffers recreating error: {}", err)
                         // };
-                        match create_pipeline(device.clone()) {
-                            Ok(pe) => pipeline = pe,
-                            Err(err) => { println!("Pipeline recreating error: {:?}", err); return; }
-                        };
                         storage_images_allocator = GenericMemoryAllocator::<Arc<BumpAllocator>>::new_default(device.clone());
-                        match create_storage_images_views(
+                        match create_storage_image_view(
                             &storage_images_allocator,
                             swapchain.clone(),
                             main_queue.queue_family_index()
                         ) {
-                            Ok(views) => storage_images_views = views,
-                            Err(err) => { println!("Storage images views recreating error: {:?}", err); return; }
+                            Ok(view) => storage_image_view = view,
+                            Err(err) => { println!("Storage image view recreating error: {:?}", err); return; }
                         };
                         match create_swapchain_images_views(&images) {
                             Ok(views) => swapchain_images_views = views,
                             Err(err) => { println!("Swapchain images views recreating error: {:?}", err); return; }
                         };
-                        match create_descriptor_sets_for_swapchain(
-                            &descriptor_allocator, 
-                            pipeline.clone(), 
-                            &storage_images_views,
-                            view_pos_buffer.clone()
-                        ) {
-                            Ok(ds) => descriptor_sets = ds,
-                            Err(err) => { println!("Descriptor sets recreating error: {:?}", err); return; }
-                        };
-                        match create_render_command_buffers(
-                            &command_buffer_allocator,
-                            pipeline.clone(), 
-                            (window.inner_size().width, window.inner_size().height),
-                            &descriptor_sets,
-                            &storage_images_views,
-                            &swapchain_images_views,
+                        match create_frame_resources(
+                            &view_position_allocator,
+                            &descriptor_allocator,
+                            &pipelines,
+                            storage_image_view.clone(),
+                            view_pos_buffer.clone(),
+                            palette_buffer.clone(),
+                            trap_image_buffer.clone(),
                             main_queue.queue_family_index()
                         ) {
-                            Ok(cb) => command_buffers = cb,
-                            Err(err) => { println!("Command buffers recreating error: {:?}", err); return; }
+                            Ok(resources) => frame = resources,
+                            Err(err) => { println!("Frame resources recreating error: {:?}", err); return; }
                         };
+                        perturbation_descriptor_set = None;
+                        refinement.restart();
                     }
                     WindowEvent::ScaleFactorChanged { .. } => {
                         //renderer.resize();
                         // Пересоздать конвейер
                     }
+                    WindowEvent::CursorMoved { position, .. } => {
+                        cursor_position = Some(position);
+                    }
+                    WindowEvent::CursorLeft { .. } => {
+                        cursor_position = None;
+                    }
+                    // Клик по множеству Мандельброта запоминает константу для режима Жюлиа
+                    WindowEvent::MouseInput { state: ElementState::Pressed, 
+                        button: MouseButton::Left, .. } if pass_events_to_game => {
+                        if let (Some(position), false) = (cursor_position, view_position.julia) {
+                            let size = window.inner_size();
+                            view_position.julia_c = view_position.point_at(position.x, position.y, 
+                                size.width as f64, size.height as f64);
+                        }
+                    }
                     WindowEvent::CloseRequested => {
                         *control_flow = ControlFlow::Exit;
                     }
@@ -659,20 +852,16 @@ pub fn main_old() {
                 match event {
                     DeviceEvent::Key(keyboard_input) => match keyboard_input {
                         KeyboardInput { scancode: 1, state: ElementState::Released, ..} 
-                            => is_show_infos = !is_show_infos,
+                            => { is_show_infos = !is_show_infos; is_redraw_needed = true; }
                         KeyboardInput { scancode: 28, state: ElementState::Released, ..} 
                             => {
                                 if !is_full_screen {
-                                    let current_monitor = match window.current_monitor() {
-                                        Some(monitor) => monitor,
-                                        None => return,
-                                    };
-                                    let video_mode = match current_monitor.video_modes().next() {
-                                        Some(video_mode) => video_mode,
+                                    let fullscreen = match get_fullscreen_mode(window.clone()) {
+                                        Some(fullscreen) => fullscreen,
                                         None => return,
                                     };
 
-                                    window.set_fullscreen(Some(Fullscreen::Exclusive(video_mode)));
+                                    window.set_fullscreen(Some(fullscreen));
                                     is_full_screen = true;
                                 }
                                 else {
@@ -694,20 +883,25 @@ pub fn main_old() {
                         else { is_mouse_zoom_active = false; }
                     }
                     DeviceEvent::MouseMotion { delta } => {
+                        is_redraw_needed |= is_mouse_move_active || is_mouse_zoom_active;
                         if is_mouse_move_active {
-                            view_position.pos_x -= (delta.0 as f32) / (view_position.zoom * 0.1).exp();
-                            view_position.pos_y -= (delta.1 as f32) / (view_position.zoom * 0.1).exp();
+                            let magnification = view_position.magnification();
+                            view_position.move_center([
+                                -delta.0 * 0.001 / magnification,
+                                -delta.1 * 0.001 / magnification,
+                            ]);
                             //println!("{} {}", delta_x, delta_y);
                         }
                         if is_mouse_zoom_active {
-                            view_position.zoom -= (delta.1 as f32) / 20.0;
+                            view_position.zoom -= delta.1 / 20.0;
                         }
                     },
                     DeviceEvent::MouseWheel { delta } => match delta {
                         winit::event::MouseScrollDelta::LineDelta(_, y) => {
-                            if view_position.zoom + y > 0.0 {
-                                view_position.zoom += y
+                            if view_position.zoom + y as f64 >= MIN_ZOOM {
+                                view_position.zoom += y as f64
                             }
+                            is_redraw_needed = true;
                         },
                         _ => (),
                     },
@@ -718,6 +912,58 @@ pub fn main_old() {
             //Event::RedrawRequested(window_id) if window_id == window_id => { }
 
             Event::MainEventsCleared => {
+                // Режим разработки: шейдер пересобирается после каждого сохранения файла,
+                // при ошибке остаются прежние конвейеры, а лог компилятора виден поверх картинки
+                // Файл проверяется и в покое, по таймеру, кадр нужен только после его изменения
+                if let Some(source) = shader_watcher.as_mut().and_then(ShaderWatcher::poll) {
+                    is_redraw_needed = true;
+                    let reloaded = match source {
+                        Ok(source) => ComputePipelines::from_source(
+                            device.clone(), &view_position.formula, source),
+                        Err(err) => Err(err.into()),
+                    };
+                    match reloaded {
+                        Ok(new_pipelines) => {
+                            pipelines = new_pipelines;
+                            shader_log = None;
+                            refinement.restart();
+                            match create_frame_resources(
+                                &view_position_allocator,
+                                &descriptor_allocator,
+                                &pipelines,
+                                storage_image_view.clone(),
+                                view_pos_buffer.clone(),
+                                palette_buffer.clone(),
+                                trap_image_buffer.clone(),
+                                main_queue.queue_family_index()
+                            ) {
+                                Ok(resources) => frame = resources,
+                                Err(err) => { println!("Frame resources recreating error: {:?}", err); return; }
+                            };
+                            perturbation_descriptor_set = None;
+                            match create_julia_preview(
+                                &view_position_allocator,
+                                &descriptor_allocator,
+                                &pipelines,
+                                palette_buffer.clone(),
+                                trap_image_buffer.clone(),
+                                main_queue.queue_family_index()
+                            ) {
+                                Ok(preview) => julia_preview = preview,
+                                Err(err) => { println!("Julia preview recreating error: {:?}", err); return; }
+                            };
+                        }
+                        Err(err) => shader_log = Some(err.to_string()),
+                    };
+                }
+
+                // В покое картинка готова, а вид, окно и палитра не менялись: кадр не нужен
+                let is_animated = palette_cycling != 0.0;
+                if !is_redraw_needed && !is_animated && refinement.pass() == RefinementPass::Done {
+                    return;
+                }
+                is_redraw_needed = false;
+
                 // if delta_time > (1000.0 / 60.0)
                 let (image_index, suboptimal, acquire_future) =
                 match swapchain::acquire_next_image(swapchain.clone(), None) {
@@ -730,6 +976,16 @@ pub fn main_old() {
                 
                 gui.immediate_ui(|gui| {
                     let ctx = gui.context();
+                    if let (Some(log), Some(watcher)) = (&shader_log, &shader_watcher) {
+                        egui::Window::new("Shader error")
+                            .anchor(egui::Align2::RIGHT_TOP, [-10.0, 10.0])
+                            .show(&ctx, |ui| {
+                                ui.label(format!("{} is not applied, the last good shader keeps running", 
+                                    watcher.path().display()));
+                                ui.label(egui::RichText::new(log).monospace()
+                                    .color(egui::Color32::LIGHT_RED));
+                            });
+                    }
                     if !is_show_infos {
                         let frame = egui::Frame::none();
                         egui::CentralPanel::default().frame(frame).show(&ctx, |ui| {
@@ -743,15 +999,198 @@ pub fn main_old() {
                                 .show(ui, |ui| {
                                     ui.style_mut().spacing.slider_width = 300.0;
                                     ui.add(egui::Slider::new(&mut view_position.quality, 1..=1000).text("Quality"));
-                                    ui.add(egui::Slider::new(&mut view_position.zoom, 1.0..=150.0).text("Zoom"));
+                                    ui.add(egui::Slider::new(&mut view_position.zoom, MIN_ZOOM..=2500.0).text("Zoom"));
                                     ui.add(egui::Slider::new(&mut view_position.pos_x, -1000.0..=1000.0).text("Pos X"));
                                     ui.add(egui::Slider::new(&mut view_position.pos_y, -1000.0..=1000.0).text("Pox Y"));
                                     ui.horizontal(|ui| {
                                         if ui.button("Reset").clicked() {
                                             view_position = view_position.reset();
                                         }
-                                        ui.color_edit_button_rgb(&mut view_position.color);
-                                        ui.color_edit_button_rgb(&mut view_position.fract_color);
+                                        ui.color_edit_button_rgb(&mut view_position.fract_color)
+                                            .on_hover_text("Points inside the set");
+                                        egui::ComboBox::from_label("Interior")
+                                            .selected_text(view_position.interior.name())
+                                            .show_ui(ui, |ui| {
+                                                for interior in Interior::ALL {
+                                                    ui.selectable_value(&mut view_position.interior, interior, interior.name());
+                                                }
+                                            });
+                                        ui.label(format!("Precision: {}", pipelines.precision_for(&view_position).name()));
+                                    });
+                                    ui.horizontal(|ui| {
+                                        let old_formula = view_position.formula.clone();
+                                        egui::ComboBox::from_label("Formula")
+                                            .selected_text(view_position.formula.name())
+                                            .show_ui(ui, |ui| {
+                                                for formula in Formula::ALL {
+                                                    let name = formula.name();
+                                                    ui.selectable_value(&mut view_position.formula, formula, name);
+                                                }
+                                            });
+                                        if view_position.formula != old_formula {
+                                            view_position = view_position.reset();
+                                        }
+                                        if view_position.formula == Formula::Multibrot {
+                                            ui.add(egui::Slider::new(&mut view_position.power, POWER_RANGE).text("Power"));
+                                        }
+                                    });
+                                    ui.horizontal(|ui| {
+                                        ui.label("z =");
+                                        ui.text_edit_singleline(&mut formula_text)
+                                            .on_hover_text(USER_FORMULA_NAMES);
+                                        if ui.button("Apply").clicked() {
+                                            match formula_text.parse::<UserFormula>() {
+                                                Ok(user_formula) => {
+                                                    let was_user = matches!(view_position.formula, Formula::User(_));
+                                                    view_position.formula = Formula::User(user_formula);
+                                                    // Правка своей формулы не сбрасывает вид
+                                                    if !was_user { view_position = view_position.reset(); }
+                                                    formula_error = None;
+                                                }
+                                                Err(err) => formula_error = Some(err.to_string()),
+                                            }
+                                        }
+                                    });
+                                    if let Some(error) = &formula_error {
+                                        ui.colored_label(egui::Color32::from_rgb(180, 0, 0), error);
+                                    }
+                                    egui::ComboBox::from_label("Coloring")
+                                        .selected_text(view_position.coloring.name())
+                                        .show_ui(ui, |ui| {
+                                            for coloring in Coloring::ALL {
+                                                ui.selectable_value(&mut view_position.coloring, coloring, coloring.name());
+                                            }
+                                        });
+                                    if view_position.coloring == Coloring::OrbitTrap {
+                                        let trap = &mut view_position.trap;
+                                        ui.horizontal(|ui| {
+                                            egui::ComboBox::from_label("Trap")
+                                                .selected_text(trap.shape.name())
+                                                .show_ui(ui, |ui| {
+                                                    for shape in TrapShape::ALL {
+                                                        ui.selectable_value(&mut trap.shape, shape, shape.name());
+                                                    }
+                                                });
+                                            ui.add(egui::DragValue::new(&mut trap.center[0]).speed(0.01).prefix("x: "));
+                                            ui.add(egui::DragValue::new(&mut trap.center[1]).speed(0.01).prefix("y: "));
+                                        });
+                                        if matches!(trap.shape, TrapShape::Circle | TrapShape::Image) {
+                                            ui.add(egui::Slider::new(&mut trap.radius, 0.01..=4.0)
+                                                .logarithmic(true).text("Trap radius"));
+                                        }
+                                        if matches!(trap.shape, TrapShape::Line | TrapShape::Cross | TrapShape::Image) {
+                                            ui.add(egui::Slider::new(&mut trap.angle, -PI..=PI).text("Trap angle"));
+                                        }
+                                        if trap.shape == TrapShape::Image {
+                                            ui.horizontal(|ui| {
+                                                ui.text_edit_singleline(&mut trap_image_path)
+                                                    .on_hover_text("PNG, transparent pixels let the orbit through");
+                                                if ui.button("Load").clicked() {
+                                                    match TrapImage::load(Path::new(&trap_image_path)) {
+                                                        Ok(image) => {
+                                                            trap.image = Arc::new(image);
+                                                            trap_image_error = None;
+                                                        }
+                                                        Err(err) => trap_image_error = Some(err.to_string()),
+                                                    }
+                                                }
+                                            });
+                                            if let Some(error) = &trap_image_error {
+                                                ui.colored_label(egui::Color32::from_rgb(180, 0, 0), error);
+                                            }
+                                        }
+                                    }
+                                    let lighting = &mut view_position.lighting;
+                                    ui.horizontal(|ui| {
+                                        ui.checkbox(&mut lighting.enabled, "Lighting");
+                                        if lighting.enabled {
+                                            egui::ComboBox::from_label("Height")
+                                                .selected_text(lighting.height.name())
+                                                .show_ui(ui, |ui| {
+                                                    for height in HeightField::ALL {
+                                                        ui.selectable_value(&mut lighting.height, height, height.name());
+                                                    }
+                                                });
+                                        }
+                                    });
+                                    if lighting.enabled {
+                                        ui.add(egui::Slider::new(&mut lighting.azimuth, -PI..=PI).text("Azimuth"));
+                                        ui.add(egui::Slider::new(&mut lighting.elevation, 0.0..=PI / 2.0).text("Elevation"));
+                                        ui.add(egui::Slider::new(&mut lighting.specular, 0.0..=1.0).text("Specular"));
+                                        ui.add(egui::Slider::new(&mut lighting.relief, 0.01..=100.0)
+                                            .logarithmic(true).text("Relief"));
+                                    }
+                                    let supersampling = &mut view_position.supersampling;
+                                    ui.horizontal(|ui| {
+                                        let samples_name = |samples: u32| if samples > 1 { 
+                                            format!("{0}x{0}", samples) 
+                                        } else { "Off".to_string() };
+                                        egui::ComboBox::from_label("Anti-aliasing")
+                                            .selected_text(samples_name(supersampling.samples))
+                                            .show_ui(ui, |ui| {
+                                                for samples in 1..=MAX_SAMPLES {
+                                                    ui.selectable_value(&mut supersampling.samples, samples, samples_name(samples));
+                                                }
+                                            });
+                                        if supersampling.is_enabled() {
+                                            let mut jittered = supersampling.pattern == SamplePattern::Jittered;
+                                            if ui.checkbox(&mut jittered, "Jittered").changed() {
+                                                supersampling.pattern = if jittered { SamplePattern::Jittered } else { SamplePattern::Grid };
+                                            }
+                                            ui.checkbox(&mut supersampling.adaptive, "Adaptive")
+                                                .on_hover_text("Extra samples only on the edges");
+                                        }
+                                    });
+                                    ui.add(egui::Slider::new(&mut frame_budget_ms, FRAME_BUDGET_RANGE).text("Frame budget, ms"))
+                                        .on_hover_text("Slower views are rendered over several frames");
+                                    ui.horizontal(|ui| {
+                                        ui.menu_button("Palette", |ui| {
+                                            for name in PRESETS {
+                                                if ui.button(name).clicked() {
+                                                    if let Some(preset) = Palette::preset(name) {
+                                                        view_position.palette.stops = preset.stops;
+                                                    }
+                                                    selected_stop = 0;
+                                                    ui.close_menu();
+                                                }
+                                            }
+                                        });
+                                        ui.add(egui::Slider::new(&mut palette_cycling, -1.0..=1.0).text("Cycling"));
+                                    });
+                                    ui::palette_editor::palette_editor(ui, &mut view_position.palette, &mut selected_stop);
+                                    ui.add(egui::Slider::new(&mut view_position.palette.offset, 0.0..=1.0).text("Offset"));
+                                    ui.add(egui::Slider::new(&mut view_position.palette.scale, 0.1..=20.0)
+                                        .logarithmic(true).text("Scale"));
+                                    ui.horizontal(|ui| {
+                                        ui.text_edit_singleline(&mut palette_path)
+                                            .on_hover_text(".map (Fractint), .ggr (GIMP) or .json");
+                                        if ui.button("Load").clicked() {
+                                            match load_palette(Path::new(&palette_path)) {
+                                                Ok(palette) => {
+                                                    view_position.palette = palette;
+                                                    selected_stop = 0;
+                                                    palette_error = None;
+                                                }
+                                                Err(err) => palette_error = Some(err.to_string()),
+                                            }
+                                        }
+                                        if ui.button("Save").clicked() {
+                                            palette_error = save_palette(Path::new(&palette_path), &view_position.palette)
+                                                .err().map(|err| err.to_string());
+                                        }
+                                    });
+                                    if let Some(error) = &palette_error {
+                                        ui.colored_label(egui::Color32::from_rgb(180, 0, 0), error);
+                                    }
+                                    ui.horizontal(|ui| {
+                                        if ui.checkbox(&mut view_position.julia, "Julia").changed() {
+                                            view_position = view_position.reset();
+                                        }
+                                        if !view_position.julia {
+                                            ui.checkbox(&mut is_julia_preview, "Julia preview");
+                                        }
+                                        ui.label(format!("c = {:.6} {:+.6}i", 
+                                            view_position.julia_c[0], view_position.julia_c[1]));
                                     });
                                 });
                             });
@@ -784,11 +1223,178 @@ pub fn main_old() {
 
 
 
+                let frame_time = last_frame_time.elapsed().as_secs_f32().min(MAX_FRAME_TIME);
+                last_frame_time = Instant::now();
+                if palette_cycling != 0.0 {
+                    let offset = view_position.palette.offset + palette_cycling * frame_time;
+                    view_position.palette.offset = offset.rem_euclid(1.0);
+                }
+
+                // Формула зашита в конвейеры, при её смене они собираются заново.
+                // Если своя формула не собралась, ошибка видна в панели, а формула откатывается.
+                if *pipelines.formula() != view_position.formula {
+                    match pipelines.with_formula(device.clone(), &view_position.formula) {
+                        Ok(new_pipelines) => pipelines = new_pipelines,
+                        Err(err) => {
+                            formula_error = Some(err.to_string());
+                            view_position.formula = pipelines.formula().clone();
+                        }
+                    };
+                    julia_preview.pipeline = pipelines.get(Precision::Single);
+                    refinement.restart();
+                }
+
+                let precision = pipelines.precision_for(&view_position);
+                let descriptor_set = if precision == Precision::Perturbation {
+                    let is_orbit_valid = matches!(&reference_orbit, 
+                        Some((orbit, _)) if orbit.is_valid_for(&view_position));
+                    if !is_orbit_valid {
+                        let orbit = ReferenceOrbit::new(&view_position);
+                        match create_reference_orbit_buffer(&view_position_allocator, &orbit) {
+                            Ok(buffer) => reference_orbit = Some((orbit, buffer)),
+                            Err(err) => { println!("Reference orbit buffer creating error: {:?}", err); return; }
+                        };
+                        perturbation_descriptor_set = None;
+                    }
+                    let (orbit, orbit_buffer) = match &reference_orbit {
+                        Some((orbit, buffer)) => (orbit, buffer.clone()),
+                        None => return,
+                    };
+                    // Зависит ещё и от масштаба и размера окна, но считается быстро
+                    let series = SeriesApproximation::for_screen(orbit, &view_position, 
+                        window.inner_size().width, window.inner_size().height);
+                    match series_buffer.write() {
+                        Ok(mut content) => *content = GpuSeriesApproximation::from(&series),
+                        Err(err) => { println!("Series buffer writing error: {:?}", err); return; }
+                    };
+                    if perturbation_descriptor_set.is_none() {
+                        match create_perturbation_descriptor_set(
+                            &descriptor_allocator,
+                            pipelines.descriptor_set_layout(precision),
+                            storage_image_view.clone(),
+                            view_pos_buffer.clone(),
+                            palette_buffer.clone(),
+                            orbit_buffer,
+                            series_buffer.clone(),
+                            frame.histogram_buffer.clone(),
+                            trap_image_buffer.clone(),
+                            frame.height_buffer.clone()
+                        ) {
+                            Ok(set) => perturbation_descriptor_set = Some(set),
+                            Err(err) => { println!("Descriptor set creating error: {:?}", err); return; }
+                        };
+                    }
+                    match &perturbation_descriptor_set {
+                        Some(set) => set.clone(),
+                        None => return,
+                    }
+                }
+                else { frame.descriptor_set.clone() };
+
+                let show_julia_preview = is_julia_preview && !view_position.julia;
+                if show_julia_preview {
+                    let size = window.inner_size();
+                    let julia_c = match cursor_position {
+                        Some(position) => view_position.point_at(position.x, position.y, 
+                            size.width as f64, size.height as f64),
+                        None => view_position.julia_c,
+                    };
+                    let preview_position = ViewPosition {
+                        quality: view_position.quality,
+                        fract_color: view_position.fract_color,
+                        ..ViewPosition::julia(julia_c)
+                    };
+                    match julia_preview.view_pos_buffer.write() {
+                        Ok(mut content) => *content = GpuViewPosition::from(&preview_position),
+                        Err(err) => { println!("Julia preview buffer writing error: {:?}", err); return; }
+                    };
+                }
+
+                // Пока вид меняется, рисуется превью, а после остановки кадр уточняется
+                // несколькими проходами в той же картинке
+                let gpu_view_position = GpuViewPosition::from(&view_position);
+                let gpu_palette = GpuPalette { 
+                    cycling: (palette_cycling != 0.0) as u32, 
+                    ..GpuPalette::from(&view_position.palette) 
+                };
+                let is_trap_image_changed = !Arc::ptr_eq(&uploaded_trap_image, &view_position.trap.image);
+                if bytes_of(&gpu_view_position) != bytes_of(&drawn_view_position) || is_trap_image_changed {
+                    refinement.restart();
+                }
+                else if bytes_of(&gpu_palette) != bytes_of(&drawn_palette) {
+                    refinement.recolor();
+                }
+                (drawn_view_position, drawn_palette) = (gpu_view_position, gpu_palette);
+                match view_pos_buffer.write() {
+                    Ok(mut content) => *content = refinement.apply(gpu_view_position),
+                    Err(err) => { println!("View position buffer writing error: {:?}", err); return; }
+                };
+                match palette_buffer.write() {
+                    Ok(mut content) => *content = gpu_palette,
+                    Err(err) => { println!("Palette buffer writing error: {:?}", err); return; }
+                };
+                if is_trap_image_changed {
+                    match trap_image_buffer.write() {
+                        Ok(mut content) => *content = GpuTrapImage::from(&*view_position.trap.image),
+                        Err(err) => { println!("Trap image buffer writing error: {:?}", err); return; }
+                    };
+                    uploaded_trap_image = view_position.trap.image.clone();
+                }
+
+                let histogram_pass = if view_position.coloring == Coloring::Histogram { 
+                    Some(&frame.histogram_pass) 
+                } else { None };
+                let frame_tiles = refinement.take_tiles([window.inner_size().width, window.inner_size().height]);
+                // Плитки идут отдельно и с ожиданием, чтобы в бюджет входили только они
+                if let Some(frame_tiles) = &frame_tiles {
+                    let tiles_command_buffer = match create_tiles_command_buffer(
+                        &command_buffer_allocator,
+                        pipelines.get(precision),
+                        pipelines.workgroup_side(),
+                        frame_tiles,
+                        descriptor_set,
+                        histogram_pass,
+                        main_queue.queue_family_index()
+                    ) {
+                        Ok(command_buffer) => command_buffer,
+                        Err(err) => { println!("Tiles command buffer creating error: {:?}", err); return; }
+                    };
+                    let render_start = Instant::now();
+                    let tiles_future = match sync::now(device.clone())
+                        .then_execute(queues[0].clone(), tiles_command_buffer) {
+                            Ok(future) => future,
+                            Err(err) => { println!("Tiles executing error: {:?}", err); return; }
+                        };
+                    match tiles_future.then_signal_fence_and_flush() {
+                        Ok(future) => { future.wait(None).unwrap(); }
+                        Err(err) => { println!("Failed to flush tiles: {:?}", err); return; }
+                    }
+                    refinement.fit_budget(frame_tiles.tiles.len(), render_start.elapsed(), 
+                        Duration::from_millis(frame_budget_ms));
+                }
+                let command_buffer = match create_render_command_buffer(
+                    &command_buffer_allocator,
+                    (window.inner_size().width, window.inner_size().height),
+                    pipelines.workgroup_side(),
+                    frame_tiles.as_ref(),
+                    histogram_pass,
+                    if view_position.lighting.enabled { 
+                        Some(&frame.lighting_pass) 
+                    } else { None },
+                    storage_image_view.clone(),
+                    swapchain_images_views[image_index as usize].clone(),
+                    if show_julia_preview { Some(&julia_preview) } else { None },
+                    main_queue.queue_family_index()
+                ) {
+                    Ok(command_buffer) => command_buffer,
+                    Err(err) => { println!("Command buffer creating error: {:?}", err); return; }
+                };
+
                 let exec_future = match sync::now(device.clone())
                     .join(acquire_future)
                     .then_execute(
                         queues[0].clone(),
-                        command_buffers[image_index as usize].clone()
+                        command_buffer
                     ) {
                         Ok(cbf) => cbf,
                         Err(err) => return
@@ -814,16 +1420,19 @@ pub fn main_old() {
                     Err(FlushError::OutOfDate) => { return; }
                     Err(e) => { println!("Failed to flush future: {:?}", e); }
                 }
-
-                let mut content = view_pos_buffer.write().unwrap();
-                content.quality = view_position.quality;
-                content.zoom = view_position.zoom;
-                content.pos_x = view_position.pos_x;
-                content.pos_y = view_position.pos_y;
-                content.color = view_position.color;
-                content.fract_color = view_position.fract_color;
+                refinement.advance();
             }
-            Event::RedrawEventsCleared => {},
+            // Кадры идут подряд, пока картинка уточняется или палитра крутится,
+            // иначе цикл спит до следующего события
+            Event::RedrawEventsCleared if *control_flow != ControlFlow::Exit => {
+                *control_flow = if refinement.pass() != RefinementPass::Done || palette_cycling != 0.0 {
+                    ControlFlow::Poll
+                }
+                else if shader_watcher.is_some() {
+                    ControlFlow::WaitUntil(Instant::now() + SHADER_POLL_INTERVAL)
+                }
+                else { ControlFlow::Wait };
+            },
             Event::LoopDestroyed => {},
             _ => (),
         }
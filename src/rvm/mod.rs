@@ -1,5 +1,26 @@
 pub mod main_old;
+pub mod headless;
+pub mod cpu_renderer;
+pub mod device_list;
+pub mod view_position;
+pub mod precision;
+pub mod formula;
+pub mod coloring;
+pub mod palette;
+pub mod palette_file;
+pub mod histogram;
+pub mod orbit_trap;
+pub mod lighting;
+pub mod supersampling;
+pub mod refinement;
+pub mod user_formula;
+pub mod pipelines;
+pub mod fixed_point;
+pub mod reference_orbit;
+pub mod series_approximation;
+pub mod shader_watcher;
 
+mod setup;
 mod instance_init_info;
 mod device_init_info;
-mod shader_module;
\ No newline at end of file
+mod shader_module;
@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytemuck::{ Pod, Zeroable };
+
+/// Side of the `TrapImage` buffer in `compute.glsl`, bigger images are scaled down.
+pub const TRAP_IMAGE_SIZE: usize = 256;
+
+/// Shape the orbit is measured against, `TRAP_*` of `compute.glsl`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TrapShape {
+    #[default]
+    Point,
+    /// Straight line through the centre along the angle.
+    Line,
+    /// Two lines crossing at right angles.
+    Cross,
+    Circle,
+    /// Picture: the first orbit point over an opaque pixel takes its color,
+    /// other points are measured against the centre like `Point`.
+    Image,
+}
+
+impl TrapShape {
+    pub const ALL: [TrapShape; 5] = [TrapShape::Point, TrapShape::Line, TrapShape::Cross,
+        TrapShape::Circle, TrapShape::Image];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrapShape::Point => "point",
+            TrapShape::Line => "line",
+            TrapShape::Cross => "cross",
+            TrapShape::Circle => "circle",
+            TrapShape::Image => "image",
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            TrapShape::Point => 0,
+            TrapShape::Line => 1,
+            TrapShape::Cross => 2,
+            TrapShape::Circle => 3,
+            TrapShape::Image => 4,
+        }
+    }
+}
+
+/// Trap of `Coloring::OrbitTrap`: escaped points are colored by the closest
+/// their orbit came to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrbitTrap {
+    pub shape: TrapShape,
+    /// The point, the crossing of the lines, the middle of the circle or the image.
+    pub center: [f64; 2],
+    /// Radius of the circle, half the longer side of the image.
+    pub radius: f64,
+    /// Turn of the trap in radians, the line goes along it.
+    pub angle: f64,
+    pub image: Arc<TrapImage>,
+}
+
+impl Default for OrbitTrap {
+    fn default() -> Self {
+        OrbitTrap {
+            shape: TrapShape::Point,
+            center: [0.0, 0.0],
+            radius: 0.5,
+            angle: 0.0,
+            image: Arc::new(TrapImage::default()),
+        }
+    }
+}
+
+/// RGBA8 picture of `TrapShape::Image`, no bigger than `TRAP_IMAGE_SIZE` on a side.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrapImage {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 4]>,
+}
+
+impl TrapImage {
+    /// Rows of `width` pixels, scaled down to the nearest pixel if too big.
+    pub fn new(width: u32, height: u32, pixels: Vec<[u8; 4]>) -> Self {
+        let longer = width.max(height) as usize;
+        if longer <= TRAP_IMAGE_SIZE { return TrapImage { width, height, pixels } }
+
+        let scaled = |side: u32| ((side as usize * TRAP_IMAGE_SIZE / longer).max(1)) as u32;
+        let (new_width, new_height) = (scaled(width), scaled(height));
+        let mut scaled_pixels = Vec::with_capacity((new_width * new_height) as usize);
+        for y in 0..new_height as usize {
+            for x in 0..new_width as usize {
+                let source_x = x * width as usize / new_width as usize;
+                let source_y = y * height as usize / new_height as usize;
+                scaled_pixels.push(pixels[source_y * width as usize + source_x]);
+            }
+        }
+        TrapImage { width: new_width, height: new_height, pixels: scaled_pixels }
+    }
+
+    /// Reads a PNG of any color type, 16-bit channels are cut to 8.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+        let bytes = &buffer[..info.buffer_size()];
+
+        let pixels: Vec<[u8; 4]> = match info.color_type {
+            png::ColorType::Rgba => bytes.chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+            png::ColorType::Rgb => bytes.chunks_exact(3).map(|p| [p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => bytes.chunks_exact(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => bytes.iter().map(|g| [*g, *g, *g, 255]).collect(),
+            color_type => return Err(format!("unsupported PNG color type {:?}", color_type).into()),
+        };
+        Ok(TrapImage::new(info.width, info.height, pixels))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+/// Layout of the `TrapImage` storage buffer in `compute.glsl`: a pixel is
+/// RGBA8 packed into a `u32`, rows are `TRAP_IMAGE_SIZE` apart.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Zeroable, Pod)]
+pub struct GpuTrapImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: [[u32; TRAP_IMAGE_SIZE]; TRAP_IMAGE_SIZE],
+}
+
+impl From<&TrapImage> for GpuTrapImage {
+    fn from(image: &TrapImage) -> Self {
+        let mut gpu_image = GpuTrapImage::zeroed();
+        gpu_image.width = image.width;
+        gpu_image.height = image.height;
+        for (y, row) in image.pixels.chunks_exact(image.width.max(1) as usize).enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                gpu_image.pixels[y][x] = u32::from_le_bytes(*pixel);
+            }
+        }
+        gpu_image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rvm::headless::save_png;
+
+    #[test]
+    fn trap_image_files() {
+        let (width, height) = (512, 300);
+        let mut pixels = vec![0u8; width as usize * height as usize * 4];
+        for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+            let x = i % width as usize;
+            pixel.copy_from_slice(&[(x / 2) as u8, 100, 200, if x < 256 { 255 } else { 0 }]);
+        }
+        let path = std::env::temp_dir().join("rvm_trap.png");
+        save_png(&path, width, height, &pixels).unwrap();
+
+        let image = TrapImage::load(&path).unwrap();
+        assert_eq!((image.width(), image.height()), (256, 150), "scaled down to fit");
+        assert_eq!(image.pixel(10, 20), [10, 100, 200, 255]);
+        assert_eq!(image.pixel(200, 149)[3], 0);
+        assert!(TrapImage::load(&std::env::temp_dir().join("rvm_missing_trap.png")).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use bytemuck::{ Pod, Zeroable };
+use serde::{ Serialize, Deserialize };
+
+/// Size of the stops array of the `Palette` buffer in `compute.glsl`,
+/// enough for the 256 colors of a Fractint map.
+pub const MAX_STOPS: usize = 256;
+
+/// Names of the bundled palettes, see `Palette::preset`.
+pub const PRESETS: [&str; 7] = ["green", "ultra-fractal", "fire", "ocean", "grayscale",
+    "rainbow", "sunset"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColorStop {
+    /// Place in the gradient, from 0 to 1.
+    pub position: f32,
+    pub color: [f32; 3],
+}
+
+/// Multi-stop gradient for escaped points. A point takes the color at its
+/// iteration value as a share of `quality`, times `scale`, plus `offset`,
+/// wrapped into `[0, 1)`. Between stops the color is interpolated linearly,
+/// before the first and after the last it is that stop's color.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    /// Sorted by position, at least one, no more than `MAX_STOPS` reach the GPU.
+    pub stops: Vec<ColorStop>,
+    #[serde(default)]
+    pub offset: f32,
+    /// How many times the gradient repeats over `quality` iterations.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+fn default_scale() -> f32 { 1.0 }
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::monochrome([0.0, 1.0, 0.0])
+    }
+}
+
+impl Palette {
+    /// Sorts `stops` by position.
+    pub fn new(stops: Vec<ColorStop>) -> Self {
+        let mut stops = stops;
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        Palette { stops, offset: 0.0, scale: 1.0 }
+    }
+
+    /// From black to `color`, the coloring RVM started with.
+    pub fn monochrome(color: [f32; 3]) -> Self {
+        Palette::new(vec![
+            ColorStop { position: 0.0, color: [0.0, 0.0, 0.0] },
+            ColorStop { position: 1.0, color },
+        ])
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        let stops: &[(f32, [u8; 3])] = match name {
+            "green" => return Some(Palette::default()),
+            "ultra-fractal" => &[(0.0, [0, 7, 100]), (0.16, [32, 107, 203]),
+                (0.42, [237, 255, 255]), (0.6425, [255, 170, 0]), (0.8575, [0, 2, 0]),
+                (1.0, [0, 7, 100])],
+            "fire" => &[(0.0, [0, 0, 0]), (0.3, [160, 20, 0]), (0.6, [255, 140, 0]),
+                (0.85, [255, 240, 120]), (1.0, [255, 255, 255])],
+            "ocean" => &[(0.0, [0, 5, 30]), (0.4, [0, 80, 160]), (0.75, [60, 200, 220]),
+                (1.0, [240, 255, 255])],
+            "grayscale" => &[(0.0, [0, 0, 0]), (1.0, [255, 255, 255])],
+            "rainbow" => &[(0.0, [255, 0, 0]), (0.17, [255, 255, 0]), (0.33, [0, 255, 0]),
+                (0.5, [0, 255, 255]), (0.67, [0, 0, 255]), (0.83, [255, 0, 255]),
+                (1.0, [255, 0, 0])],
+            "sunset" => &[(0.0, [20, 10, 60]), (0.35, [150, 30, 110]), (0.65, [250, 110, 60]),
+                (0.9, [255, 220, 130]), (1.0, [20, 10, 60])],
+            _ => return None,
+        };
+        Some(Palette::new(stops.iter()
+            .map(|(position, color)| ColorStop {
+                position: *position,
+                color: color.map(|channel| channel as f32 / 255.0),
+            })
+            .collect()))
+    }
+
+    /// `palette_color` of the shader without cycling, `value` is the iteration
+    /// value over `quality`. The end of every repeat takes the last stop.
+    pub fn color_at(&self, value: f32) -> [f32; 3] {
+        let t = value * self.scale + self.offset;
+        let wrapped = t - t.floor();
+        self.gradient(if wrapped == 0.0 && t > 0.0 { 1.0 } else { wrapped })
+    }
+
+    /// Color of the gradient itself at `t` from 0 to 1, without offset and scale.
+    pub fn gradient(&self, t: f32) -> [f32; 3] {
+        let stops = &self.stops[..self.stops.len().min(MAX_STOPS)];
+        let mut previous = match stops.first() {
+            Some(first) => *first,
+            None => return [0.0, 0.0, 0.0],
+        };
+        if t <= previous.position { return previous.color }
+        for next in &stops[1..] {
+            if t <= next.position {
+                let a = (t - previous.position) / (next.position - previous.position);
+                return [0, 1, 2].map(|i| previous.color[i] * (1.0 - a) + next.color[i] * a);
+            }
+            previous = *next;
+        }
+        previous.color
+    }
+
+    /// Adds a stop at `position` with the color the gradient has there,
+    /// returns its index.
+    pub fn insert_stop(&mut self, position: f32) -> usize {
+        let color = self.gradient(position);
+        let index = self.stops.partition_point(|stop| stop.position < position);
+        self.stops.insert(index, ColorStop { position, color });
+        index
+    }
+}
+
+/// Layout of the `Palette` storage buffer in `compute.glsl`,
+/// each stop is the color in `xyz` and the position in `w`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Zeroable, Pod)]
+pub struct GpuPalette {
+    pub offset: f32,
+    pub scale: f32,
+    pub count: u32,
+    /// Non-zero while the offset is animated, then the gradient wraps
+    /// around at the end of every repeat as well.
+    pub cycling: u32,
+    pub stops: [[f32; 4]; MAX_STOPS],
+}
+
+impl From<&Palette> for GpuPalette {
+    fn from(palette: &Palette) -> Self {
+        let mut stops = [[0.0; 4]; MAX_STOPS];
+        for (gpu_stop, stop) in stops.iter_mut().zip(&palette.stops) {
+            *gpu_stop = [stop.color[0], stop.color[1], stop.color[2], stop.position];
+        }
+        GpuPalette {
+            offset: palette.offset,
+            scale: palette.scale,
+            count: palette.stops.len().min(MAX_STOPS) as u32,
+            cycling: 0,
+            stops,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_gradient() {
+        let palette = Palette::new(vec![
+            ColorStop { position: 0.75, color: [0.0, 0.0, 1.0] },
+            ColorStop { position: 0.25, color: [1.0, 0.0, 0.0] },
+        ]);
+        assert_eq!(palette.color_at(0.1), [1.0, 0.0, 0.0], "before the first stop");
+        assert_eq!(palette.color_at(0.5), [0.5, 0.0, 0.5]);
+        assert_eq!(palette.color_at(0.9), [0.0, 0.0, 1.0], "after the last stop");
+
+        let shifted = Palette { offset: 0.5, scale: 2.0, ..palette.clone() };
+        assert_eq!(shifted.color_at(0.5), palette.color_at(0.5), "wraps around");
+        assert_eq!(palette.color_at(1.0), [0.0, 0.0, 1.0], "the end of a repeat is the last stop");
+        assert_eq!(shifted.color_at(0.75), [0.0, 0.0, 1.0]);
+
+        let mut inserted = palette.clone();
+        assert_eq!(inserted.insert_stop(0.5), 1);
+        assert_eq!(inserted.stops[1].color, [0.5, 0.0, 0.5]);
+        for value in [0.0, 0.3, 0.5, 0.8] {
+            assert_eq!(inserted.color_at(value), palette.color_at(value));
+        }
+
+        for name in PRESETS {
+            let preset = Palette::preset(name).unwrap();
+            assert!(preset.stops.windows(2).all(|pair| pair[0].position <= pair[1].position), "{}", name);
+        }
+    }
+}
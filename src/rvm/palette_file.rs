@@ -0,0 +1,219 @@
+use super::palette::{ Palette, ColorStop, MAX_STOPS };
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Files palettes are loaded from and saved to, told apart by extension.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// Fractint color map: 256 lines of `R G B` from 0 to 255.
+    Map,
+    /// GIMP gradient: segments between two colors with a blending function.
+    Ggr,
+    /// `Palette` as it is, with offset and scale, which the other two lack.
+    Json,
+}
+
+impl PaletteFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "map" => Some(PaletteFormat::Map),
+            "ggr" => Some(PaletteFormat::Ggr),
+            "json" => Some(PaletteFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn parse(&self, text: &str) -> Result<Palette, Box<dyn Error>> {
+        let palette = match self {
+            PaletteFormat::Map => parse_map(text)?,
+            PaletteFormat::Ggr => parse_ggr(text)?,
+            PaletteFormat::Json => {
+                let palette: Palette = serde_json::from_str(text)?;
+                Palette { offset: palette.offset, scale: palette.scale, ..Palette::new(palette.stops) }
+            }
+        };
+        if palette.stops.is_empty() { return Err("palette has no colors".into()) }
+        if palette.stops.len() > MAX_STOPS {
+            return Err(format!("palette has {} colors, at most {} are supported",
+                palette.stops.len(), MAX_STOPS).into());
+        }
+        Ok(palette)
+    }
+
+    pub fn write(&self, palette: &Palette) -> Result<String, Box<dyn Error>> {
+        match self {
+            PaletteFormat::Map => Ok(write_map(palette)),
+            PaletteFormat::Ggr => Ok(write_ggr(palette)),
+            PaletteFormat::Json => Ok(serde_json::to_string_pretty(palette)?),
+        }
+    }
+}
+
+fn format_of(path: &Path) -> Result<PaletteFormat, Box<dyn Error>> {
+    PaletteFormat::from_path(path).ok_or_else(|| format!(
+        "{}: unknown palette format, expected .map, .ggr or .json", path.display()).into())
+}
+
+pub fn load_palette(path: &Path) -> Result<Palette, Box<dyn Error>> {
+    let format = format_of(path)?;
+    let text = fs::read_to_string(path)?;
+    format.parse(&text).map_err(|err| format!("{}: {}", path.display(), err).into())
+}
+
+pub fn save_palette(path: &Path, palette: &Palette) -> Result<(), Box<dyn Error>> {
+    let text = format_of(path)?.write(palette)?;
+    fs::write(path, text)?;
+    Ok(())
+}
+
+fn parse_numbers(line: &str, line_number: usize) -> Result<Vec<f32>, Box<dyn Error>> {
+    line.split_whitespace()
+        .map(|word| word.parse::<f32>()
+            .map_err(|_| format!("line {}: '{}' is not a number", line_number, word).into()))
+        .collect()
+}
+
+/// Colors are spread evenly over the gradient. Fractint allows a comment
+/// after the three numbers of a line.
+fn parse_map(text: &str) -> Result<Palette, Box<dyn Error>> {
+    let mut colors = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().take(3).collect();
+        if words.is_empty() { continue }
+        let numbers = parse_numbers(&words.join(" "), index + 1)?;
+        if numbers.len() < 3 {
+            return Err(format!("line {}: expected three numbers R G B", index + 1).into());
+        }
+        colors.push([numbers[0], numbers[1], numbers[2]].map(|channel| channel / 255.0));
+    }
+    let last = colors.len().saturating_sub(1).max(1) as f32;
+    Ok(Palette::new(colors.into_iter().enumerate()
+        .map(|(index, color)| ColorStop { position: index as f32 / last, color })
+        .collect()))
+}
+
+fn write_map(palette: &Palette) -> String {
+    (0..256)
+        .map(|index| {
+            let color = palette.gradient(index as f32 / 255.0);
+            let [r, g, b] = color.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+            format!("{} {} {}\n", r, g, b)
+        })
+        .collect()
+}
+
+/// Every segment becomes stops at its ends and its middle point. Blending
+/// other than linear and HSV segments are approximated by these three colors.
+fn parse_ggr(text: &str) -> Result<Palette, Box<dyn Error>> {
+    let mut lines = text.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    match lines.next() {
+        Some((_, header)) if header.trim() == "GIMP Gradient" => (),
+        _ => return Err("expected 'GIMP Gradient' in the first line".into()),
+    }
+    let (count_index, mut count_line) = lines.next().ok_or("no segment count")?;
+    let mut count_number = count_index + 1;
+    if count_line.starts_with("Name:") {
+        let (index, line) = lines.next().ok_or("no segment count")?;
+        (count_line, count_number) = (line, index + 1);
+    }
+    let count: usize = count_line.trim().parse()
+        .map_err(|_| format!("line {}: expected the number of segments", count_number))?;
+
+    let mut stops: Vec<ColorStop> = vec![];
+    for _ in 0..count {
+        let (index, line) = lines.next().ok_or("fewer segments than declared")?;
+        let numbers = parse_numbers(line, index + 1)?;
+        if numbers.len() < 11 {
+            return Err(format!("line {}: expected at least 11 numbers per segment", index + 1).into());
+        }
+        let left = [numbers[3], numbers[4], numbers[5]];
+        let right = [numbers[7], numbers[8], numbers[9]];
+        let middle = [0, 1, 2].map(|i| (left[i] + right[i]) / 2.0);
+        for (position, color) in [(numbers[0], left), (numbers[1], middle), (numbers[2], right)] {
+            let position = position.clamp(0.0, 1.0);
+            // Сегменты стыкуются, одинаковые остановки на стыке не нужны
+            let same = stops.last()
+                .is_some_and(|last| last.position == position && last.color == color);
+            if !same { stops.push(ColorStop { position, color }) }
+        }
+    }
+    Ok(Palette::new(stops))
+}
+
+/// One linear RGB segment between every two neighbouring stops.
+fn write_ggr(palette: &Palette) -> String {
+    let first = palette.stops.first().map_or([0.0; 3], |stop| stop.color);
+    let last = palette.stops.last().map_or([0.0; 3], |stop| stop.color);
+    let points: Vec<(f32, [f32; 3])> = [(0.0, first)].into_iter()
+        .chain(palette.stops.iter().map(|stop| (stop.position, stop.color)))
+        .chain([(1.0, last)])
+        .collect();
+    let mut segments: Vec<String> = points.windows(2)
+        .filter(|pair| pair[1].0 > pair[0].0)
+        .map(|pair| {
+            let ((left, left_color), (right, right_color)) = (pair[0], pair[1]);
+            format!("{:.6} {:.6} {:.6} {:.6} {:.6} {:.6} 1.000000 {:.6} {:.6} {:.6} 1.000000 0 0",
+                left, (left + right) / 2.0, right, left_color[0], left_color[1], left_color[2],
+                right_color[0], right_color[1], right_color[2])
+        })
+        .collect();
+    if segments.is_empty() {
+        segments.push(format!("0.000000 0.500000 1.000000 {0:.6} {1:.6} {2:.6} 1.000000 \
+            {0:.6} {1:.6} {2:.6} 1.000000 0 0", first[0], first[1], first[2]));
+    }
+    format!("GIMP Gradient\nName: RVM palette\n{}\n{}\n", segments.len(), segments.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_gradients_match(name: &str, actual: &Palette, expected: &Palette, tolerance: f32) {
+        for step in 0..=100 {
+            let t = step as f32 / 100.0;
+            let (a, e) = (actual.gradient(t), expected.gradient(t));
+            assert!(a.iter().zip(e).all(|(a, e)| (a - e).abs() <= tolerance), 
+                "{} at {}: {:?} != {:?}", name, t, a, e);
+        }
+    }
+
+    #[test]
+    fn palette_files_round_trip() {
+        let palette = Palette { offset: 0.25, scale: 3.0, ..Palette::preset("ultra-fractal").unwrap() };
+        for extension in ["map", "ggr", "json"] {
+            let path = std::env::temp_dir().join(format!("rvm_palette.{}", extension));
+            save_palette(&path, &palette).unwrap();
+            let loaded = load_palette(&path).unwrap();
+            assert_gradients_match(extension, &loaded, &palette, 1.0 / 255.0);
+        }
+        let path = std::env::temp_dir().join("rvm_palette.json");
+        assert_eq!(load_palette(&path).unwrap(), palette, "json keeps everything");
+    }
+
+    #[test]
+    fn palette_file_formats() {
+        let map = "0 0 0 black\n255 0 0\n\n255 255 255 ; white\n";
+        let palette = PaletteFormat::Map.parse(map).unwrap();
+        assert_eq!(palette.stops.len(), 3);
+        assert_eq!(palette.stops[1], ColorStop { position: 0.5, color: [1.0, 0.0, 0.0] });
+
+        // Two segments from GIMP, the second one with the middle point moved
+        let ggr = "GIMP Gradient\nName: Test\n2\n\
+            0.0 0.25 0.5 0 0 0 1 1 0 0 1 0 0\n\
+            0.5 0.6 1.0 1 0 0 1 0 0 1 1 0 0 0 0\n";
+        let palette = PaletteFormat::Ggr.parse(ggr).unwrap();
+        assert_eq!(palette.gradient(0.25), [0.5, 0.0, 0.0]);
+        assert_eq!(palette.gradient(0.5), [1.0, 0.0, 0.0]);
+        assert_eq!(palette.gradient(0.6), [0.5, 0.0, 0.5]);
+        assert_eq!(palette.gradient(1.0), [0.0, 0.0, 1.0]);
+
+        assert!(PaletteFormat::Map.parse("0 0 zero").is_err());
+        assert!(PaletteFormat::Ggr.parse("GIMP Gradient\n2\n0 0.5 1 0 0 0 1 1 1 1 1 0 0").is_err());
+        assert!(PaletteFormat::Json.parse(r#"{ "stops": [] }"#).is_err());
+        assert_eq!(PaletteFormat::from_path(Path::new("fire.MAP")), Some(PaletteFormat::Map));
+    }
+}
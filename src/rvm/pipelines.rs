@@ -0,0 +1,223 @@
+use super::setup::create_pipeline;
+use super::shader_module;
+use super::precision::Precision;
+use super::formula::Formula;
+use super::view_position::ViewPosition;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use vulkano::device::Device;
+use vulkano::pipeline::{ Pipeline, ComputePipeline };
+use vulkano::descriptor_set::layout::DescriptorSetLayout;
+use vulkano::shader::{ 
+    ShaderModule, ShaderCreationError, SpecializationConstants, SpecializationMapEntry 
+};
+
+/// Largest side of the square workgroups of `compute.glsl`, a cap rather
+/// than a device limit: the halo of adaptive supersampling in shared memory
+/// grows with its square. The tiles of the window and the bins of the
+/// histogram are multiples of it.
+pub const MAX_WORKGROUP_SIDE: u32 = 16;
+
+/// Side of the workgroups a device with these limits runs: `MAX_WORKGROUP_SIDE`,
+/// halved until the device allows it.
+pub fn workgroup_side(max_size: [u32; 3], max_invocations: u32) -> u32 {
+    let mut side = MAX_WORKGROUP_SIDE;
+    while side > 1 && (side > max_size[0] || side > max_size[1] || side * side > max_invocations) {
+        side /= 2;
+    }
+    side
+}
+
+/// Specialization constants of `compute.glsl`, the same for every variant:
+/// Vulkan skips the entries of constants a variant does not declare.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct KernelConstants {
+    formula: u32,
+    histogram_stage: u32,
+    workgroup_width: u32,
+    workgroup_height: u32,
+}
+
+unsafe impl SpecializationConstants for KernelConstants {
+    fn descriptors() -> &'static [SpecializationMapEntry] {
+        static DESCRIPTORS: [SpecializationMapEntry; 4] = [
+            SpecializationMapEntry { constant_id: 0, offset: 0, size: 4 },
+            SpecializationMapEntry { constant_id: 1, offset: 4, size: 4 },
+            SpecializationMapEntry { constant_id: 2, offset: 8, size: 4 },
+            SpecializationMapEntry { constant_id: 3, offset: 12, size: 4 },
+        ];
+        &DESCRIPTORS
+    }
+}
+
+/// Every precision variant of the kernel for one formula. All but `Perturbation`
+/// share one descriptor set layout, so the same descriptor sets can be bound to
+/// any of them, whatever the formula. `Perturbation` additionally takes the
+/// reference orbit and the series approximation at bindings 3 and 4.
+/// The two stages of the histogram pass share a layout of their own,
+/// the lighting pass has another one. All of them run workgroups of the
+/// side `workgroup_side` picks for the device.
+pub struct ComputePipelines {
+    formula: Formula,
+    workgroup_side: u32,
+    /// Text of `compute.glsl` compiled at runtime instead of the built-in kernels.
+    source: Option<String>,
+    single: Arc<ComputePipeline>,
+    double: Option<Arc<ComputePipeline>>,
+    double_single: Arc<ComputePipeline>,
+    perturbation: Option<Arc<ComputePipeline>>,
+    histogram_cumulative: Arc<ComputePipeline>,
+    histogram_color: Arc<ComputePipeline>,
+    lighting: Arc<ComputePipeline>,
+}
+
+type LoadShader = fn(Arc<Device>) -> Result<Arc<ShaderModule>, ShaderCreationError>;
+
+impl ComputePipelines {
+    pub fn new(device: Arc<Device>, formula: &Formula) -> Result<Self, Box<dyn Error>> {
+        Self::create(device, formula, None)
+    }
+
+    /// Same kernels compiled at runtime from `source`, an edited `compute.glsl`.
+    pub fn from_source(device: Arc<Device>, formula: &Formula, source: String) 
+    -> Result<Self, Box<dyn Error>> {
+        Self::create(device, formula, Some(source))
+    }
+
+    /// Pipelines of another formula from the same shader source.
+    pub fn with_formula(&self, device: Arc<Device>, formula: &Formula) 
+    -> Result<Self, Box<dyn Error>> {
+        Self::create(device, formula, self.source.clone())
+    }
+
+    fn create(device: Arc<Device>, formula: &Formula, source: Option<String>) 
+    -> Result<Self, Box<dyn Error>> {
+        let limits = device.physical_device().properties();
+        let workgroup_side = workgroup_side(limits.max_compute_work_group_size, 
+            limits.max_compute_work_group_invocations);
+        let constants = |formula: u32, histogram_stage: u32| KernelConstants { 
+            formula, 
+            histogram_stage, 
+            workgroup_width: workgroup_side, 
+            workgroup_height: workgroup_side,
+        };
+        let load = |defines: &[(&str, &str)], load_built_in: LoadShader| match &source {
+            Some(source) => shader_module::compile(device.clone(), source, defines),
+            None => Ok(load_built_in(device.clone())?),
+        };
+        // Проходы после ядра от формулы не зависят
+        let histogram_shader = load(&[("HISTOGRAM_PASS", "1")], shader_module::cs_histogram::load)?;
+        let histogram_stage = |stage| create_pipeline(device.clone(), histogram_shader.clone(),
+            &constants(0, stage));
+        let histogram_cumulative = histogram_stage(0)?;
+        let histogram_color = histogram_stage(1)?;
+        let lighting = create_pipeline(device.clone(), 
+            load(&[("LIGHTING_PASS", "1")], shader_module::cs_lighting::load)?, &constants(0, 0))?;
+
+        if let Formula::User(user_formula) = formula {
+            // Пользовательская формула есть только во float, остальные точности сводятся к ней
+            let shader = shader_module::compile(device.clone(), 
+                source.as_deref().unwrap_or(shader_module::SOURCE),
+                &[("USER_FORMULA", &user_formula.to_glsl())])?;
+            let single = create_pipeline(device.clone(), shader, &constants(0, 0))?;
+            return Ok(ComputePipelines { formula: formula.clone(), workgroup_side, source, 
+                single: single.clone(), double: None, double_single: single, perturbation: None, 
+                histogram_cumulative, histogram_color, lighting });
+        }
+
+        let kernel_constants = constants(formula.id(), 0);
+        let single = create_pipeline(device.clone(), 
+            load(&[], shader_module::cs::load)?, &kernel_constants)?;
+        let shader_float64 = device.enabled_features().shader_float64;
+        let double = if shader_float64 {
+            Some(create_pipeline(device.clone(), 
+                load(&[("PRECISION_DOUBLE", "1")], shader_module::cs_double::load)?,
+                &kernel_constants)?)
+        }
+        else { None };
+        let perturbation = if shader_float64 && formula.supports_perturbation() {
+            Some(create_pipeline(device.clone(), 
+                load(&[("PRECISION_PERTURBATION", "1")], shader_module::cs_perturbation::load)?,
+                &kernel_constants)?)
+        }
+        else { None };
+        let double_single = create_pipeline(device.clone(), 
+            load(&[("PRECISION_DOUBLE_SINGLE", "1")], shader_module::cs_double_single::load)?,
+            &kernel_constants)?;
+
+        Ok(ComputePipelines { formula: formula.clone(), workgroup_side, source, single, double, 
+            double_single, perturbation, histogram_cumulative, histogram_color, lighting })
+    }
+
+    pub fn formula(&self) -> &Formula {
+        &self.formula
+    }
+
+    /// Side of the workgroups, dispatches cover the image with
+    /// `side.div_ceil(workgroup_side)` of them.
+    pub fn workgroup_side(&self) -> u32 {
+        self.workgroup_side
+    }
+
+    pub fn supports_double(&self) -> bool {
+        self.double.is_some()
+    }
+
+    pub fn precision_for(&self, view_position: &ViewPosition) -> Precision {
+        Precision::for_view(view_position, self.supports_double())
+    }
+
+    /// Variant that will actually run: formulas without perturbation fall back
+    /// to `Double`, user formulas to `Single`, and without `shader_float64`
+    /// `Double` falls back to `DoubleSingle`.
+    pub fn resolve(&self, precision: Precision) -> Precision {
+        match precision.supported_by(&self.formula) {
+            Precision::Double | Precision::Perturbation if !self.supports_double() =>
+                Precision::DoubleSingle,
+            precision => precision,
+        }
+    }
+
+    pub fn get(&self, precision: Precision) -> Arc<ComputePipeline> {
+        match (self.resolve(precision), &self.double, &self.perturbation) {
+            (Precision::Double, Some(double), _) => double.clone(),
+            (Precision::Perturbation, _, Some(perturbation)) => perturbation.clone(),
+            (Precision::DoubleSingle, _, _) => self.double_single.clone(),
+            _ => self.single.clone(),
+        }
+    }
+
+    /// Cumulative and color stages of the histogram pass.
+    pub fn histogram(&self) -> (Arc<ComputePipeline>, Arc<ComputePipeline>) {
+        (self.histogram_cumulative.clone(), self.histogram_color.clone())
+    }
+
+    pub fn lighting(&self) -> Arc<ComputePipeline> {
+        self.lighting.clone()
+    }
+
+    pub fn descriptor_set_layout(&self, precision: Precision) -> Arc<DescriptorSetLayout> {
+        self.get(precision).layout().set_layouts().first()
+            .expect("DescriptorSetLayout not found by index 0")
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rvm::refinement::TILE_SIZE;
+
+    #[test]
+    fn workgroup_side_fits_the_limits() {
+        assert_eq!(workgroup_side([1024, 1024, 64], 1024), MAX_WORKGROUP_SIDE);
+        // The minimum limits of Vulkan allow 128 invocations
+        assert_eq!(workgroup_side([128, 128, 64], 128), 8);
+        assert_eq!(workgroup_side([4, 1024, 64], 1024), 4);
+        assert_eq!(workgroup_side([1, 1, 1], 1), 1);
+        assert!(TILE_SIZE.is_multiple_of(MAX_WORKGROUP_SIDE));
+    }
+}
@@ -0,0 +1,75 @@
+use super::view_position::ViewPosition;
+use super::formula::Formula;
+
+/// Beyond this zoom single precision floats can't tell neighbouring pixels
+/// apart and the image breaks into blocks.
+pub const SINGLE_PRECISION_MAX_ZOOM: f64 = 75.0;
+
+/// Same limit for `double`, around 1e13 magnification.
+pub const DOUBLE_PRECISION_MAX_ZOOM: f64 = 300.0;
+
+/// Number type the kernel iterates in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Precision {
+    Single,
+    /// Native `double`, needs the `shader_float64` feature.
+    Double,
+    /// `double` emulated with a pair of floats, for devices without `shader_float64`.
+    DoubleSingle,
+    /// Per-pixel `double` deltas against a reference orbit computed on the CPU
+    /// in arbitrary precision, for zooms no native type can reach.
+    /// Needs `shader_float64` too, and works for `Formula::Mandelbrot` only.
+    Perturbation,
+}
+
+impl Precision {
+    pub const ALL: [Precision; 4] = [Precision::Single, Precision::Double,
+        Precision::DoubleSingle, Precision::Perturbation];
+
+    pub fn for_view(view_position: &ViewPosition, supports_double: bool) -> Self {
+        let precision = if view_position.zoom <= SINGLE_PRECISION_MAX_ZOOM { Precision::Single }
+            else if !supports_double { Precision::DoubleSingle }
+            else if view_position.zoom <= DOUBLE_PRECISION_MAX_ZOOM { Precision::Double }
+            else { Precision::Perturbation };
+        precision.supported_by(&view_position.formula)
+    }
+
+    /// Formulas without perturbation stay in `Double`, however deep the view,
+    /// and user formulas have only `Single`.
+    pub fn supported_by(self, formula: &Formula) -> Self {
+        match formula {
+            Formula::User(_) => Precision::Single,
+            _ if self == Precision::Perturbation && !formula.supports_perturbation() =>
+                Precision::Double,
+            _ => self,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Precision::Single => "single",
+            Precision::Double => "double",
+            Precision::DoubleSingle => "double-single",
+            Precision::Perturbation => "perturbation",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(zoom: f64) -> ViewPosition { ViewPosition { zoom, ..ViewPosition::new() } }
+
+    #[test]
+    fn automatic_precision() {
+        assert_eq!(Precision::for_view(&view(1.0), true), Precision::Single);
+        assert_eq!(Precision::for_view(&view(180.0), true), Precision::Double);
+        assert_eq!(Precision::for_view(&view(180.0), false), Precision::DoubleSingle);
+        assert_eq!(Precision::for_view(&view(1151.0), true), Precision::Perturbation);
+        assert_eq!(Precision::for_view(&view(1151.0), false), Precision::DoubleSingle);
+
+        let deep_burning_ship = ViewPosition { zoom: 1151.0, ..ViewPosition::formula(Formula::BurningShip) };
+        assert_eq!(Precision::for_view(&deep_burning_ship, true), Precision::Double);
+    }
+}
@@ -0,0 +1,74 @@
+use super::fixed_point::FixedPoint;
+use super::view_position::ViewPosition;
+
+use std::f64::consts::LN_2;
+
+/// Orbit of the view centre, `Z(n+1) = Z(n)^2 + C` starting at `Z(0) = 0`
+/// (or at `Z(0)` = centre with `C` = Julia constant in the Julia mode),
+/// iterated in arbitrary precision and rounded to `f64` point by point.
+/// Pixels of the perturbation kernel iterate only their small difference
+/// from it, which `double` holds well at any zoom.
+/// Ends at `quality` iterations or at the first point past the bailout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceOrbit {
+    pub points: Vec<[f64; 2]>,
+    pub julia: bool,
+    start: [FixedPoint; 2],
+    c: [FixedPoint; 2],
+    quality: u32,
+}
+
+impl ReferenceOrbit {
+    pub fn new(view_position: &ViewPosition) -> Self {
+        let (start, c) = orbit_start(view_position);
+        let bits = precision_bits(view_position.zoom);
+
+        let mut points = Vec::with_capacity(view_position.quality as usize + 1);
+        points.push([start[0].to_f64(), start[1].to_f64()]);
+        let [mut z_x, mut z_y] = start.clone();
+        for _ in 0..view_position.quality {
+            let z_x2 = z_x.mul(&z_x, bits);
+            let z_y2 = z_y.mul(&z_y, bits);
+            let z_xy = z_x.mul(&z_y, bits);
+            z_x = &(&z_x2 - &z_y2) + &c[0];
+            z_y = &z_xy.double() + &c[1];
+
+            let point = [z_x.to_f64(), z_y.to_f64()];
+            points.push(point);
+            if (point[0] * point[0] + point[1] * point[1]).sqrt() > 4.0 { break }
+        }
+
+        ReferenceOrbit { points, julia: view_position.julia, start, c, 
+            quality: view_position.quality }
+    }
+
+    /// Whether the orbit still belongs to the view, so it needn't be recomputed.
+    pub fn is_valid_for(&self, view_position: &ViewPosition) -> bool {
+        let (start, c) = orbit_start(view_position);
+        self.quality == view_position.quality && self.julia == view_position.julia
+            && start == self.start && c == self.c
+    }
+
+    /// `C` of the orbit rounded to `f64`: the view centre, or the Julia constant.
+    pub fn constant(&self) -> [f64; 2] {
+        [self.c[0].to_f64(), self.c[1].to_f64()]
+    }
+}
+
+/// `Z(0)` and `C` of the orbit, rounded to the precision the zoom needs.
+fn orbit_start(view_position: &ViewPosition) -> ([FixedPoint; 2], [FixedPoint; 2]) {
+    let bits = precision_bits(view_position.zoom);
+    let center = view_position.precise_center().map(|part| part.with_bits(bits));
+    if view_position.julia {
+        let julia_c = view_position.julia_c.map(|part| FixedPoint::from_f64(part).with_bits(bits));
+        (center, julia_c)
+    }
+    else { ([FixedPoint::zero().with_bits(bits), FixedPoint::zero().with_bits(bits)], center) }
+}
+
+/// Bits the centre needs to tell pixels apart, with a margin for the image size.
+/// Rounded up so that small zoom steps reuse the orbit.
+fn precision_bits(zoom: f64) -> u32 {
+    let magnification_bits = (zoom / 10.0 / LN_2).max(0.0).ceil() as u32;
+    (magnification_bits + 64).next_multiple_of(32)
+}
@@ -0,0 +1,312 @@
+use super::view_position::GpuViewPosition;
+use super::coloring::Coloring;
+
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use bytemuck::{ Pod, Zeroable };
+
+/// Side of the blocks one pixel of a refinement pass fills, coarse to fine.
+pub const BLOCK_SIZES: [u32; 4] = [8, 4, 2, 1];
+
+/// The preview iterates `quality` divided by this.
+pub const PREVIEW_QUALITY_DIVISOR: u32 = 4;
+
+/// Side of a tile in cells of a pass, a multiple of `MAX_WORKGROUP_SIDE`
+/// and so of the side of the workgroups on any device.
+pub const TILE_SIZE: u32 = 64;
+
+/// Time the kernel may take in a frame unless the viewer is told otherwise.
+pub const DEFAULT_FRAME_BUDGET: Duration = Duration::from_millis(30);
+
+/// Frame budgets in milliseconds the viewer accepts.
+pub const FRAME_BUDGET_RANGE: RangeInclusive<u64> = 5..=200;
+
+/// Tiles the first frame of the window takes, later ones fit the budget.
+const FIRST_TILES_PER_FRAME: usize = 4;
+
+/// Layout of the `Tile` push constant of `compute.glsl`: where the
+/// dispatch starts, in cells of the pass. Zero for whole image dispatches.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Zeroable, Pod)]
+pub struct GpuTile {
+    pub origin: [u32; 2],
+}
+
+/// What the next frame of the window computes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RefinementPass {
+    /// The coarsest blocks with a fraction of the iterations, while the view
+    /// keeps changing.
+    Preview,
+    /// Blocks of `BLOCK_SIZES[level]` with all the iterations. With
+    /// `keeps_previous` the pixels the twice coarser level computed stay.
+    Level { level: usize, keeps_previous: bool },
+    /// The image is complete.
+    Done,
+}
+
+/// What one frame of a pass renders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameTiles {
+    pub block_size: u32,
+    /// Size of the pass in cells, one cell fills a block of pixels.
+    pub cells: [u32; 2],
+    /// Origins of the tiles in cells.
+    pub tiles: Vec<[u32; 2]>,
+    /// The first frame of the pass, which clears the histogram.
+    pub is_first: bool,
+    /// The last frame of the pass, after which the passes over the whole
+    /// frame run.
+    pub is_last: bool,
+}
+
+/// Progressive rendering of the window: a cheap preview while the view is
+/// moving, then ever finer passes once it stops, accumulated in the
+/// storage image. Every pass is split into tiles, rendered from the centre
+/// outward over as many frames as the frame budget needs. The preview and
+/// the recolored image start over every frame while the view moves or the
+/// palette cycles, so they are rendered whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refinement {
+    pass: RefinementPass,
+    /// Tiles of the pass left to render, the next one last.
+    tiles: Vec<[u32; 2]>,
+    is_started: bool,
+    /// All the tiles of the pass go in one frame.
+    is_whole: bool,
+    tiles_per_frame: usize,
+}
+
+impl Default for Refinement {
+    fn default() -> Self {
+        Refinement { 
+            pass: RefinementPass::Preview, 
+            tiles: vec![], 
+            is_started: false, 
+            is_whole: true,
+            tiles_per_frame: FIRST_TILES_PER_FRAME,
+        }
+    }
+}
+
+impl Refinement {
+    pub fn pass(&self) -> RefinementPass {
+        self.pass
+    }
+
+    /// Block side of the current pass, `None` when there is nothing to compute.
+    pub fn block_size(&self) -> Option<u32> {
+        match self.pass {
+            RefinementPass::Preview => Some(BLOCK_SIZES[0]),
+            RefinementPass::Level { level, .. } => Some(BLOCK_SIZES[level]),
+            RefinementPass::Done => None,
+        }
+    }
+
+    /// The view changed, the image has to start over. The tiles of the new
+    /// view cost differently, so the budget is fitted anew.
+    pub fn restart(&mut self) {
+        self.start_pass(RefinementPass::Preview, true);
+        self.tiles_per_frame = FIRST_TILES_PER_FRAME;
+    }
+
+    fn start_pass(&mut self, pass: RefinementPass, is_whole: bool) {
+        self.pass = pass;
+        self.tiles.clear();
+        self.is_started = false;
+        self.is_whole = is_whole;
+    }
+
+    /// Only the colors changed: the same view costs the same, so it is
+    /// redrawn in one pass at full resolution.
+    pub fn recolor(&mut self) {
+        if self.pass == RefinementPass::Preview { return }
+        self.start_pass(RefinementPass::Level { level: BLOCK_SIZES.len() - 1, keeps_previous: false }, true);
+    }
+
+    /// Tiles of the next frame of the window of `extent` pixels, `None` if
+    /// the image is complete.
+    pub fn take_tiles(&mut self, extent: [u32; 2]) -> Option<FrameTiles> {
+        let block_size = self.block_size()?;
+        let cells = extent.map(|side| side.div_ceil(block_size));
+        let is_first = !self.is_started;
+        if is_first {
+            self.tiles = tiles_from_centre(cells);
+            self.tiles.reverse();
+            self.is_started = true;
+        }
+        let count = if self.is_whole { self.tiles.len() } else { self.tiles_per_frame.min(self.tiles.len()) };
+        let mut tiles = self.tiles.split_off(self.tiles.len() - count);
+        tiles.reverse();
+        Some(FrameTiles { block_size, cells, tiles, is_first, is_last: self.tiles.is_empty() })
+    }
+
+    /// Fits the tiles of the next frames into `budget`, from how long the
+    /// last frame took with `rendered` tiles. Whole passes don't count.
+    pub fn fit_budget(&mut self, rendered: usize, frame_time: Duration, budget: Duration) {
+        if rendered == 0 || self.is_whole { return }
+        let tile_time = frame_time.as_secs_f64() / rendered as f64;
+        let fitting = (budget.as_secs_f64() / tile_time.max(1e-6)) as usize;
+        // Плитки у центра обычно дороже следующих, поэтому рост не больше чем вдвое
+        self.tiles_per_frame = fitting.clamp(1, 2 * self.tiles_per_frame);
+    }
+
+    /// A frame went by with the view unchanged: on to the next finer pass
+    /// once all the tiles of this one are rendered.
+    pub fn advance(&mut self) {
+        if !self.tiles.is_empty() { return }
+        let pass = match self.pass {
+            RefinementPass::Preview => RefinementPass::Level { level: 0, keeps_previous: false },
+            RefinementPass::Level { level, .. } if level + 1 < BLOCK_SIZES.len() =>
+                RefinementPass::Level { level: level + 1, keeps_previous: true },
+            _ => RefinementPass::Done,
+        };
+        self.start_pass(pass, false);
+    }
+
+    /// The kernel parameters of the current pass on top of `view_position`.
+    /// The histogram and the lighting go over the whole frame again after
+    /// the kernel, so with them every pass computes all of its pixels.
+    pub fn apply(&self, view_position: GpuViewPosition) -> GpuViewPosition {
+        let whole_frame = view_position.coloring == Coloring::Histogram.id()
+            || view_position.lighting != 0;
+        match self.pass {
+            RefinementPass::Preview => GpuViewPosition {
+                quality: view_position.quality.div_ceil(PREVIEW_QUALITY_DIVISOR),
+                block_size: BLOCK_SIZES[0],
+                ..view_position
+            },
+            RefinementPass::Level { level, keeps_previous } => GpuViewPosition {
+                block_size: BLOCK_SIZES[level],
+                keep_previous: (keeps_previous && !whole_frame) as u32,
+                ..view_position
+            },
+            RefinementPass::Done => view_position,
+        }
+    }
+}
+
+/// Origins of the tiles covering `cells`, the ones nearer to the centre first.
+pub fn tiles_from_centre(cells: [u32; 2]) -> Vec<[u32; 2]> {
+    let counts = cells.map(|side| side.div_ceil(TILE_SIZE));
+    let mut tiles: Vec<[u32; 2]> = (0..counts[1])
+        .flat_map(|y| (0..counts[0]).map(move |x| [x * TILE_SIZE, y * TILE_SIZE]))
+        .collect();
+    let distance = |tile: &[u32; 2]| {
+        let [x, y] = [0, 1].map(|i| {
+            let middle = tile[i] as f64 + (cells[i] - tile[i]).min(TILE_SIZE) as f64 / 2.0;
+            middle - cells[i] as f64 / 2.0
+        });
+        x * x + y * y
+    };
+    tiles.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rvm::view_position::ViewPosition;
+
+    /// The window previews with fewer iterations while the view moves, then
+    /// refines from coarse to fine blocks keeping the pixels of the previous
+    /// level, unless a pass over the whole frame follows the kernel.
+    #[test]
+    fn refinement_passes() {
+        let passes = |view_position: GpuViewPosition| {
+            let mut refinement = Refinement::default();
+            let mut passes = vec![];
+            while let Some(block_size) = refinement.block_size() {
+                let pass = refinement.apply(view_position);
+                assert_eq!(pass.block_size, block_size);
+                passes.push((block_size, pass.quality, pass.keep_previous));
+                refinement.advance();
+            }
+            passes
+        };
+        let full_set = ViewPosition { quality: 100, ..ViewPosition::new() };
+        let view_position = GpuViewPosition::from(&full_set);
+        assert_eq!(passes(view_position), [(8, 25, 0), (8, 100, 0), (4, 100, 1), (2, 100, 1), (1, 100, 1)]);
+        let histogram = GpuViewPosition::from(&ViewPosition { coloring: Coloring::Histogram, ..full_set });
+        assert!(passes(histogram).iter().all(|(_, _, keep_previous)| *keep_previous == 0));
+
+        let mut refinement = Refinement::default();
+        refinement.recolor();
+        assert_eq!(refinement.pass(), RefinementPass::Preview, "the view is still moving");
+        refinement.advance();
+        refinement.recolor();
+        assert_eq!(refinement.apply(view_position).block_size, 1);
+        refinement.advance();
+        assert_eq!(refinement.pass(), RefinementPass::Done);
+        refinement.restart();
+        assert_eq!(refinement.pass(), RefinementPass::Preview);
+    }
+
+    /// Tiles cover a pass once, starting from the one under the centre, and a
+    /// pass spans as many frames as its tiles need.
+    #[test]
+    fn refinement_tiles() {
+        let cells = [3 * TILE_SIZE + 10, 2 * TILE_SIZE];
+        let tiles = tiles_from_centre(cells);
+        assert_eq!(tiles.len(), 4 * 2);
+        assert!(tiles.iter().all(|tile| tiles.iter().filter(|other| *other == tile).count() == 1));
+        assert!(tiles[..2].contains(&[TILE_SIZE, TILE_SIZE]) && tiles[..2].contains(&[TILE_SIZE, 0]), 
+            "{:?}", tiles);
+
+        let mut refinement = Refinement::default();
+        refinement.advance();
+        let extent = cells.map(|side| side * 8);
+        let mut frames = vec![];
+        while refinement.pass() == (RefinementPass::Level { level: 0, keeps_previous: false }) {
+            let frame = refinement.take_tiles(extent).unwrap();
+            assert_eq!((frame.block_size, frame.cells), (8, cells));
+            // Every tile takes a third of the budget
+            refinement.fit_budget(frame.tiles.len(), 
+                Duration::from_millis(10) * frame.tiles.len() as u32, Duration::from_millis(30));
+            frames.push(frame);
+            refinement.advance();
+        }
+        let rendered: Vec<[u32; 2]> = frames.iter().flat_map(|frame| frame.tiles.clone()).collect();
+        assert_eq!(rendered, tiles);
+        assert_eq!(frames.iter().map(|frame| frame.tiles.len()).collect::<Vec<_>>(), [4, 3, 1]);
+        assert!(frames[0].is_first && frames[1..].iter().all(|frame| !frame.is_first));
+        assert!(frames.last().unwrap().is_last 
+            && frames[..frames.len() - 1].iter().all(|frame| !frame.is_last));
+    }
+
+    /// Colors changing every frame, as the palette cycling does, still redraw
+    /// every tile, and so does the preview of a moving view. A new view fits
+    /// the budget anew.
+    #[test]
+    fn refinement_restarted_every_frame() {
+        let cells = [3 * TILE_SIZE + 10, 2 * TILE_SIZE];
+        let mut tiles = tiles_from_centre(cells);
+        tiles.sort();
+        let whole = |frame: FrameTiles| {
+            let mut rendered = frame.tiles.clone();
+            rendered.sort();
+            rendered == tiles && frame.is_first && frame.is_last
+        };
+
+        let mut refinement = Refinement::default();
+        while refinement.pass() != RefinementPass::Done {
+            refinement.take_tiles(cells);
+            refinement.fit_budget(1, Duration::from_millis(1), Duration::from_millis(30));
+            refinement.advance();
+        }
+        for _ in 0..3 {
+            refinement.recolor();
+            assert!(whole(refinement.take_tiles(cells).unwrap()));
+            refinement.fit_budget(tiles.len(), Duration::from_secs(1), Duration::from_millis(30));
+            refinement.advance();
+            assert_eq!(refinement.pass(), RefinementPass::Done);
+        }
+        for _ in 0..3 {
+            refinement.restart();
+            assert!(whole(refinement.take_tiles(cells.map(|side| side * 8)).unwrap()));
+        }
+        refinement.advance();
+        assert_eq!(refinement.take_tiles(cells.map(|side| side * 8)).unwrap().tiles.len(), 4);
+    }
+}
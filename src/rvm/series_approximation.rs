@@ -0,0 +1,145 @@
+use super::reference_orbit::ReferenceOrbit;
+use super::view_position::ViewPosition;
+use super::coloring::Coloring;
+
+use bytemuck::{ Pod, Zeroable };
+
+/// Largest allowed ratio of the cubic and the first dropped term to the linear one.
+/// Past it the series would move pixels by a visible part of their size.
+const SERIES_TOLERANCE: f64 = 1e-6;
+
+/// Deep views spend most of the iterations where every pixel still follows
+/// the reference orbit closely. There `dz(n) = A(n) dc + B(n) dc^2 + C(n) dc^3`,
+/// with coefficients shared by the whole screen, so each pixel can start
+/// right at iteration `skipped` instead of zero.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct SeriesApproximation {
+    pub coefficients: [[f64; 2]; 3],
+    pub skipped: u32,
+}
+
+impl SeriesApproximation {
+    /// `radius` is the largest `|dc|` on the screen, the series has to hold
+    /// for every pixel.
+    pub fn new(reference_orbit: &ReferenceOrbit, radius: f64) -> Self {
+        let points = &reference_orbit.points;
+        let step = if reference_orbit.julia { [0.0, 0.0] } else { [1.0, 0.0] };
+        let mut result = SeriesApproximation::start(reference_orbit);
+        // Член dc^4 не хранится, он нужен только для оценки ошибки: у Жюлиа
+        // с центром в критической точке A = C = 0, и решает только он
+        let mut dropped = [0.0, 0.0];
+        // Пиксель продолжает с Z(skipped) и берёт следующую точку орбиты
+        for (n, point) in points.iter().enumerate().take(points.len().saturating_sub(2)) {
+            let z2 = [2.0 * point[0], 2.0 * point[1]];
+            let [a, b, c] = result.coefficients;
+            let next_a = add(mul(z2, a), step);
+            let next_b = add(mul(z2, b), mul(a, a));
+            let next_c = add(mul(z2, c), mul([2.0 * a[0], 2.0 * a[1]], b));
+            let next_dropped = add(mul(z2, dropped), add(mul([2.0 * a[0], 2.0 * a[1]], c), mul(b, b)));
+
+            let error_term = (norm(next_c) * radius.powi(3))
+                .max(norm(next_dropped) * radius.powi(4));
+            let linear_term = norm(next_a) * radius;
+            if !error_term.is_finite() || error_term > SERIES_TOLERANCE * linear_term { break }
+            // На мелком зуме пиксели могут уйти за границу раньше опорной точки
+            let delta_bound = linear_term + norm(next_b) * radius * radius + error_term;
+            if norm(points[n + 1]) + delta_bound > 4.0 { break }
+
+            result.coefficients = [next_a, next_b, next_c];
+            dropped = next_dropped;
+            result.skipped = n as u32 + 1;
+        }
+        result
+    }
+
+    /// Series that skips nothing: every pixel starts at iteration zero.
+    pub fn start(reference_orbit: &ReferenceOrbit) -> Self {
+        // У Жюлиа dz(0) = dc, у Мандельброта dz(0) = 0, а dc прибавляется на каждом шаге
+        let initial_a = if reference_orbit.julia { [1.0, 0.0] } else { [0.0, 0.0] };
+        SeriesApproximation {
+            coefficients: [initial_a, [0.0, 0.0], [0.0, 0.0]],
+            skipped: 0,
+        }
+    }
+
+    pub fn for_screen(reference_orbit: &ReferenceOrbit, view_position: &ViewPosition, 
+        width: u32, height: u32) -> Self {
+        // Ловушке нужна вся орбита пикселя, пропущенные итерации она бы не увидела
+        if view_position.coloring == Coloring::OrbitTrap {
+            return SeriesApproximation::start(reference_orbit);
+        }
+        // Дальше всего от центра углы экрана
+        let corner = [width as f64 / height as f64, 1.0];
+        let radius = (corner[0] * corner[0] + corner[1] * corner[1]).sqrt()
+            / view_position.magnification();
+        SeriesApproximation::new(reference_orbit, radius)
+    }
+
+    /// Pixel offset from the reference orbit at iteration `skipped`.
+    pub fn delta(&self, dc: [f64; 2]) -> [f64; 2] {
+        let [a, b, c] = self.coefficients;
+        mul(add(mul(add(mul(c, dc), b), dc), a), dc)
+    }
+}
+
+/// Layout of the `SeriesApproximation` storage buffer in `compute.glsl`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Zeroable, Pod)]
+pub struct GpuSeriesApproximation {
+    pub coefficients: [[f64; 2]; 3],
+    pub skipped: u32,
+    pub padding: [u32; 3],
+}
+
+impl From<&SeriesApproximation> for GpuSeriesApproximation {
+    fn from(series: &SeriesApproximation) -> Self {
+        GpuSeriesApproximation {
+            coefficients: series.coefficients,
+            skipped: series.skipped,
+            padding: [0; 3],
+        }
+    }
+}
+
+fn add(a: [f64; 2], b: [f64; 2]) -> [f64; 2] {
+    [a[0] + b[0], a[1] + b[1]]
+}
+
+fn mul(a: [f64; 2], b: [f64; 2]) -> [f64; 2] {
+    [a[0] * b[0] - a[1] * b[1], a[0] * b[1] + a[1] * b[0]]
+}
+
+fn norm(a: [f64; 2]) -> f64 {
+    (a[0] * a[0] + a[1] * a[1]).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The series has to land where plain perturbation iterations do.
+    #[test]
+    fn series_approximation_skips_iterations() {
+        // Magnification 1e50 next to the Misiurewicz point i
+        let mut view_position = ViewPosition { zoom: 1151.0, quality: 200, ..ViewPosition::new() };
+        view_position.set_precise_center([
+            "0.00000000000000000000000000000000000000000000000000003".parse().unwrap(),
+            "1.00000000000000000000000000000000000000000000000000002".parse().unwrap(),
+        ]);
+        let (width, height) = (192, 144);
+        let reference_orbit = ReferenceOrbit::new(&view_position);
+        let series = SeriesApproximation::for_screen(&reference_orbit, &view_position, width, height);
+        assert!(series.skipped > view_position.quality / 4, "skipped {}", series.skipped);
+
+        let corner = [width as f64 / height as f64, 1.0];
+        let dc = corner.map(|part| part / view_position.magnification());
+        let mut dz = [0.0f64, 0.0f64];
+        for point in &reference_orbit.points[..series.skipped as usize] {
+            let a = [2.0 * point[0] + dz[0], 2.0 * point[1] + dz[1]];
+            dz = [a[0] * dz[0] - a[1] * dz[1] + dc[0], a[0] * dz[1] + a[1] * dz[0] + dc[1]];
+        }
+        let approximated = series.delta(dc);
+        let error = (approximated[0] - dz[0]).hypot(approximated[1] - dz[1]);
+        assert!(error <= 1e-5 * dz[0].hypot(dz[1]), "{:?} != {:?}", approximated, dz);
+    }
+}
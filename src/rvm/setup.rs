@@ -0,0 +1,140 @@
+use super::instance_init_info::InstanceInitInfo;
+use super::device_init_info::DeviceInitInfo;
+
+use std::error::Error;
+use std::sync::Arc;
+
+use vulkano::{ VulkanLibrary, VulkanError };
+use vulkano::instance::{ Instance, InstanceCreateInfo };
+use vulkano::device::physical::{ PhysicalDevice, PhysicalDeviceType };
+use vulkano::device::{ Device, DeviceCreateInfo, QueueCreateInfo, Queue };
+use vulkano::pipeline::ComputePipeline;
+use vulkano::shader::{ ShaderModule, SpecializationConstants };
+use vulkano::shader::spirv::SpirvError;
+
+pub fn create_vulkan_instance(library: Arc<VulkanLibrary>, init_info: InstanceInitInfo) 
+-> Result<Arc<Instance>, Box<dyn Error>> {
+    let supported_extensions = library.supported_extensions();
+    let enabled_extensions = init_info.confirm_extensions(supported_extensions)?;
+
+    let layers: Vec<_> = library.layer_properties()?
+        .filter(|l| l.name().contains("VK_LAYER_LUNARG_monitor"))
+        .collect();
+
+    let instance = Instance::new(
+        library,
+        InstanceCreateInfo {
+            enabled_extensions,
+            enabled_layers: layers.iter().map(|l| l.name().to_owned()).collect(),
+            ..InstanceCreateInfo::application_from_cargo_toml()
+        },
+    )?;
+    Ok(instance)
+}
+
+pub fn get_right_devices(instance: Arc<Instance>, init_info: &DeviceInitInfo) 
+-> Result<Vec<Arc<PhysicalDevice>>, VulkanError> {
+    let physical_devices: Vec<Arc<PhysicalDevice>> = instance
+        .enumerate_physical_devices()?.collect();
+
+    let priority_devices = sort_physical_devices_by_device_type(physical_devices);
+    let correct_devices = find_correct_physical_devices(&priority_devices, init_info);
+    if correct_devices.len() == 0 { Err(VulkanError::DeviceLost) }
+    else { Ok(correct_devices) }
+}
+
+pub fn sort_physical_devices_by_device_type(physical_devices: Vec<Arc<PhysicalDevice>>) 
+-> Vec<Arc<PhysicalDevice>> {
+    let mut priority_devices: Vec<Arc<PhysicalDevice>> = vec![];
+    for device in physical_devices {
+        match device.properties().device_type {
+            PhysicalDeviceType::DiscreteGpu => priority_devices.insert(0, device.clone()),
+            PhysicalDeviceType::IntegratedGpu => priority_devices.push(device.clone()),
+            _ => priority_devices.push(device.clone()),
+        }
+    }
+    priority_devices
+}
+
+pub fn find_correct_physical_devices(
+    physical_devices: &Vec<Arc<PhysicalDevice>>, 
+    init_info: &DeviceInitInfo) 
+-> Vec<Arc<PhysicalDevice>> {
+    let mut correct_devices: Vec<Arc<PhysicalDevice>> = vec![];
+    for device in physical_devices {
+        let supported_extensions = device.supported_extensions();
+        if let Err(_) = init_info.confirm_extensions(supported_extensions) {
+            continue;
+        }
+
+        let supported_features = device.supported_features();
+        if let Err(_) = init_info.confirm_features(supported_features) {
+            continue;
+        }
+        correct_devices.push(device.clone());
+    }
+    correct_devices
+}
+
+fn get_device_queue_create_infos(physical_device: Arc<PhysicalDevice>)
+-> Result<Vec<QueueCreateInfo>, Box<dyn Error>> {
+    let queue_family_properties = physical_device.queue_family_properties();
+
+    let mut queue_family_indices: Vec<u32> = vec![];
+    for (i, q) in queue_family_properties.iter().enumerate() {
+        if q.queue_flags.compute && q.queue_flags.transfer {
+            queue_family_indices.push(i as u32);
+        }
+    }
+    if queue_family_indices.len() < 1 {
+        return Err(Box::new(VulkanError::InitializationFailed));
+    }
+
+    let queue_create_infos = queue_family_indices.into_iter()
+    .map(|queue_family_index| {
+        QueueCreateInfo {
+            queue_family_index,
+            ..Default::default()
+        }
+    }).collect();
+
+    Ok(queue_create_infos)
+}
+
+pub fn create_device_connection(physical_device: Arc<PhysicalDevice>, init_info: &DeviceInitInfo)
+-> Result<(Arc<Device>, Vec<Arc<Queue>>), Box<dyn Error>> {
+    let supported_extensions = physical_device.supported_extensions();
+    let enabled_extensions = init_info.confirm_extensions(supported_extensions)?;
+
+    let supported_features = physical_device.supported_features();
+    let enabled_features = init_info.confirm_features(supported_features)?;
+
+    let queue_create_infos = get_device_queue_create_infos(physical_device.clone())?;
+
+    let (device, queues) = Device::new(
+        physical_device,
+        DeviceCreateInfo {
+            enabled_extensions,
+            enabled_features,
+            queue_create_infos,
+            ..Default::default()
+        }
+    )?;
+    Ok((device, queues.collect()))
+}
+
+pub fn create_pipeline<Css: SpecializationConstants>(device: Arc<Device>, 
+    shader: Arc<ShaderModule>, specialization_constants: &Css) 
+-> Result<Arc<ComputePipeline>, Box<dyn Error>> {
+    let entry_point = if let Some(ep) = shader.entry_point("main") { ep }
+    else { return Err(Box::new(SpirvError::InvalidHeader)) };
+    
+    let pipeline = ComputePipeline::new(
+        device.clone(),
+        entry_point,
+        specialization_constants,
+        None,     // Добавить кеш!!!
+        |_| {}
+    )?;
+    Ok(pipeline)
+}
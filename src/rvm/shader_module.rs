@@ -1,6 +1,76 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use vulkano::device::Device;
+use vulkano::shader::ShaderModule;
+
 pub mod cs {
     vulkano_shaders::shader!(
         ty: "compute", 
         path: "src/compute.glsl",
     );
-}
\ No newline at end of file
+}
+
+pub mod cs_double {
+    vulkano_shaders::shader!(
+        ty: "compute", 
+        path: "src/compute.glsl",
+        define: [("PRECISION_DOUBLE", "1")],
+    );
+}
+
+pub mod cs_double_single {
+    vulkano_shaders::shader!(
+        ty: "compute", 
+        path: "src/compute.glsl",
+        define: [("PRECISION_DOUBLE_SINGLE", "1")],
+    );
+}
+
+pub mod cs_perturbation {
+    vulkano_shaders::shader!(
+        ty: "compute", 
+        path: "src/compute.glsl",
+        define: [("PRECISION_PERTURBATION", "1")],
+    );
+}
+
+pub mod cs_histogram {
+    vulkano_shaders::shader!(
+        ty: "compute", 
+        path: "src/compute.glsl",
+        define: [("HISTOGRAM_PASS", "1")],
+    );
+}
+
+pub mod cs_lighting {
+    vulkano_shaders::shader!(
+        ty: "compute", 
+        path: "src/compute.glsl",
+        define: [("LIGHTING_PASS", "1")],
+    );
+}
+
+/// Text of `compute.glsl` the built-in kernels are compiled from.
+pub const SOURCE: &str = include_str!("../compute.glsl");
+
+/// Compiles `source`, a version of `compute.glsl`, with `defines` set.
+/// Compile errors come back as the log of `shaderc`.
+pub fn compile(device: Arc<Device>, source: &str, defines: &[(&str, &str)])
+-> Result<Arc<ShaderModule>, Box<dyn Error>> {
+    let compiler = shaderc::Compiler::new().ok_or("shaderc compiler creating error")?;
+    let mut options = shaderc::CompileOptions::new().ok_or("shaderc options creating error")?;
+    for (name, value) in defines {
+        options.add_macro_definition(name, Some(value));
+    }
+
+    let artifact = compiler.compile_into_spirv(
+        source,
+        shaderc::ShaderKind::Compute,
+        "compute.glsl",
+        "main",
+        Some(&options)
+    )?;
+    let shader = unsafe { ShaderModule::from_words(device, artifact.as_binary())? };
+    Ok(shader)
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::time::SystemTime;
+
+/// Watches a shader file on disk for the development mode of the viewer.
+/// Polled before every frame, which is cheap next to rendering it, and a
+/// few times a second while the window is idle; only an edit redraws it.
+pub struct ShaderWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ShaderWatcher {
+    /// The file as it is now counts as seen, only later edits are reported.
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_time(&path).ok();
+        ShaderWatcher { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// New text of the file if it was modified since the last call.
+    pub fn poll(&mut self) -> Option<io::Result<String>> {
+        let modified = match modified_time(&self.path) {
+            Ok(modified) => modified,
+            // Редакторы иногда удаляют файл перед записью, ждём его появления
+            Err(_) => return None,
+        };
+        if self.modified == Some(modified) { return None }
+
+        self.modified = Some(modified);
+        Some(fs::read_to_string(&self.path))
+    }
+}
+
+fn modified_time(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn shader_watcher_reports_edits() {
+        let path = std::env::temp_dir().join("rvm_watched.glsl");
+        fs::write(&path, "void main() {}").unwrap();
+        let mut watcher = ShaderWatcher::new(path.clone());
+        assert!(watcher.poll().is_none(), "the file as it was is not an edit");
+
+        fs::write(&path, "void main() { }").unwrap();
+        // Modification times may be as coarse as seconds, move it explicitly
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(watcher.poll().unwrap().unwrap(), "void main() { }");
+        assert!(watcher.poll().is_none());
+    }
+}
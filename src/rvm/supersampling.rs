@@ -0,0 +1,55 @@
+/// Most samples on a side of a pixel.
+pub const MAX_SAMPLES: u32 = 4;
+
+/// Where the samples of a pixel go, `PATTERN_*` of `compute.glsl`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SamplePattern {
+    /// Middles of the cells of a regular grid.
+    #[default]
+    Grid,
+    /// A point in every cell of the grid, shifted by a hash of the pixel:
+    /// no regular pattern left to alias with thin details.
+    Jittered,
+}
+
+impl SamplePattern {
+    pub const ALL: [SamplePattern; 2] = [SamplePattern::Grid, SamplePattern::Jittered];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplePattern::Grid => "grid",
+            SamplePattern::Jittered => "jittered",
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            SamplePattern::Grid => 0,
+            SamplePattern::Jittered => 1,
+        }
+    }
+}
+
+/// Anti-aliasing of the kernel: a pixel averages the colors of
+/// `samples x samples` points instead of taking one at its corner.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Supersampling {
+    /// Samples on a side of a pixel, 1 turns supersampling off.
+    pub samples: u32,
+    pub pattern: SamplePattern,
+    /// Take one sample first and the rest only where the neighbours differ
+    /// strongly, that is on the edges and in the thin details.
+    pub adaptive: bool,
+}
+
+impl Default for Supersampling {
+    fn default() -> Self {
+        Supersampling { samples: 1, pattern: SamplePattern::Grid, adaptive: false }
+    }
+}
+
+impl Supersampling {
+    pub fn is_enabled(&self) -> bool {
+        self.samples > 1
+    }
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Iteration formula typed by the user, like `z^3 + c*exp(z)`.
+/// Turned into GLSL for a kernel compiled at runtime, and evaluated directly
+/// by the CPU renderer. Works in single precision only: `double` in GLSL has
+/// neither exponents nor trigonometry.
+#[derive(Debug, Clone)]
+pub struct UserFormula {
+    source: String,
+    expression: Expression,
+}
+
+/// Names a formula may use besides numbers and `+ - * / ^`.
+pub const USER_FORMULA_NAMES: &str = "z, c, i, sin, cos, sinh, cosh, exp, log, sqrt, \
+    conj, abs (of both parts), re, im";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Number(f32),
+    ImaginaryUnit,
+    Z,
+    C,
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Function(Function, Box<Expression>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Operator { Add, Sub, Mul, Div, Pow }
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Function { Sin, Cos, Sinh, Cosh, Exp, Log, Sqrt, Conj, Abs, Re, Im }
+
+const FUNCTIONS: [(&str, Function); 11] = [
+    ("sin", Function::Sin), ("cos", Function::Cos), ("sinh", Function::Sinh),
+    ("cosh", Function::Cosh), ("exp", Function::Exp), ("log", Function::Log),
+    ("sqrt", Function::Sqrt), ("conj", Function::Conj), ("abs", Function::Abs),
+    ("re", Function::Re), ("im", Function::Im),
+];
+
+impl UserFormula {
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// GLSL expression over `vec2 z` and `vec2 c`, built on the helpers
+    /// `compute.glsl` defines together with `USER_FORMULA`.
+    pub fn to_glsl(&self) -> String {
+        self.expression.to_glsl()
+    }
+
+    /// One iteration on the CPU, the same operations as the generated GLSL.
+    pub fn step(&self, z: [f32; 2], c: [f32; 2]) -> [f32; 2] {
+        self.expression.evaluate(z, c)
+    }
+}
+
+/// Formulas are the same when they are written the same.
+impl PartialEq for UserFormula {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for UserFormula {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFormulaError {
+    /// Byte offset in the source where the error was found.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseFormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at character {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseFormulaError {}
+
+impl FromStr for UserFormula {
+    type Err = ParseFormulaError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let source = source.trim();
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, index: 0, end: source.len() };
+        let expression = parser.expression()?;
+        if let Some((position, token)) = parser.peek() {
+            return Err(ParseFormulaError {
+                position,
+                message: format!("unexpected '{}'", token),
+            });
+        }
+        Ok(UserFormula { source: String::from(source), expression })
+    }
+}
+
+impl Expression {
+    fn to_glsl(&self) -> String {
+        match self {
+            Expression::Number(value) => format!("vec2({:?}, 0.0)", value),
+            Expression::ImaginaryUnit => String::from("vec2(0.0, 1.0)"),
+            Expression::Z => String::from("z"),
+            Expression::C => String::from("c"),
+            Expression::Negate(inner) => format!("(-{})", inner.to_glsl()),
+            Expression::Binary(operator, left, right) => {
+                let (left, right_glsl) = (left.to_glsl(), right.to_glsl());
+                match operator {
+                    Operator::Add => format!("({} + {})", left, right_glsl),
+                    Operator::Sub => format!("({} - {})", left, right_glsl),
+                    Operator::Mul => format!("complex_mul({}, {})", left, right_glsl),
+                    Operator::Div => format!("complex_div({}, {})", left, right_glsl),
+                    // Вещественная степень идёт через complex_pow, как у Multibrot
+                    Operator::Pow => match **right {
+                        Expression::Number(power) => format!("complex_pow({}, {:?})", left, power),
+                        _ => format!("complex_pow_complex({}, {})", left, right_glsl),
+                    },
+                }
+            }
+            Expression::Function(function, argument) => {
+                let name = FUNCTIONS.iter()
+                    .find(|(_, known)| known == function)
+                    .map(|(name, _)| *name)
+                    .unwrap_or_default();
+                format!("complex_{}({})", name, argument.to_glsl())
+            }
+        }
+    }
+
+    fn evaluate(&self, z: [f32; 2], c: [f32; 2]) -> [f32; 2] {
+        match self {
+            Expression::Number(value) => [*value, 0.0],
+            Expression::ImaginaryUnit => [0.0, 1.0],
+            Expression::Z => z,
+            Expression::C => c,
+            Expression::Negate(inner) => inner.evaluate(z, c).map(|part| -part),
+            Expression::Binary(operator, left, right) => {
+                let a = left.evaluate(z, c);
+                match (operator, &**right) {
+                    (Operator::Pow, Expression::Number(power)) => real_pow(a, *power),
+                    _ => {
+                        let b = right.evaluate(z, c);
+                        match operator {
+                            Operator::Add => [a[0] + b[0], a[1] + b[1]],
+                            Operator::Sub => [a[0] - b[0], a[1] - b[1]],
+                            Operator::Mul => mul(a, b),
+                            Operator::Div => div(a, b),
+                            Operator::Pow => if a == [0.0, 0.0] { a } else { exp(mul(b, log(a))) },
+                        }
+                    }
+                }
+            }
+            Expression::Function(function, argument) => {
+                let a = argument.evaluate(z, c);
+                match function {
+                    Function::Sin => [a[0].sin() * a[1].cosh(), a[0].cos() * a[1].sinh()],
+                    Function::Cos => [a[0].cos() * a[1].cosh(), -a[0].sin() * a[1].sinh()],
+                    Function::Sinh => [a[0].sinh() * a[1].cos(), a[0].cosh() * a[1].sin()],
+                    Function::Cosh => [a[0].cosh() * a[1].cos(), a[0].sinh() * a[1].sin()],
+                    Function::Exp => exp(a),
+                    Function::Log => log(a),
+                    Function::Sqrt => real_pow(a, 0.5),
+                    Function::Conj => [a[0], -a[1]],
+                    Function::Abs => [a[0].abs(), a[1].abs()],
+                    Function::Re => [a[0], 0.0],
+                    Function::Im => [a[1], 0.0],
+                }
+            }
+        }
+    }
+}
+
+fn mul(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [a[0] * b[0] - a[1] * b[1], a[0] * b[1] + a[1] * b[0]]
+}
+
+fn div(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    let norm = b[0] * b[0] + b[1] * b[1];
+    [(a[0] * b[0] + a[1] * b[1]) / norm, (a[1] * b[0] - a[0] * b[1]) / norm]
+}
+
+fn exp(a: [f32; 2]) -> [f32; 2] {
+    let length = a[0].exp();
+    [length * a[1].cos(), length * a[1].sin()]
+}
+
+fn log(a: [f32; 2]) -> [f32; 2] {
+    [(a[0] * a[0] + a[1] * a[1]).sqrt().ln(), a[1].atan2(a[0])]
+}
+
+/// `complex_pow` of `compute.glsl`: integer powers by multiplication, the rest in polar form.
+fn real_pow(a: [f32; 2], power: f32) -> [f32; 2] {
+    if power >= 1.0 && power == power.floor() {
+        let mut result = a;
+        for _ in 1..power as i32 { result = mul(result, a) }
+        return result;
+    }
+    if a == [0.0, 0.0] { return a }
+    let angle = a[1].atan2(a[0]) * power;
+    let length = (a[0] * a[0] + a[1] * a[1]).sqrt().powf(power);
+    [length * angle.cos(), length * angle.sin()]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Name(String),
+    Symbol(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ParseFormulaError> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(position, ch)) = chars.peek() {
+        if ch.is_whitespace() { chars.next(); }
+        else if ch.is_ascii_digit() || ch == '.' {
+            let mut end = position;
+            let mut previous = ' ';
+            // Экспонента вида 1e-3 забирает и свой знак
+            while let Some(&(index, ch)) = chars.peek() {
+                let is_exponent_sign = (ch == '-' || ch == '+') && previous == 'e';
+                if !(ch.is_ascii_digit() || ch == '.' || ch == 'e' || is_exponent_sign) { break }
+                previous = ch;
+                end = index + ch.len_utf8();
+                chars.next();
+            }
+            let value = source[position..end].parse().map_err(|_| ParseFormulaError {
+                position,
+                message: format!("invalid number '{}'", &source[position..end]),
+            })?;
+            tokens.push((position, Token::Number(value)));
+        }
+        else if ch.is_ascii_alphabetic() {
+            let mut name = String::new();
+            while let Some(&(_, ch)) = chars.peek() {
+                if !ch.is_ascii_alphanumeric() { break }
+                name.push(ch);
+                chars.next();
+            }
+            tokens.push((position, Token::Name(name)));
+        }
+        else if "+-*/^()".contains(ch) {
+            tokens.push((position, Token::Symbol(ch)));
+            chars.next();
+        }
+        else {
+            return Err(ParseFormulaError { position, message: format!("unexpected '{}'", ch) });
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent, from the lowest priority to the highest:
+/// `+ -`, `* /`, unary minus, right-associative `^`, then numbers, names and brackets.
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    index: usize,
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<(usize, &'a Token)> {
+        self.tokens.get(self.index).map(|(position, token)| (*position, token))
+    }
+
+    fn next_symbol_is(&self, symbols: &str) -> Option<char> {
+        match self.peek() {
+            Some((_, Token::Symbol(symbol))) if symbols.contains(*symbol) => Some(*symbol),
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expression, ParseFormulaError> {
+        let mut left = self.term()?;
+        while let Some(symbol) = self.next_symbol_is("+-") {
+            self.index += 1;
+            let operator = if symbol == '+' { Operator::Add } else { Operator::Sub };
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Expression, ParseFormulaError> {
+        let mut left = self.unary()?;
+        while let Some(symbol) = self.next_symbol_is("*/") {
+            self.index += 1;
+            let operator = if symbol == '*' { Operator::Mul } else { Operator::Div };
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, ParseFormulaError> {
+        if self.next_symbol_is("-").is_some() {
+            self.index += 1;
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<Expression, ParseFormulaError> {
+        let base = self.atom()?;
+        if self.next_symbol_is("^").is_none() { return Ok(base) }
+        self.index += 1;
+        Ok(Expression::Binary(Operator::Pow, Box::new(base), Box::new(self.unary()?)))
+    }
+
+    fn atom(&mut self) -> Result<Expression, ParseFormulaError> {
+        let (position, token) = match self.peek() {
+            Some(token) => token,
+            None => return Err(ParseFormulaError {
+                position: self.end,
+                message: String::from("unexpected end of formula"),
+            }),
+        };
+        self.index += 1;
+        match token {
+            Token::Number(value) => Ok(Expression::Number(*value)),
+            Token::Symbol('(') => {
+                let inner = self.expression()?;
+                self.expect_closing(position)?;
+                Ok(inner)
+            }
+            Token::Name(name) => match name.as_str() {
+                "z" => Ok(Expression::Z),
+                "c" => Ok(Expression::C),
+                "i" => Ok(Expression::ImaginaryUnit),
+                _ => {
+                    let function = FUNCTIONS.iter()
+                        .find(|(known, _)| known == name)
+                        .map(|(_, function)| *function)
+                        .ok_or_else(|| ParseFormulaError {
+                            position,
+                            message: format!("unknown name '{}', expected one of {}",
+                                name, USER_FORMULA_NAMES),
+                        })?;
+                    if self.next_symbol_is("(").is_none() {
+                        return Err(ParseFormulaError {
+                            position,
+                            message: format!("'{}' needs an argument in brackets", name),
+                        });
+                    }
+                    let (opening, _) = self.peek().unwrap_or((position, token));
+                    self.index += 1;
+                    let argument = self.expression()?;
+                    self.expect_closing(opening)?;
+                    Ok(Expression::Function(function, Box::new(argument)))
+                }
+            },
+            Token::Symbol(symbol) => Err(ParseFormulaError {
+                position,
+                message: format!("unexpected '{}'", symbol),
+            }),
+        }
+    }
+
+    fn expect_closing(&mut self, opening: usize) -> Result<(), ParseFormulaError> {
+        if self.next_symbol_is(")").is_some() {
+            self.index += 1;
+            return Ok(());
+        }
+        Err(ParseFormulaError { position: opening, message: String::from("unclosed '('") })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_formula_syntax() {
+        let formula: UserFormula = "-z^2 + 0.5*c/sin(z)".parse().unwrap();
+        assert_eq!(formula.to_glsl(), "((-complex_pow(z, 2.0)) + \
+            complex_div(complex_mul(vec2(0.5, 0.0), c), complex_sin(z)))");
+
+        for (source, position) in [("z^2 + q", 6), ("sin(z", 3), ("z +", 3), ("z c", 2), ("", 0)] {
+            let err = source.parse::<UserFormula>().unwrap_err();
+            assert_eq!(err.position, position, "{}: {}", source, err);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use super::fixed_point::FixedPoint;
+use super::formula::Formula;
+use super::coloring::{ Coloring, Interior };
+use super::palette::Palette;
+use super::orbit_trap::OrbitTrap;
+use super::lighting::Lighting;
+use super::supersampling::Supersampling;
+
+use bytemuck::{ Pod, Zeroable };
+
+/// Lowest zoom the controls of the window go to, the starting views of
+/// Julia sets and of every formula are above it.
+pub const MIN_ZOOM: f64 = -10.0;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ViewPosition {
+    pub palette: Palette,
+    pub quality: u32,
+    pub fract_color: [f32; 3],
+    pub zoom: f64,
+    pub pos_x: f64,
+    pub pos_y: f64,
+    /// Part of the centre that doesn't fit `pos_x` and `pos_y`,
+    /// the exact centre is `center() + center_offset`.
+    pub center_offset: [FixedPoint; 2],
+    /// Render the Julia set of `julia_c` instead of the Mandelbrot set:
+    /// the pixel is the starting `z`, not `c`.
+    pub julia: bool,
+    pub julia_c: [f64; 2],
+    pub formula: Formula,
+    /// Exponent of `Formula::Multibrot`.
+    pub power: f64,
+    pub coloring: Coloring,
+    /// Trap of `Coloring::OrbitTrap`.
+    pub trap: OrbitTrap,
+    pub interior: Interior,
+    pub lighting: Lighting,
+    pub supersampling: Supersampling,
+}
+impl ViewPosition {
+    pub fn new() -> Self {
+        ViewPosition {
+            quality: 500,
+            zoom: 1.0,
+            pos_x: -500.0,
+            pos_y: 0.0,
+            palette: Palette::default(),
+            fract_color: [0.0, 0.0, 0.0],
+            center_offset: Default::default(),
+            julia: false,
+            julia_c: [-0.8, 0.156],
+            formula: Formula::Mandelbrot,
+            power: 2.0,
+            coloring: Coloring::IterationCount,
+            trap: OrbitTrap::default(),
+            interior: Interior::Flat,
+            lighting: Lighting::default(),
+            supersampling: Supersampling::default(),
+        }
+    }
+
+    /// Starting view of `formula` with the whole fractal on the screen.
+    pub fn formula(formula: Formula) -> Self {
+        let (center, zoom) = match &formula {
+            Formula::Mandelbrot => ([-0.5, 0.0], 1.0),
+            Formula::BurningShip => ([-0.5, -0.5], -7.0),
+            Formula::Tricorn => ([-0.3, 0.0], -5.0),
+            Formula::Multibrot => ([0.0, 0.0], -4.0),
+            Formula::Celtic => ([-0.5, 0.0], -5.0),
+            Formula::Buffalo => ([-0.5, -0.5], -7.0),
+            Formula::User(_) => ([0.0, 0.0], -4.0),
+        };
+        let mut view_position = ViewPosition {
+            zoom,
+            power: if formula == Formula::Multibrot { 3.0 } else { 2.0 },
+            formula,
+            ..ViewPosition::new()
+        };
+        view_position.set_center(center);
+        view_position
+    }
+
+    /// Julia set of `julia_c` with the whole of it on the screen.
+    pub fn julia(julia_c: [f64; 2]) -> Self {
+        ViewPosition {
+            zoom: -4.0,
+            pos_x: 0.0,
+            julia: true,
+            julia_c,
+            ..ViewPosition::new()
+        }
+    }
+
+    /// Point of the complex plane under the pixel `(x, y)` of a `width` x `height` screen.
+    pub fn point_at(&self, x: f64, y: f64, width: f64, height: f64) -> [f64; 2] {
+        let center = self.center();
+        let magnification = self.magnification();
+        [
+            center[0] + (2.0 * x - width) / height / magnification,
+            center[1] + (2.0 * y - height) / height / magnification,
+        ]
+    }
+
+    /// Point of the complex plane in the middle of the screen.
+    pub fn center(&self) -> [f64; 2] {
+        [self.pos_x * 0.001, self.pos_y * 0.001]
+    }
+
+    pub fn set_center(&mut self, center: [f64; 2]) {
+        self.pos_x = center[0] * 1000.0;
+        self.pos_y = center[1] * 1000.0;
+        self.center_offset = Default::default();
+    }
+
+    /// Centre with all the precision deep zooms need.
+    pub fn precise_center(&self) -> [FixedPoint; 2] {
+        let center = self.center();
+        [
+            &FixedPoint::from_f64(center[0]) + &self.center_offset[0],
+            &FixedPoint::from_f64(center[1]) + &self.center_offset[1],
+        ]
+    }
+
+    pub fn set_precise_center(&mut self, center: [FixedPoint; 2]) {
+        self.set_center([center[0].to_f64(), center[1].to_f64()]);
+        let rounded = self.center();
+        self.center_offset = [
+            &center[0] - &FixedPoint::from_f64(rounded[0]),
+            &center[1] - &FixedPoint::from_f64(rounded[1]),
+        ];
+    }
+
+    /// Shifts the centre by `delta` without losing the digits `f64` can't hold.
+    pub fn move_center(&mut self, delta: [f64; 2]) {
+        let center = self.precise_center();
+        self.set_precise_center([
+            &center[0] + &FixedPoint::from_f64(delta[0]),
+            &center[1] + &FixedPoint::from_f64(delta[1]),
+        ]);
+    }
+
+    /// How many times the view is magnified relative to `zoom = 0`.
+    pub fn magnification(&self) -> f64 {
+        (self.zoom / 10.0).exp()
+    }
+
+    pub fn reset(&self) -> Self {
+        let formula_start = ViewPosition::formula(self.formula.clone());
+        let start = if self.julia {
+            ViewPosition { formula: self.formula.clone(), power: formula_start.power, 
+                ..ViewPosition::julia(self.julia_c) }
+        }
+        else { ViewPosition { julia_c: self.julia_c, ..formula_start } };
+        ViewPosition {
+            palette: self.palette.clone(),
+            fract_color: self.fract_color,
+            coloring: self.coloring,
+            trap: self.trap.clone(),
+            interior: self.interior,
+            lighting: self.lighting,
+            supersampling: self.supersampling,
+            ..start
+        }
+    }
+}
+
+/// Layout of the `ViewPosition` storage buffer in `compute.glsl`.
+/// The same buffer feeds every precision variant of the kernel: the centre is
+/// split into three floats, which add up to the original `f64` exactly, and each
+/// kernel takes as many parts as its number type can hold, as well as the
+/// Julia constant. The formula itself is baked into the pipeline,
+/// the palette and the trap image have buffers of their own, see `GpuPalette`
+/// and `GpuTrapImage`. The lighting pass reads the light from here too.
+/// The perturbation kernel works far beyond `exp(zoom / 10)` fitting a float,
+/// so it gets `1 / magnification = scale * 2^scale_exponent` instead.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Zeroable, Pod)]
+pub struct GpuViewPosition {
+    pub fract_color: [f32; 3],
+    pub quality: u32,
+    pub zoom: f32,
+    pub center_x: [f32; 3],
+    pub center_y: [f32; 3],
+    pub scale: f32,
+    pub scale_exponent: i32,
+    pub julia_x: [f32; 3],
+    pub julia_y: [f32; 3],
+    pub julia: u32,
+    pub power: f32,
+    pub coloring: u32,
+    pub trap_shape: u32,
+    pub trap_x: f32,
+    pub trap_y: f32,
+    pub trap_radius: f32,
+    pub trap_angle: f32,
+    pub interior: u32,
+    pub lighting: u32,
+    pub light_height: u32,
+    pub light_azimuth: f32,
+    pub light_elevation: f32,
+    pub light_specular: f32,
+    pub light_relief: f32,
+    pub samples: u32,
+    pub sample_pattern: u32,
+    pub adaptive: u32,
+    /// Pixels of the window's refinement passes, see `Refinement::apply`:
+    /// each one fills a block of this side, 1 elsewhere.
+    pub block_size: u32,
+    pub keep_previous: u32,
+}
+
+impl From<&ViewPosition> for GpuViewPosition {
+    fn from(view_position: &ViewPosition) -> Self {
+        let center = view_position.center();
+        let (scale, scale_exponent) = split_exponent(1.0 / view_position.magnification());
+        GpuViewPosition {
+            fract_color: view_position.fract_color,
+            quality: view_position.quality,
+            zoom: view_position.zoom as f32,
+            center_x: split_f64(center[0]),
+            center_y: split_f64(center[1]),
+            scale: scale as f32,
+            scale_exponent,
+            julia_x: split_f64(view_position.julia_c[0]),
+            julia_y: split_f64(view_position.julia_c[1]),
+            julia: view_position.julia as u32,
+            power: view_position.power as f32,
+            coloring: view_position.coloring.id(),
+            trap_shape: view_position.trap.shape.id(),
+            trap_x: view_position.trap.center[0] as f32,
+            trap_y: view_position.trap.center[1] as f32,
+            trap_radius: view_position.trap.radius as f32,
+            trap_angle: view_position.trap.angle as f32,
+            interior: view_position.interior.id(),
+            lighting: view_position.lighting.enabled as u32,
+            light_height: view_position.lighting.height.id(),
+            light_azimuth: view_position.lighting.azimuth as f32,
+            light_elevation: view_position.lighting.elevation as f32,
+            light_specular: view_position.lighting.specular as f32,
+            light_relief: view_position.lighting.relief as f32,
+            samples: view_position.supersampling.samples,
+            sample_pattern: view_position.supersampling.pattern.id(),
+            adaptive: view_position.supersampling.adaptive as u32,
+            block_size: 1,
+            keep_previous: 0,
+        }
+    }
+}
+
+pub fn split_f64(value: f64) -> [f32; 3] {
+    let hi = value as f32;
+    let mid = (value - hi as f64) as f32;
+    let lo = (value - hi as f64 - mid as f64) as f32;
+    [hi, mid, lo]
+}
+
+pub fn join_f64(parts: [f32; 3]) -> f64 {
+    parts[0] as f64 + parts[1] as f64 + parts[2] as f64
+}
+
+/// `value = mantissa * 2^exponent` with the mantissa in `[1, 2)`.
+pub fn split_exponent(value: f64) -> (f64, i32) {
+    if value == 0.0 || !value.is_finite() { return (value, 0) }
+    let exponent = value.abs().log2().floor() as i32;
+    let mantissa = value / 2f64.powi(exponent);
+    (mantissa, exponent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn center_survives_the_buffer_split() {
+        let center = [-0.743_643_887_037_151, 0.131_825_904_205_330];
+        let mut view_position = ViewPosition { zoom: 180.0, ..ViewPosition::new() };
+        view_position.set_center(center);
+        let gpu_view_position = GpuViewPosition::from(&view_position);
+        assert_eq!(join_f64(gpu_view_position.center_x), center[0]);
+        assert_eq!(join_f64(gpu_view_position.center_y), center[1]);
+    }
+
+    #[test]
+    fn precise_center_survives_moves() {
+        let center: FixedPoint = "-1.7499999999999999999999999999999999999999999999999999997"
+            .parse().unwrap();
+        let mut view_position = ViewPosition::new();
+        view_position.set_precise_center([center.clone(), FixedPoint::zero()]);
+        for _ in 0..1000 { view_position.move_center([1e-55, 0.0]) }
+        for _ in 0..1000 { view_position.move_center([-1e-55, 0.0]) }
+
+        let precise_center = view_position.precise_center();
+        assert_eq!((&precise_center[0] - &center).to_f64(), 0.0);
+        assert_eq!(precise_center[1].to_f64(), 0.0);
+        assert_eq!(view_position.center()[0], -1.75);
+    }
+
+    /// Opening the panel must not clamp the zoom of a starting view.
+    #[test]
+    fn starting_views_fit_the_zoom_controls() {
+        let user_formula = Formula::User("z^3 + c".parse().unwrap());
+        let starts = Formula::ALL.into_iter().map(ViewPosition::formula)
+            .chain([ViewPosition::formula(user_formula), ViewPosition::julia([-0.8, 0.156])]);
+        for view_position in starts {
+            assert!(view_position.zoom >= MIN_ZOOM, "{}", view_position.formula.name());
+        }
+    }
+}
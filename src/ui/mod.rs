@@ -1 +1,2 @@
 pub mod ui_old;
+pub mod palette_editor;
@@ -0,0 +1,89 @@
+use crate::rvm::palette::{ Palette, ColorStop, MAX_STOPS };
+
+const BAR_HEIGHT: f32 = 24.0;
+const HANDLE_SIZE: f32 = 7.0;
+
+fn to_color32(color: [f32; 3]) -> egui::Color32 {
+    let [r, g, b] = color.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+    egui::Color32::from_rgb(r, g, b)
+}
+
+/// Gradient bar of `palette` with a handle under every stop. Dragging a handle
+/// moves the stop between its neighbours, a click on the bar adds a stop there
+/// while there are fewer than `MAX_STOPS`, the selected stop gets a color
+/// picker and can be removed while two remain.
+pub fn palette_editor(ui: &mut egui::Ui, palette: &mut Palette, selected: &mut usize) {
+    let width = ui.spacing().slider_width;
+    let (rect, _) = ui.allocate_exact_size(
+        egui::vec2(width, BAR_HEIGHT + 2.0 * HANDLE_SIZE), egui::Sense::hover());
+    let bar = egui::Rect::from_min_size(rect.min, egui::vec2(width, BAR_HEIGHT));
+    let position_at = |x: f32| ((x - bar.left()) / bar.width()).clamp(0.0, 1.0);
+
+    let bar_response = ui.interact(bar, ui.id().with("palette_bar"), egui::Sense::click());
+    let clicked = bar_response.interact_pointer_pos().filter(|_| bar_response.clicked());
+    if let (Some(pointer), true) = (clicked, palette.stops.len() < MAX_STOPS) {
+        *selected = palette.insert_stop(position_at(pointer.x));
+    }
+
+    for index in 0..palette.stops.len() {
+        let x = bar.left() + palette.stops[index].position * bar.width();
+        let handle = egui::Rect::from_center_size(
+            egui::pos2(x, bar.bottom() + HANDLE_SIZE), egui::vec2(HANDLE_SIZE * 1.5, HANDLE_SIZE * 2.0));
+        let response = ui.interact(handle, ui.id().with(("palette_stop", index)),
+            egui::Sense::click_and_drag());
+        if response.clicked() || response.drag_started() { *selected = index }
+        if let Some(pointer) = response.interact_pointer_pos().filter(|_| response.dragged()) {
+            // Остановка не обгоняет соседей, так порядок сохраняется
+            let low = if index == 0 { 0.0 } else { palette.stops[index - 1].position };
+            let high = palette.stops.get(index + 1).map_or(1.0, |next| next.position);
+            palette.stops[index].position = position_at(pointer.x).clamp(low, high);
+        }
+    }
+    *selected = (*selected).min(palette.stops.len().saturating_sub(1));
+
+    // Градиент рисуется полосами между остановками, цвета вершин смешивает egui
+    let painter = ui.painter_at(rect);
+    let mut mesh = egui::Mesh::default();
+    let first = palette.stops.first().map_or([0.0; 3], |stop| stop.color);
+    let last = palette.stops.last().map_or([0.0; 3], |stop| stop.color);
+    let points: Vec<(f32, [f32; 3])> = [(0.0, first)].into_iter()
+        .chain(palette.stops.iter().map(|stop| (stop.position, stop.color)))
+        .chain([(1.0, last)])
+        .collect();
+    for pair in points.windows(2) {
+        let (left, right) = (bar.left() + pair[0].0 * width, bar.left() + pair[1].0 * width);
+        let base = mesh.vertices.len() as u32;
+        mesh.colored_vertex(egui::pos2(left, bar.top()), to_color32(pair[0].1));
+        mesh.colored_vertex(egui::pos2(right, bar.top()), to_color32(pair[1].1));
+        mesh.colored_vertex(egui::pos2(left, bar.bottom()), to_color32(pair[0].1));
+        mesh.colored_vertex(egui::pos2(right, bar.bottom()), to_color32(pair[1].1));
+        mesh.add_triangle(base, base + 1, base + 2);
+        mesh.add_triangle(base + 1, base + 2, base + 3);
+    }
+    painter.add(mesh);
+    painter.rect_stroke(bar, 0.0, egui::Stroke::new(1.0, egui::Color32::BLACK));
+    for (index, stop) in palette.stops.iter().enumerate() {
+        let x = bar.left() + stop.position * width;
+        let stroke_color = if index == *selected { egui::Color32::WHITE } else { egui::Color32::BLACK };
+        painter.add(egui::Shape::convex_polygon(
+            vec![
+                egui::pos2(x, bar.bottom()),
+                egui::pos2(x + HANDLE_SIZE * 0.75, bar.bottom() + 2.0 * HANDLE_SIZE),
+                egui::pos2(x - HANDLE_SIZE * 0.75, bar.bottom() + 2.0 * HANDLE_SIZE),
+            ],
+            to_color32(stop.color),
+            egui::Stroke::new(1.5, stroke_color)
+        ));
+    }
+
+    ui.horizontal(|ui| {
+        if let Some(ColorStop { position, color }) = palette.stops.get_mut(*selected) {
+            ui.color_edit_button_rgb(color);
+            ui.label(format!("Stop at {:.3}", position));
+        }
+        if palette.stops.len() > 2 && ui.button("Remove").clicked() {
+            palette.stops.remove(*selected);
+            *selected = selected.saturating_sub(1);
+        }
+    });
+}
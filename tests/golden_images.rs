@@ -0,0 +1,465 @@
+//! Golden-image regression tests for `compute.glsl`.
+//!
+//! The CPU reference renderer is checked against the images stored in
+//! `tests/golden`, and the shader is checked against the CPU renderer
+//! through the headless path. Tests that need Vulkan are ignored by default,
+//! run them with `cargo test -- --ignored` where a device is available,
+//! e.g. in CI with lavapipe. Run with `RVM_UPDATE_GOLDEN=1` to rewrite the
+//! stored images after an intended change of the output.
+
+use std::env;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rvm::rvm::cpu_renderer;
+use rvm::rvm::headless::{ self, HeadlessRenderer };
+use rvm::rvm::view_position::ViewPosition;
+use rvm::rvm::precision::Precision;
+use rvm::rvm::formula::Formula;
+use rvm::rvm::coloring::{ Coloring, Interior };
+use rvm::rvm::palette::Palette;
+use rvm::rvm::orbit_trap::{ OrbitTrap, TrapShape, TrapImage };
+use rvm::rvm::lighting::{ Lighting, HeightField };
+use rvm::rvm::supersampling::{ Supersampling, SamplePattern };
+
+const WIDTH: u32 = 192;
+const HEIGHT: u32 = 144;
+
+/// Max per-channel difference for two pixels to be considered equal.
+const CHANNEL_TOLERANCE: u8 = 8;
+/// Share of pixels allowed to differ more than `CHANNEL_TOLERANCE`.
+/// Points on the boundary are chaotic, one ulp may change their iteration count.
+const MAX_MISMATCHED_FRACTION: f64 = 0.02;
+
+fn view(center: [f64; 2], zoom: f64, quality: u32) -> ViewPosition {
+    let mut view_position = ViewPosition {
+        zoom,
+        quality,
+        palette: Palette::monochrome([1.0, 0.5, 0.25]),
+        fract_color: [0.0, 0.0, 0.2],
+        ..ViewPosition::new()
+    };
+    view_position.set_center(center);
+    view_position
+}
+
+fn full_set() -> ViewPosition { view([-0.5, 0.0], 1.0, 100) }
+
+fn seahorse_valley() -> ViewPosition { view([-0.745_3, 0.112_7], 50.0, 200) }
+
+fn deep_zoom() -> ViewPosition { view([-0.743_566_9, 0.131_402_3], 80.0, 1000) }
+
+/// Magnification around 7e7, far beyond what `f32` can resolve.
+fn double_zoom() -> ViewPosition {
+    view([-0.743_643_887_037_151, 0.131_825_904_205_330], 180.0, 1000)
+}
+
+/// Magnification 1e50 next to the Misiurewicz point `i`, only perturbation gets there.
+fn perturbation_zoom() -> ViewPosition {
+    let mut view_position = view([0.0, 1.0], 1151.0, 200);
+    view_position.set_precise_center([
+        "0.00000000000000000000000000000000000000000000000000003".parse().unwrap(),
+        "1.00000000000000000000000000000000000000000000000000002".parse().unwrap(),
+    ]);
+    view_position
+}
+
+fn julia_set() -> ViewPosition {
+    ViewPosition {
+        quality: 200,
+        palette: Palette::monochrome([1.0, 0.5, 0.25]),
+        fract_color: [0.0, 0.0, 0.2],
+        ..ViewPosition::julia([-0.8, 0.156])
+    }
+}
+
+fn julia_zoom() -> ViewPosition {
+    let mut view_position = ViewPosition { zoom: 150.0, quality: 300, ..julia_set() };
+    view_position.set_center([0.850_956_820_866_164, -0.381_579_278_879_968]);
+    view_position
+}
+
+fn formula_view(formula: Formula) -> ViewPosition {
+    ViewPosition {
+        quality: 100,
+        palette: Palette::monochrome([1.0, 0.5, 0.25]),
+        fract_color: [0.0, 0.0, 0.2],
+        ..ViewPosition::formula(formula)
+    }
+}
+
+fn smooth(view_position: ViewPosition) -> ViewPosition {
+    ViewPosition { coloring: Coloring::Smooth, ..view_position }
+}
+
+fn ultra_fractal(view_position: ViewPosition) -> ViewPosition {
+    let palette = Palette { offset: 0.3, scale: 4.0, ..Palette::preset("ultra-fractal").unwrap() };
+    ViewPosition { palette, ..smooth(view_position) }
+}
+
+fn histogram(view_position: ViewPosition) -> ViewPosition {
+    let palette = Palette::preset("fire").unwrap();
+    ViewPosition { palette, coloring: Coloring::Histogram, ..view_position }
+}
+
+fn coloring(coloring: Coloring, palette: &str, view_position: ViewPosition) -> ViewPosition {
+    ViewPosition { coloring, palette: Palette::preset(palette).unwrap(), ..view_position }
+}
+
+fn orbit_trap(shape: TrapShape, view_position: ViewPosition) -> ViewPosition {
+    let trap = OrbitTrap { shape, radius: 0.3, angle: 0.5, image: Arc::new(checkerboard()), 
+        ..Default::default() };
+    ViewPosition { trap, ..coloring(Coloring::OrbitTrap, "ultra-fractal", view_position) }
+}
+
+fn interior(interior: Interior, view_position: ViewPosition) -> ViewPosition {
+    ViewPosition { interior, ..coloring(Coloring::Smooth, "ultra-fractal", view_position) }
+}
+
+fn lighting(height: HeightField, view_position: ViewPosition) -> ViewPosition {
+    let lighting = Lighting { enabled: true, height, ..Default::default() };
+    ViewPosition { lighting, ..ultra_fractal(view_position) }
+}
+
+fn supersampling(samples: u32, pattern: SamplePattern, adaptive: bool, 
+    view_position: ViewPosition) -> ViewPosition {
+    let supersampling = Supersampling { samples, pattern, adaptive };
+    ViewPosition { supersampling, ..ultra_fractal(view_position) }
+}
+
+/// 8x8 squares, red ones on the top half and blue ones on the bottom half
+/// with transparent ones in between.
+fn checkerboard() -> TrapImage {
+    let pixels = (0..8 * 8).map(|i| match (i % 8 + i / 8) % 2 {
+        0 if i < 32 => [255, 0, 0, 255],
+        0 => [0, 0, 255, 255],
+        _ => [255, 255, 255, 0],
+    }).collect();
+    TrapImage::new(8, 8, pixels)
+}
+
+fn user_formula(source: &str) -> Formula {
+    Formula::User(source.parse().unwrap())
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+        .join(format!("{}.png", name))
+}
+
+fn load_png(path: &PathBuf) -> (u32, u32, Vec<u8>) {
+    let file = File::open(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgba, "{}", path.display());
+    pixels.truncate(info.buffer_size());
+    (info.width, info.height, pixels)
+}
+
+fn assert_images_match(name: &str, width: u32, height: u32, actual: &[u8], expected: &[u8]) {
+    assert_eq!(actual.len(), expected.len(), "{}: image sizes differ", name);
+
+    let mismatched = actual.chunks_exact(4).zip(expected.chunks_exact(4))
+        .filter(|(a, e)| a.iter().zip(e.iter()).any(|(a, e)| a.abs_diff(*e) > CHANNEL_TOLERANCE))
+        .count();
+    let fraction = mismatched as f64 / (actual.len() / 4) as f64;
+    if fraction > MAX_MISMATCHED_FRACTION {
+        let failed_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("{}.actual.png", name));
+        headless::save_png(&failed_path, width, height, actual).unwrap();
+        panic!("{}: {} pixels ({:.2}%) differ, actual image saved to {}",
+            name, mismatched, fraction * 100.0, failed_path.display());
+    }
+}
+
+fn check_cpu_against_golden(name: &str, view_position: ViewPosition, precision: Precision) {
+    let actual = cpu_renderer::render(&view_position, WIDTH, HEIGHT, precision);
+    let path = golden_path(name);
+    if env::var_os("RVM_UPDATE_GOLDEN").is_some() {
+        headless::save_png(&path, WIDTH, HEIGHT, &actual).unwrap();
+        return;
+    }
+
+    let (width, height, expected) = load_png(&path);
+    assert_eq!((width, height), (WIDTH, HEIGHT), "{}: golden image size", name);
+    assert_images_match(name, WIDTH, HEIGHT, &actual, &expected);
+}
+
+fn headless_renderer() -> HeadlessRenderer {
+    HeadlessRenderer::new().unwrap_or_else(|err| panic!("Vulkan device is required: {:?}", err))
+}
+
+fn check_shader_against_cpu(name: &str, view_position: ViewPosition, precision: Precision) {
+    let renderer = headless_renderer();
+    let actual = renderer.render(&view_position, WIDTH, HEIGHT, precision).unwrap();
+    let expected = cpu_renderer::render(&view_position, WIDTH, HEIGHT, precision);
+    assert_images_match(&format!("{}_{}_gpu", name, precision.name()), 
+        WIDTH, HEIGHT, &actual, &expected);
+}
+
+#[test]
+fn cpu_full_set() { check_cpu_against_golden("full_set", full_set(), Precision::Single) }
+
+#[test]
+fn cpu_seahorse_valley() {
+    check_cpu_against_golden("seahorse_valley", seahorse_valley(), Precision::Single)
+}
+
+#[test]
+fn cpu_deep_zoom() { check_cpu_against_golden("deep_zoom", deep_zoom(), Precision::Single) }
+
+#[test]
+fn cpu_double_zoom() { check_cpu_against_golden("double_zoom", double_zoom(), Precision::Double) }
+
+#[test]
+fn cpu_perturbation_zoom() {
+    check_cpu_against_golden("perturbation_zoom", perturbation_zoom(), Precision::Perturbation)
+}
+
+/// Where `double` still works both must agree, glitches or all.
+#[test]
+fn cpu_perturbation_matches_double() {
+    let actual = cpu_renderer::render(&double_zoom(), WIDTH, HEIGHT, Precision::Perturbation);
+    let expected = cpu_renderer::render(&double_zoom(), WIDTH, HEIGHT, Precision::Double);
+    assert_images_match("perturbation_double_zoom", WIDTH, HEIGHT, &actual, &expected);
+}
+
+#[test]
+fn cpu_julia_set() { check_cpu_against_golden("julia_set", julia_set(), Precision::Single) }
+
+#[test]
+fn cpu_julia_perturbation_matches_double() {
+    let actual = cpu_renderer::render(&julia_zoom(), WIDTH, HEIGHT, Precision::Perturbation);
+    let expected = cpu_renderer::render(&julia_zoom(), WIDTH, HEIGHT, Precision::Double);
+    assert_images_match("perturbation_julia_zoom", WIDTH, HEIGHT, &actual, &expected);
+}
+
+#[test]
+fn cpu_formulas() {
+    for formula in Formula::ALL.into_iter().filter(|formula| *formula != Formula::Mandelbrot) {
+        check_cpu_against_golden(&format!("formula_{}", formula.name()), 
+            formula_view(formula), Precision::Single);
+    }
+}
+
+#[test]
+fn multibrot_of_power_two_is_mandelbrot() {
+    let mandelbrot = ViewPosition { formula: Formula::Mandelbrot, ..formula_view(Formula::Multibrot) };
+    let multibrot = ViewPosition { power: 2.0, ..formula_view(Formula::Multibrot) };
+    for precision in [Precision::Single, Precision::Double] {
+        assert!(cpu_renderer::render(&multibrot, WIDTH, HEIGHT, precision)
+            == cpu_renderer::render(&mandelbrot, WIDTH, HEIGHT, precision));
+    }
+}
+
+#[test]
+fn cpu_user_formula() {
+    check_cpu_against_golden("formula_user", formula_view(user_formula("z^3 - z + c")), 
+        Precision::Single)
+}
+
+/// Typed formulas go through the same operations as the built-in ones.
+#[test]
+fn user_formula_matches_builtin() {
+    let pairs = [("z^2 + c", Formula::Mandelbrot), ("abs(z)^2 + c", Formula::BurningShip),
+        ("conj(z)*conj(z) + c", Formula::Tricorn)];
+    for (source, formula) in pairs {
+        let builtin = formula_view(formula);
+        let user = ViewPosition { formula: user_formula(source), ..builtin.clone() };
+        assert!(cpu_renderer::render(&user, WIDTH, HEIGHT, Precision::Single)
+            == cpu_renderer::render(&builtin, WIDTH, HEIGHT, Precision::Single), "{}", source);
+    }
+}
+
+/// Colorings and passes after the kernel as `(name, view_position, precision)`.
+/// `Perturbation` cases are checked against `Double` where it still works,
+/// the others against their golden images, and the shader against the CPU.
+fn coloring_cases() -> Vec<(&'static str, ViewPosition, Precision)> {
+    let distance = |view_position| coloring(Coloring::Distance, "ultra-fractal", view_position);
+    vec![
+        ("smooth_seahorse_valley", smooth(seahorse_valley()), Precision::Single),
+        ("smooth_multibrot", smooth(formula_view(Formula::Multibrot)), Precision::Single),
+        ("smooth_double_zoom", smooth(double_zoom()), Precision::Perturbation),
+        ("palette_seahorse_valley", ultra_fractal(seahorse_valley()), Precision::Single),
+        ("palette_double_zoom", ultra_fractal(double_zoom()), Precision::Perturbation),
+        ("distance_seahorse_valley", distance(seahorse_valley()), Precision::Single),
+        ("distance_double_zoom", distance(double_zoom()), Precision::Perturbation),
+        ("line_art_full_set", coloring(Coloring::LineArt, "grayscale", full_set()), Precision::Single),
+        ("orbit_trap_point_full_set", orbit_trap(TrapShape::Point, full_set()), Precision::Single),
+        ("orbit_trap_cross_seahorse_valley", orbit_trap(TrapShape::Cross, seahorse_valley()), 
+            Precision::Single),
+        ("orbit_trap_circle_julia_set", orbit_trap(TrapShape::Circle, julia_set()), Precision::Single),
+        ("orbit_trap_image_julia_set", orbit_trap(TrapShape::Image, julia_set()), Precision::Single),
+        ("orbit_trap_line_double_zoom", orbit_trap(TrapShape::Line, double_zoom()), 
+            Precision::Perturbation),
+        ("interior_period_full_set", interior(Interior::Period, full_set()), Precision::Single),
+        ("interior_magnitude_julia_set", interior(Interior::Magnitude, julia_set()), Precision::Single),
+        ("interior_distance_full_set", interior(Interior::Distance, full_set()), Precision::Double),
+        ("interior_period_double_zoom", interior(Interior::Period, double_zoom()), 
+            Precision::Perturbation),
+        ("interior_distance_double_zoom", interior(Interior::Distance, double_zoom()), 
+            Precision::Perturbation),
+        ("lighting_iterations_seahorse_valley", lighting(HeightField::Iterations, seahorse_valley()), 
+            Precision::Single),
+        ("lighting_distance_full_set", lighting(HeightField::Distance, full_set()), Precision::Single),
+        ("lighting_distance_double_zoom", lighting(HeightField::Distance, double_zoom()), 
+            Precision::Perturbation),
+    ]
+}
+
+#[test]
+fn cpu_colorings() {
+    for (name, view_position, precision) in coloring_cases() {
+        if precision != Precision::Perturbation {
+            check_cpu_against_golden(name, view_position, precision);
+            continue;
+        }
+        let actual = cpu_renderer::render(&view_position, WIDTH, HEIGHT, Precision::Perturbation);
+        let expected = cpu_renderer::render(&view_position, WIDTH, HEIGHT, Precision::Double);
+        assert_images_match(name, WIDTH, HEIGHT, &actual, &expected);
+    }
+}
+
+#[test]
+fn cpu_histogram_coloring() {
+    check_cpu_against_golden("histogram_deep_zoom", histogram(deep_zoom()), Precision::Single);
+}
+
+#[test]
+fn cpu_supersampling() {
+    check_cpu_against_golden("supersampling_grid_seahorse_valley", 
+        supersampling(3, SamplePattern::Grid, false, seahorse_valley()), Precision::Single);
+    check_cpu_against_golden("supersampling_jittered_julia_set", 
+        supersampling(2, SamplePattern::Jittered, false, julia_set()), Precision::Single);
+}
+
+/// Pixels the adaptive mode leaves with one sample sit among neighbours of
+/// nearly the same color, so they stay close to the full average.
+#[test]
+fn adaptive_supersampling_matches_full() {
+    let adaptive = supersampling(3, SamplePattern::Grid, true, seahorse_valley());
+    let full = supersampling(3, SamplePattern::Grid, false, seahorse_valley());
+    let actual = cpu_renderer::render(&adaptive, WIDTH, HEIGHT, Precision::Single);
+    let expected = cpu_renderer::render(&full, WIDTH, HEIGHT, Precision::Single);
+    assert_images_match("adaptive_supersampling_seahorse_valley", WIDTH, HEIGHT, &actual, &expected);
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_full_set() { check_shader_against_cpu("full_set", full_set(), Precision::Single) }
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_seahorse_valley() {
+    check_shader_against_cpu("seahorse_valley", seahorse_valley(), Precision::Single)
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_deep_zoom() { check_shader_against_cpu("deep_zoom", deep_zoom(), Precision::Single) }
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_double_zoom() {
+    check_shader_against_cpu("double_zoom", double_zoom(), Precision::Double)
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_double_single_zoom() {
+    check_shader_against_cpu("double_zoom", double_zoom(), Precision::DoubleSingle)
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_perturbation_zoom() {
+    check_shader_against_cpu("perturbation_zoom", perturbation_zoom(), Precision::Perturbation)
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_julia_set() { check_shader_against_cpu("julia_set", julia_set(), Precision::Single) }
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_julia_zoom() {
+    check_shader_against_cpu("julia_zoom", julia_zoom(), Precision::Double);
+    check_shader_against_cpu("julia_zoom", julia_zoom(), Precision::DoubleSingle);
+    check_shader_against_cpu("julia_zoom", julia_zoom(), Precision::Perturbation);
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_formulas() {
+    let fractional_power = ViewPosition { power: 2.5, ..formula_view(Formula::Multibrot) };
+    let views = Formula::ALL.into_iter().map(formula_view).chain([fractional_power]);
+    for view_position in views {
+        let name = format!("formula_{}", view_position.formula.name());
+        for precision in [Precision::Single, Precision::Double, Precision::DoubleSingle] {
+            check_shader_against_cpu(&name, view_position.clone(), precision);
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_user_formula() {
+    check_shader_against_cpu("formula_user", formula_view(user_formula("z^3 - z + c")), 
+        Precision::Single)
+}
+
+/// Single precision cases also go through the other float kernels.
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_colorings() {
+    for (name, view_position, precision) in coloring_cases() {
+        let precisions = if precision == Precision::Single {
+            vec![Precision::Single, Precision::Double, Precision::DoubleSingle]
+        }
+        else { vec![precision] };
+        for precision in precisions {
+            check_shader_against_cpu(name, view_position.clone(), precision);
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_histogram_coloring() {
+    for precision in [Precision::Single, Precision::DoubleSingle] {
+        check_shader_against_cpu("histogram_deep_zoom", histogram(deep_zoom()), precision);
+    }
+    // Lighting after the histogram pass
+    check_shader_against_cpu("lighting_histogram_full_set", 
+        ViewPosition { lighting: Lighting { enabled: true, ..Default::default() }, 
+            ..histogram(full_set()) }, Precision::Single);
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_supersampling() {
+    for precision in [Precision::Single, Precision::Double, Precision::DoubleSingle] {
+        check_shader_against_cpu("supersampling_grid_seahorse_valley", 
+            supersampling(3, SamplePattern::Grid, false, seahorse_valley()), precision);
+    }
+    check_shader_against_cpu("supersampling_jittered_julia_set", 
+        supersampling(2, SamplePattern::Jittered, false, julia_set()), Precision::Single);
+    // Neighbours across the edges of the workgroups come from the halo
+    check_shader_against_cpu("supersampling_adaptive_seahorse_valley", 
+        supersampling(4, SamplePattern::Grid, true, seahorse_valley()), Precision::Single);
+    check_shader_against_cpu("supersampling_histogram_full_set", 
+        ViewPosition { coloring: Coloring::Histogram, 
+            ..supersampling(2, SamplePattern::Grid, true, full_set()) }, Precision::Single);
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn shader_odd_image_size() {
+    let renderer = headless_renderer();
+    let (width, height) = (WIDTH + 7, HEIGHT + 3);
+    let actual = renderer.render(&full_set(), width, height, Precision::Single).unwrap();
+    let expected = cpu_renderer::render(&full_set(), width, height, Precision::Single);
+    assert_images_match("odd_size_gpu", width, height, &actual, &expected);
+}